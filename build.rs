@@ -0,0 +1,46 @@
+//! Embeds what `GET /version` reports about the build.
+
+use std::env;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn git_sha() -> Option<String> {
+    let output = Command::new("git")
+        .args(&["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let sha = String::from_utf8(output.stdout).ok()?;
+    Some(sha.trim().to_owned())
+}
+
+fn main() {
+    // builds outside of a checkout, like in a Docker image, can pass the SHA in instead
+    let sha = env::var("GIT_SHA")
+        .ok()
+        .or_else(git_sha)
+        .unwrap_or_else(|| "unknown".to_owned());
+    // reproducible builds set SOURCE_DATE_EPOCH instead of using the current time
+    let built_at = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0)
+        });
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| name.strip_prefix("CARGO_FEATURE_").map(str::to_owned))
+        .map(|feature| feature.to_lowercase().replace('_', "-"))
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=BUILD_GIT_SHA={}", sha);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", built_at);
+    println!("cargo:rustc-env=BUILD_FEATURES={}", features.join(","));
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -0,0 +1,24 @@
+-- Databases set up from the first schema.sql already have a todos table, which the
+-- "create table if not exists" of the next migration leaves without the columns added to it
+-- since. This brings it up to what that migration creates. It runs before it on those
+-- databases, and changes nothing on any other.
+do $$
+begin
+  if to_regclass('todos') is null then
+    return;
+  end if;
+
+  alter table todos add column if not exists version bigint not null default 1;
+  alter table todos add column if not exists completed_at timestamptz;
+  alter table todos alter column "order" type double precision;
+
+  if not exists (select 1 from pg_constraint where conname = 'todos_order_unique') then
+    -- the first schema didn't keep orders unique, new todos all got 0
+    update todos set "order" = ranked.position
+      from (select id, row_number() over (order by "order", id) as position from todos) ranked
+      where todos.id = ranked.id;
+    alter table todos
+      add constraint todos_order_unique unique ("order") deferrable initially deferred;
+  end if;
+end
+$$;
@@ -0,0 +1,26 @@
+create table if not exists todos (
+  id bigserial,
+  title text not null,
+  "order" double precision not null default 0,
+  completed boolean not null default false,
+  version bigint not null default 1,
+  completed_at timestamptz,
+  -- deferred, so that shifting orders in a single statement doesn't trip over itself
+  constraint todos_order_unique unique ("order") deferrable initially deferred
+);
+
+create table if not exists todo_revisions (
+  id bigserial primary key,
+  todo_id bigint not null,
+  action text not null,
+  before jsonb,
+  after jsonb,
+  created_at timestamptz not null default now()
+);
+
+create table if not exists sync_tokens (
+  token text primary key,
+  device_id text not null unique,
+  cursor bigint not null,
+  expires_at timestamptz not null
+);
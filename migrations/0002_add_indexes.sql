@@ -0,0 +1,8 @@
+alter table todos add constraint todos_pkey primary key (id);
+
+-- "order" is already covered by the index backing todos_order_unique
+create index todos_completed_idx on todos (completed);
+create index todos_completed_at_idx on todos (completed_at) where completed_at is not null;
+
+create index todo_revisions_todo_id_idx on todo_revisions (todo_id);
+create index todo_revisions_created_at_idx on todo_revisions (created_at);
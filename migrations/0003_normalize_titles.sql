@@ -0,0 +1,4 @@
+-- Titles are stored trimmed and in Unicode NFC since the API normalizes them, bring existing
+-- rows to the same form.
+update todos set title = btrim(normalize(title, NFC)), version = version + 1
+    where title <> btrim(normalize(title, NFC));
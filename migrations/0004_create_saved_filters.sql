@@ -0,0 +1,6 @@
+create table if not exists saved_filters (
+  id bigserial primary key,
+  name text not null,
+  query text not null,
+  created_at timestamptz not null default now()
+);
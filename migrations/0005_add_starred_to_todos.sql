@@ -0,0 +1,3 @@
+alter table todos add column if not exists starred boolean not null default false;
+
+create index todos_starred_idx on todos (starred) where starred;
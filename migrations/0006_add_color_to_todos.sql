@@ -0,0 +1 @@
+alter table todos add column if not exists color text;
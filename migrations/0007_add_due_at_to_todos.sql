@@ -0,0 +1,3 @@
+alter table todos add column if not exists due_at timestamptz;
+
+create index todos_due_at_idx on todos (due_at) where due_at is not null and not completed;
@@ -0,0 +1,6 @@
+alter table todos add column if not exists status text not null default 'todo';
+update todos set status = 'done' where completed and status <> 'done';
+
+alter table todos add constraint todos_status_check
+    check (status in ('todo', 'in_progress', 'done', 'blocked'));
+create index todos_status_idx on todos (status);
@@ -0,0 +1,10 @@
+create table if not exists custom_fields (
+  key text primary key,
+  name text not null,
+  kind text not null check (kind in ('text', 'number', 'date', 'select')),
+  options jsonb not null default '[]',
+  created_at timestamptz not null default now()
+);
+
+alter table todos add column if not exists custom_fields jsonb not null default '{}';
+create index todos_custom_fields_idx on todos using gin (custom_fields);
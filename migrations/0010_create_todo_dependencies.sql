@@ -0,0 +1,9 @@
+create table if not exists todo_dependencies (
+  todo_id bigint not null references todos (id) on delete cascade,
+  blocker_id bigint not null references todos (id) on delete cascade,
+  created_at timestamptz not null default now(),
+  primary key (todo_id, blocker_id),
+  check (todo_id <> blocker_id)
+);
+
+create index todo_dependencies_blocker_id_idx on todo_dependencies (blocker_id);
@@ -0,0 +1,12 @@
+create table if not exists time_entries (
+  id bigserial primary key,
+  todo_id bigint not null references todos (id) on delete cascade,
+  started_at timestamptz not null default now(),
+  stopped_at timestamptz
+);
+
+-- a todo has at most one running timer
+create unique index time_entries_running_idx on time_entries (todo_id) where stopped_at is null;
+create index time_entries_started_at_idx on time_entries (started_at);
+
+alter table todos add column if not exists tracked_seconds bigint not null default 0;
@@ -0,0 +1,19 @@
+alter table todos add column if not exists latitude double precision;
+alter table todos add column if not exists longitude double precision;
+alter table todos add column if not exists place_name text;
+
+-- the great-circle distance in meters between two points, kept in sync with the Rust version
+create or replace function haversine_distance(
+  latitude1 double precision,
+  longitude1 double precision,
+  latitude2 double precision,
+  longitude2 double precision
+) returns double precision as $$
+  select 2 * 6371000 * asin(least(1, sqrt(
+    power(sin(radians(latitude2 - latitude1) / 2), 2)
+    + cos(radians(latitude1)) * cos(radians(latitude2))
+      * power(sin(radians(longitude2 - longitude1) / 2), 2)
+  )))
+$$ language sql immutable strict;
+
+create index todos_location_idx on todos (latitude, longitude) where latitude is not null and not completed;
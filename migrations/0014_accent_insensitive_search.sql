@@ -0,0 +1,11 @@
+create extension if not exists unaccent;
+create extension if not exists pg_trgm;
+
+-- unaccent() is only stable since its dictionary could change, which rules it out for indexes.
+-- Pinning the dictionary makes the result depend on the input alone.
+create or replace function search_text(text) returns text as $$
+  select lower(public.unaccent('public.unaccent', $1))
+$$ language sql immutable parallel safe strict;
+
+-- title searches match anywhere in the title, which only a trigram index can speed up
+create index todos_title_search_idx on todos using gin (search_text(title) gin_trgm_ops);
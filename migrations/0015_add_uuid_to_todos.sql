@@ -0,0 +1,4 @@
+-- clients creating todos offline pick their own UUIDs, the others get a random one
+alter table todos add column if not exists uuid uuid not null default gen_random_uuid();
+
+create unique index todos_uuid_key on todos (uuid);
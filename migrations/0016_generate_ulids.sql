@@ -0,0 +1,12 @@
+-- ULIDs stored as UUIDs: a 48 bit millisecond timestamp followed by 80 random bits, so that
+-- newer todos sort after older ones
+create or replace function gen_ulid() returns uuid as $$
+  select (
+    lpad(to_hex(floor(extract(epoch from clock_timestamp()) * 1000)::bigint), 12, '0')
+    || substr(random_uuid, 1, 12)
+    || substr(random_uuid, 17, 8)
+  )::uuid
+  from (select replace(gen_random_uuid()::text, '-', '') as random_uuid) as random
+$$ language sql volatile;
+
+alter table todos alter column uuid set default gen_ulid();
@@ -0,0 +1,12 @@
+-- Tells listeners about every revision written, which wakes up long polling requests. The
+-- notifications are only delivered once the transaction writing the revision commits.
+create or replace function notify_todo_revision() returns trigger as $$
+begin
+  perform pg_notify('todo_revisions', new.id::text);
+  return null;
+end;
+$$ language plpgsql;
+
+create trigger todo_revisions_notify
+  after insert on todo_revisions
+  for each row execute function notify_todo_revision();
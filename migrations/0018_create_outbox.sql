@@ -0,0 +1,13 @@
+create table if not exists outbox (
+  id bigserial primary key,
+  event jsonb not null,
+  created_at timestamptz not null default now(),
+  attempts integer not null default 0,
+  next_attempt_at timestamptz not null default now(),
+  delivered_at timestamptz,
+  last_error text
+);
+
+-- the dispatcher only ever looks at events that still need to be delivered
+create index outbox_pending_idx on outbox (next_attempt_at) where delivered_at is null;
+create index outbox_delivered_at_idx on outbox (delivered_at) where delivered_at is not null;
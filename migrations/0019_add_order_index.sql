@@ -0,0 +1,3 @@
+-- lists are ordered by "order" and then id, which the index of todos_order_unique can't serve
+-- on its own
+create index todos_order_idx on todos ("order", id);
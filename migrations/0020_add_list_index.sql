@@ -0,0 +1,3 @@
+-- the todo list and searches show starred todos first and then go by id, todos_order_idx only
+-- serves the DAV listings ordered by "order"
+create index todos_list_idx on todos (starred desc, id);
@@ -0,0 +1,7 @@
+-- the due date each todo was last reported overdue for, so the reminder job notifies about a
+-- todo once, and again only after it's rescheduled
+create table if not exists overdue_notifications (
+  todo_id bigint primary key references todos (id) on delete cascade,
+  due_at timestamptz not null,
+  notified_at timestamptz not null default now()
+);
@@ -0,0 +1,173 @@
+//! Operator endpoints under `/admin`, authenticated with the `ADMIN_TOKEN` bearer token for the
+//! whole scope. They are disabled altogether when no token is configured.
+
+use crate::error::Error;
+use crate::jobs;
+use crate::maintenance::MaintenanceMode;
+use crate::retention::{self, RetentionPolicy};
+use crate::scheduler::{JobMetrics, JobsMetrics};
+use crate::settings::Settings;
+use crate::sync;
+use actix_web::dev::Service;
+use actix_web::http::header;
+use actix_web::{get, post, put, web, HttpRequest, HttpResponse};
+use futures_util::future;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+
+pub struct AdminSettings {
+    pub token: Option<String>,
+}
+
+/// Compares without returning early, so the token can't be guessed from response times.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Fails unless the request carries the admin token, pretending the endpoint doesn't exist
+/// when none is configured.
+fn authorize(req: &HttpRequest) -> Result<(), Error> {
+    let token = req
+        .app_data::<web::Data<AdminSettings>>()
+        .and_then(|settings| settings.token.as_deref())
+        .ok_or(Error::NotFound)?;
+    let given = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(Error::Unauthorized)?;
+
+    if constant_time_eq(given.trim().as_bytes(), token.as_bytes()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Maintenance {
+    enabled: bool,
+}
+
+#[derive(Serialize)]
+struct PoolStats {
+    size: u32,
+    idle: usize,
+}
+
+#[derive(Serialize)]
+struct Stats {
+    pool: PoolStats,
+    jobs: BTreeMap<&'static str, JobMetrics>,
+    maintenance: bool,
+}
+
+#[derive(Deserialize)]
+struct PurgeCompleted {
+    older_than_days: i32,
+}
+
+#[derive(Serialize)]
+struct Purged {
+    deleted: u64,
+}
+
+/// Registers the admin scope, every endpoint in it requires the admin token.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin")
+            .wrap_fn(|req, srv| match authorize(req.request()) {
+                Err(e) => future::Either::Left(future::ok(req.error_response(e))),
+                Ok(()) => future::Either::Right(srv.call(req)),
+            })
+            .service(stats_handler)
+            .service(purge_completed_handler)
+            .service(purge_sync_tokens_handler)
+            .service(retention_report_handler)
+            .service(reload_settings_handler)
+            .service(show_maintenance_handler)
+            .service(update_maintenance_handler),
+    );
+}
+
+#[get("/stats")]
+async fn stats_handler(
+    pool: web::Data<PgPool>,
+    jobs: web::Data<JobsMetrics>,
+    maintenance: web::Data<MaintenanceMode>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(Stats {
+        pool: PoolStats {
+            size: pool.size(),
+            idle: pool.num_idle(),
+        },
+        jobs: jobs.snapshot(),
+        maintenance: maintenance.is_enabled(),
+    })
+}
+
+/// Deletes completed todos right away, like the opt-in cleanup job does.
+#[post("/purges/completed")]
+async fn purge_completed_handler(
+    pool: web::Data<PgPool>,
+    purge: web::Json<PurgeCompleted>,
+) -> Result<HttpResponse, Error> {
+    let deleted = jobs::cleanup_completed(pool.get_ref(), purge.older_than_days).await?;
+    info!("Purged {} completed todos on request", deleted);
+
+    Ok(HttpResponse::Ok().json(Purged {
+        deleted: deleted as u64,
+    }))
+}
+
+#[post("/purges/sync-tokens")]
+async fn purge_sync_tokens_handler(pool: web::Data<PgPool>) -> Result<HttpResponse, Error> {
+    let deleted = sync::delete_expired_tokens(pool.get_ref()).await?;
+
+    Ok(HttpResponse::Ok().json(Purged { deleted }))
+}
+
+/// A dry run of the retention policies, reporting what the next run of the job would delete.
+#[get("/retention")]
+async fn retention_report_handler(
+    pool: web::Data<PgPool>,
+    retention: web::Data<RetentionPolicy>,
+) -> Result<HttpResponse, Error> {
+    let report = retention::report(pool.get_ref(), **retention).await?;
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Reloads the settings like SIGHUP does, responding with a conflict when they're invalid.
+#[post("/reload")]
+async fn reload_settings_handler(settings: web::Data<Settings>) -> Result<HttpResponse, Error> {
+    settings.reload().map_err(|errors| Error::Conflict {
+        reason: format!(
+            "the settings are invalid, the current ones are kept: {}",
+            errors.join("; ")
+        ),
+    })?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/maintenance")]
+async fn show_maintenance_handler(maintenance: web::Data<MaintenanceMode>) -> HttpResponse {
+    HttpResponse::Ok().json(Maintenance {
+        enabled: maintenance.is_enabled(),
+    })
+}
+
+#[put("/maintenance")]
+async fn update_maintenance_handler(
+    maintenance: web::Data<MaintenanceMode>,
+    update: web::Json<Maintenance>,
+) -> HttpResponse {
+    maintenance.set(update.enabled);
+
+    HttpResponse::Ok().json(Maintenance {
+        enabled: maintenance.is_enabled(),
+    })
+}
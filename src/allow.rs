@@ -0,0 +1,93 @@
+use crate::error::Error;
+use actix_web::dev::ResourceDef;
+use actix_web::http::{header, Method};
+use actix_web::{web, HttpRequest, HttpResponse};
+
+/// Methods supported by each route, used to answer OPTIONS and to tell 405 apart from 404.
+/// Needs to be kept in sync with the services registered in `main`.
+const ROUTES: &[(&str, &str)] = &[
+    ("/metrics", "GET, OPTIONS"),
+    ("/version", "GET, OPTIONS"),
+    ("/admin/stats", "GET, OPTIONS"),
+    ("/admin/purges/completed", "POST, OPTIONS"),
+    ("/admin/purges/sync-tokens", "POST, OPTIONS"),
+    ("/admin/retention", "GET, OPTIONS"),
+    ("/admin/reload", "POST, OPTIONS"),
+    ("/admin/maintenance", "GET, PUT, OPTIONS"),
+    ("/todos", "GET, HEAD, POST, DELETE, OPTIONS"),
+    ("/todos/stats", "GET, OPTIONS"),
+    ("/todos/export.xlsx", "GET, OPTIONS"),
+    ("/todos/search", "GET, HEAD, OPTIONS"),
+    ("/todos/today", "GET, HEAD, OPTIONS"),
+    ("/todos/overdue", "GET, HEAD, OPTIONS"),
+    ("/todos/calendar", "GET, OPTIONS"),
+    ("/todos/workload", "GET, OPTIONS"),
+    ("/todos/nearby", "GET, OPTIONS"),
+    ("/todos/changes", "GET, OPTIONS"),
+    ("/todos/poll", "GET, OPTIONS"),
+    (
+        "/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}",
+        "GET, HEAD, PUT, PATCH, DELETE, OPTIONS",
+    ),
+    ("/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}/duplicate", "POST, OPTIONS"),
+    ("/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}/history", "GET, OPTIONS"),
+    ("/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}/revert", "POST, OPTIONS"),
+    ("/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}/dependencies", "GET, POST, OPTIONS"),
+    (
+        "/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}/dependencies/{blocker_id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}",
+        "DELETE, OPTIONS",
+    ),
+    ("/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}/timer/start", "POST, OPTIONS"),
+    ("/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}/timer/stop", "POST, OPTIONS"),
+    ("/time-entries/report", "GET, OPTIONS"),
+    ("/undo", "POST, OPTIONS"),
+    ("/sync", "POST, OPTIONS"),
+    ("/import/todoist", "POST, OPTIONS"),
+    ("/import/trello", "POST, OPTIONS"),
+    ("/integrations/telegram", "POST, OPTIONS"),
+    ("/integrations/mailgun", "POST, OPTIONS"),
+    ("/.well-known/caldav", "GET, PROPFIND, OPTIONS"),
+    ("/dav", "PROPFIND, OPTIONS"),
+    ("/dav/todos", "PROPFIND, REPORT, OPTIONS"),
+    ("/dav/todos/{name}", "GET, PUT, DELETE, PROPFIND, OPTIONS"),
+    ("/filters", "GET, POST, OPTIONS"),
+    ("/filters/{id:\\d+}", "GET, DELETE, OPTIONS"),
+    ("/filters/{id:\\d+}/todos", "GET, HEAD, OPTIONS"),
+    ("/custom-fields", "GET, POST, OPTIONS"),
+    ("/custom-fields/{key}", "DELETE, OPTIONS"),
+];
+
+pub struct AllowedMethods(Vec<(ResourceDef, &'static str)>);
+
+impl AllowedMethods {
+    pub fn new() -> Self {
+        AllowedMethods(
+            ROUTES
+                .iter()
+                .map(|(path, allow)| (ResourceDef::new(*path), *allow))
+                .collect(),
+        )
+    }
+
+    fn for_path(&self, path: &str) -> Option<&'static str> {
+        self.0
+            .iter()
+            .find(|(resource, _)| resource.is_match(path))
+            .map(|(_, allow)| *allow)
+    }
+}
+
+/// Handles requests no service matched: OPTIONS gets the `Allow` header of the route, other
+/// methods of known routes get 405 and everything else 404.
+pub async fn fallback_handler(
+    req: HttpRequest,
+    allowed: web::Data<AllowedMethods>,
+) -> Result<HttpResponse, Error> {
+    match allowed.for_path(req.path()) {
+        Some(allow) if req.method() == Method::OPTIONS => Ok(HttpResponse::NoContent()
+            .insert_header((header::ALLOW, allow))
+            .finish()),
+        Some(allow) => Err(Error::MethodNotAllowed { allow }),
+        None => Err(Error::NotFound),
+    }
+}
@@ -0,0 +1,251 @@
+//! The `bench` subcommand, firing create, list and patch requests at a running instance from a
+//! number of concurrent clients and reporting latency percentiles for each kind of request:
+//!
+//!     todo-backend bench --url http://127.0.0.1:8080 --concurrency 16 --duration 30
+//!
+//! Every client creates its own todos and patches those, they're deleted again at the end.
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::future;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Requests of each client per round, in order.
+const ROUND: &[Operation] = &[Operation::Create, Operation::List, Operation::Patch];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Operation {
+    Create,
+    List,
+    Patch,
+}
+
+impl Operation {
+    fn as_str(self) -> &'static str {
+        match self {
+            Operation::Create => "create",
+            Operation::List => "list",
+            Operation::Patch => "patch",
+        }
+    }
+}
+
+struct Options {
+    url: String,
+    concurrency: usize,
+    duration: Duration,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut options = Options {
+            url: "http://127.0.0.1:8080".to_owned(),
+            concurrency: 8,
+            duration: Duration::from_secs(10),
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+            match arg.as_str() {
+                "--url" => options.url = value()?.trim_end_matches('/').to_owned(),
+                "--concurrency" => {
+                    options.concurrency = value()?
+                        .parse()
+                        .ok()
+                        .filter(|concurrency| *concurrency > 0)
+                        .context("--concurrency needs to be a positive number of clients")?
+                }
+                "--duration" => {
+                    options.duration = Duration::from_secs(
+                        value()?
+                            .parse()
+                            .context("--duration needs to be a number of seconds")?,
+                    )
+                }
+                _ => bail!(
+                    "unknown option {}, expected --url, --concurrency or --duration",
+                    arg
+                ),
+            }
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Deserialize)]
+struct CreatedTodo {
+    url: String,
+    completed: bool,
+}
+
+#[derive(Default)]
+struct Samples {
+    latencies: BTreeMap<Operation, Vec<Duration>>,
+    errors: BTreeMap<Operation, usize>,
+}
+
+impl Samples {
+    fn record(&mut self, operation: Operation, started: Instant, result: &Result<()>) {
+        match result {
+            Ok(()) => self
+                .latencies
+                .entry(operation)
+                .or_default()
+                .push(started.elapsed()),
+            Err(e) => {
+                debug!("{} failed: {:#}", operation.as_str(), e);
+                *self.errors.entry(operation).or_default() += 1;
+            }
+        }
+    }
+
+    fn merge(&mut self, other: Samples) {
+        for (operation, latencies) in other.latencies {
+            self.latencies
+                .entry(operation)
+                .or_default()
+                .extend(latencies);
+        }
+        for (operation, errors) in other.errors {
+            *self.errors.entry(operation).or_default() += errors;
+        }
+    }
+}
+
+async fn send(request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+    Ok(request.send().await?.error_for_status()?)
+}
+
+/// Runs rounds of requests until the deadline, returning its samples and the todos it created.
+async fn client(
+    http: reqwest::Client,
+    base_url: String,
+    number: usize,
+    deadline: Instant,
+) -> (Samples, Vec<String>) {
+    let mut samples = Samples::default();
+    let mut created: Vec<CreatedTodo> = Vec::new();
+    let mut round = 0;
+    while Instant::now() < deadline {
+        for operation in ROUND {
+            let started = Instant::now();
+            let result = match operation {
+                Operation::Create => {
+                    let title = format!("bench {}-{}", number, round);
+                    let request = http
+                        .post(format!("{}/todos", base_url))
+                        .json(&serde_json::json!({ "title": title }));
+                    match send(request).await {
+                        Ok(response) => response
+                            .json::<CreatedTodo>()
+                            .await
+                            .map(|todo| created.push(todo))
+                            .map_err(anyhow::Error::from),
+                        Err(e) => Err(e),
+                    }
+                }
+                Operation::List => {
+                    let request = http.get(format!("{}/todos?per_page=50", base_url));
+                    match send(request).await {
+                        Ok(response) => response.bytes().await.map(drop).map_err(Into::into),
+                        Err(e) => Err(e),
+                    }
+                }
+                Operation::Patch => match created.last_mut() {
+                    Some(todo) => {
+                        todo.completed = !todo.completed;
+                        let request = http
+                            .patch(&todo.url)
+                            .json(&serde_json::json!({ "completed": todo.completed }));
+                        send(request).await.map(drop)
+                    }
+                    None => continue,
+                },
+            };
+            samples.record(*operation, started, &result);
+        }
+        round += 1;
+    }
+    (samples, created.into_iter().map(|todo| todo.url).collect())
+}
+
+/// The latency below which `percentile` percent of the sorted samples fall.
+fn percentile(sorted: &[Duration], percentile: usize) -> Duration {
+    let index = (sorted.len() * percentile + 99) / 100;
+    sorted[index.saturating_sub(1).min(sorted.len() - 1)]
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn print_report(samples: &mut Samples, elapsed: Duration) {
+    println!(
+        "{:<8} {:>8} {:>7} {:>9} {:>9} {:>9} {:>9} {:>9}",
+        "request", "ok", "errors", "req/s", "p50 ms", "p90 ms", "p99 ms", "max ms"
+    );
+    for operation in ROUND {
+        let errors = samples.errors.get(operation).copied().unwrap_or(0);
+        let latencies = samples.latencies.entry(*operation).or_default();
+        latencies.sort();
+        if latencies.is_empty() {
+            println!("{:<8} {:>8} {:>7}", operation.as_str(), 0, errors);
+            continue;
+        }
+        println!(
+            "{:<8} {:>8} {:>7} {:>9.1} {:>9.2} {:>9.2} {:>9.2} {:>9.2}",
+            operation.as_str(),
+            latencies.len(),
+            errors,
+            latencies.len() as f64 / elapsed.as_secs_f64(),
+            millis(percentile(latencies, 50)),
+            millis(percentile(latencies, 90)),
+            millis(percentile(latencies, 99)),
+            millis(latencies[latencies.len() - 1]),
+        );
+    }
+}
+
+pub async fn run(args: impl Iterator<Item = String>) -> Result<()> {
+    let options = Options::parse(args)?;
+    let http = reqwest::Client::builder()
+        .pool_max_idle_per_host(options.concurrency)
+        .build()?;
+
+    println!(
+        "Benchmarking {} with {} clients for {}s",
+        options.url,
+        options.concurrency,
+        options.duration.as_secs()
+    );
+    let started = Instant::now();
+    let deadline = started + options.duration;
+    let clients = (0..options.concurrency)
+        .map(|number| client(http.clone(), options.url.clone(), number, deadline));
+    let results = future::join_all(clients).await;
+    let elapsed = started.elapsed();
+
+    let mut samples = Samples::default();
+    let mut created = Vec::new();
+    for (client_samples, client_created) in results {
+        samples.merge(client_samples);
+        created.extend(client_created);
+    }
+    print_report(&mut samples, elapsed);
+
+    // cleaning up isn't measured
+    let deletions = created.iter().map(|url| send(http.delete(url)));
+    let failed = future::join_all(deletions)
+        .await
+        .into_iter()
+        .filter(Result::is_err)
+        .count();
+    if failed > 0 {
+        warn!(
+            "Failed to delete {} of the {} todos created",
+            failed,
+            created.len()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,193 @@
+//! A circuit breaker around the database. After `failure_threshold` consecutive failed
+//! requests it opens and requests fail fast with 503 for the cool-down, instead of each one
+//! waiting for a timeout. Once the cool-down is over a single trial request is let through,
+//! closing the breaker again if it succeeds. A trial that never finishes, because the client went
+//! away or the request was cancelled, is given up on after another cool-down.
+
+use crate::error::Error;
+use crate::metrics::Metrics;
+use actix_web::dev::ServiceResponse;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { since: Instant },
+}
+
+impl State {
+    fn gauge(self) -> f64 {
+        match self {
+            State::Closed { .. } => 0.0,
+            State::Open { .. } => 1.0,
+            State::HalfOpen { .. } => 2.0,
+        }
+    }
+}
+
+pub struct CircuitBreaker {
+    state: Mutex<State>,
+    failure_threshold: u32,
+    cool_down: Duration,
+    metrics: Metrics,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cool_down: Duration, metrics: Metrics) -> Self {
+        let breaker = CircuitBreaker {
+            state: Mutex::new(State::Closed { failures: 0 }),
+            failure_threshold,
+            cool_down,
+            metrics,
+        };
+        breaker.report(State::Closed { failures: 0 });
+        breaker
+    }
+
+    fn report(&self, state: State) {
+        self.metrics.set_gauge(
+            "database_circuit_state",
+            "State of the database circuit breaker, 0 closed, 1 open, 2 half-open.",
+            state.gauge(),
+        );
+    }
+
+    /// Fails with 503 while the breaker is open, or while a trial request is already running.
+    pub fn check(&self) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } if until <= now => {
+                *state = State::HalfOpen { since: now };
+                self.report(*state);
+                Ok(())
+            }
+            State::Open { until } => Err(Error::ServiceUnavailable {
+                retry_after: retry_after(until),
+            }),
+            State::HalfOpen { since } if since + self.cool_down <= now => {
+                warn!("Trial request didn't finish, letting another one through");
+                *state = State::HalfOpen { since: now };
+                Ok(())
+            }
+            State::HalfOpen { since } => Err(Error::ServiceUnavailable {
+                retry_after: retry_after(since + self.cool_down),
+            }),
+        }
+    }
+
+    /// Records the outcome of a request that was let through. Internal errors and timeouts are
+    /// what a failing database turns into, other responses show it's reachable.
+    pub fn record<B>(&self, res: &ServiceResponse<B>) {
+        let failed = res
+            .response()
+            .error()
+            .and_then(|e| e.as_error::<Error>())
+            .map(|e| matches!(e, Error::InternalError | Error::Timeout))
+            .unwrap_or(false);
+        self.settle(failed);
+    }
+
+    /// Records a request that failed without a response.
+    pub fn record_failure(&self) {
+        self.settle(true);
+    }
+
+    fn settle(&self, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        let next = match (*state, failed) {
+            (State::Closed { .. }, false) | (State::HalfOpen { .. }, false) => {
+                State::Closed { failures: 0 }
+            }
+            (State::Closed { failures }, true) if failures + 1 < self.failure_threshold => {
+                State::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (State::Closed { .. }, true) | (State::HalfOpen { .. }, true) => {
+                warn!(
+                    "Database failing, rejecting requests for {:?}",
+                    self.cool_down
+                );
+                self.metrics.increment(
+                    "database_circuit_trips_total",
+                    "Times the database circuit breaker opened.",
+                );
+                State::Open {
+                    until: Instant::now() + self.cool_down,
+                }
+            }
+            // requests started before the breaker opened don't change anything
+            (State::Open { .. }, _) => return,
+        };
+        if let (State::HalfOpen { .. }, State::Closed { .. }) = (*state, next) {
+            info!("Database recovered, accepting requests again");
+        }
+        *state = next;
+        self.report(next);
+    }
+}
+
+/// Seconds until `until`, rounded up so clients don't retry too early.
+fn retry_after(until: Instant) -> u64 {
+    let remaining = until.saturating_duration_since(Instant::now());
+    remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    const COOL_DOWN: Duration = Duration::from_millis(20);
+
+    fn open_breaker() -> CircuitBreaker {
+        let breaker = CircuitBreaker::new(2, COOL_DOWN, Metrics::default());
+        breaker.record_failure();
+        assert!(breaker.check().is_ok());
+        breaker.record_failure();
+        breaker
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = open_breaker();
+        assert!(matches!(
+            breaker.check(),
+            Err(Error::ServiceUnavailable { .. })
+        ));
+    }
+
+    #[test]
+    fn lets_a_single_trial_through_after_the_cool_down() {
+        let breaker = open_breaker();
+        sleep(COOL_DOWN);
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_err());
+
+        breaker.settle(false);
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn failed_trials_open_the_breaker_again() {
+        let breaker = open_breaker();
+        sleep(COOL_DOWN);
+        assert!(breaker.check().is_ok());
+        breaker.record_failure();
+        assert!(breaker.check().is_err());
+    }
+
+    #[test]
+    fn unfinished_trials_are_given_up_on() {
+        let breaker = open_breaker();
+        sleep(COOL_DOWN);
+        assert!(breaker.check().is_ok());
+        sleep(COOL_DOWN);
+        assert!(breaker.check().is_ok());
+    }
+}
@@ -0,0 +1,99 @@
+use crate::negotiation::{self, Format};
+use actix_web::dev::ServiceResponse;
+use actix_web::http::{header, header::HeaderValue, Method};
+use actix_web::{HttpRequest, HttpResponse};
+use serde::Serialize;
+
+/// How long browsers and CDNs may reuse successful read responses.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheSettings {
+    pub max_age: u32,
+}
+
+fn is_read(method: &Method) -> bool {
+    method == Method::GET || method == Method::HEAD
+}
+
+/// Sets `Cache-Control` unless the handler already chose one: successful reads may be cached
+/// for a short while, everything else must not be stored.
+pub fn set_cache_control<B>(res: &mut ServiceResponse<B>, settings: CacheSettings) {
+    if res.headers().contains_key(header::CACHE_CONTROL) {
+        return;
+    }
+
+    let cacheable = is_read(res.request().method())
+        && (res.status().is_success() || res.status().as_u16() == 304);
+    let value = if cacheable {
+        format!("max-age={}", settings.max_age)
+    } else {
+        "no-store".to_owned()
+    };
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        res.headers_mut().insert(header::CACHE_CONTROL, value);
+    }
+}
+
+fn if_none_match(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == "*" || tag == etag)
+        })
+        .unwrap_or(false)
+}
+
+pub fn quote_etag(etag: &str) -> String {
+    format!("\"{}\"", etag)
+}
+
+/// Returns 304 Not Modified when the client already has the representation tagged `etag`.
+pub fn not_modified(req: &HttpRequest, etag: &str) -> Option<HttpResponse> {
+    if is_read(req.method()) && if_none_match(req, etag) {
+        Some(
+            HttpResponse::NotModified()
+                .insert_header((header::ETAG, etag.to_owned()))
+                .finish(),
+        )
+    } else {
+        None
+    }
+}
+
+/// Renders `body` in the negotiated format tagged with `etag`, or 304 Not Modified if the
+/// client already has it.
+pub fn respond_with_etag<T: Serialize>(req: &HttpRequest, etag: &str, body: &T) -> HttpResponse {
+    let etag = match negotiation::preferred_format(req) {
+        Format::MessagePack => quote_etag(&format!("{}-msgpack", etag)),
+        Format::Json | Format::Protobuf | Format::PlainText => quote_etag(etag),
+    };
+    if let Some(response) = not_modified(req, &etag) {
+        return response;
+    }
+
+    let mut response = HttpResponse::Ok();
+    response.insert_header((header::ETAG, etag));
+    negotiation::respond(req, response, body)
+}
+
+/// Renders `message` as protobuf tagged with `etag`, or 304 Not Modified if the client already
+/// has it.
+pub fn protobuf_with_etag<M: prost::Message>(
+    req: &HttpRequest,
+    etag: &str,
+    message: &M,
+) -> HttpResponse {
+    let etag = quote_etag(&format!("{}-protobuf", etag));
+    if let Some(response) = not_modified(req, &etag) {
+        return response;
+    }
+
+    HttpResponse::Ok()
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::VARY, "Accept"))
+        .content_type(negotiation::PROTOBUF)
+        .body(message.encode_to_vec())
+}
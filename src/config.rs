@@ -0,0 +1,471 @@
+//! Configuration read from the environment at startup. Every variable is validated up front and
+//! all the problems are reported together, so that a misconfigured deployment can be fixed in
+//! one go instead of one restart per mistake.
+
+use crate::admin::AdminSettings;
+use crate::caching::CacheSettings;
+use crate::crypto;
+use crate::dependencies::DependencySettings;
+use crate::deprecation::Deprecations;
+use crate::events::{Broker, EventSettings};
+use crate::ids::IdScheme;
+use crate::inbound_email::InboundEmailSettings;
+use crate::mqtt::MqttSettings;
+use crate::retention::RetentionPolicy;
+use crate::settings::Settings;
+use crate::status::Workflow;
+use crate::telegram::TelegramSettings;
+use reqwest::Url;
+use sqlx::postgres::PgConnectOptions;
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub struct Config {
+    pub database: PgConnectOptions,
+    pub database_timeout_ms: u64,
+    pub slow_query_threshold: Duration,
+    pub host: String,
+    pub port: u16,
+    pub scheme: String,
+    /// The public address of the API, when it differs from the one the server listens on.
+    pub base_url: Option<Url>,
+    pub base_path: Option<String>,
+    pub trust_proxy: bool,
+    pub metrics_addr: Option<String>,
+    pub slack_webhook_url: Option<String>,
+    /// Where change events are published to, nowhere when `None`.
+    pub events: Option<EventSettings>,
+    /// The broker the open todos are published to, nothing is published when `None`.
+    pub mqtt: Option<MqttSettings>,
+    /// The Telegram bot is disabled when `None`.
+    pub telegram: Option<TelegramSettings>,
+    /// Emails aren't turned into todos when `None`.
+    pub inbound_email: Option<InboundEmailSettings>,
+    pub cache: CacheSettings,
+    pub rebalance_interval: Duration,
+    pub breaker_threshold: u32,
+    pub breaker_cool_down: Duration,
+    pub cleanup_completed_after_days: Option<i32>,
+    pub retention: RetentionPolicy,
+    pub dependencies: DependencySettings,
+    pub workflow: Workflow,
+    pub maintenance: bool,
+    pub admin: AdminSettings,
+    pub id_scheme: IdScheme,
+    pub deprecations: Deprecations,
+    pub settings: Settings,
+}
+
+/// Everything wrong with the configuration, one problem per line.
+#[derive(Debug)]
+pub struct ConfigErrors(Vec<String>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "The configuration has {} problem(s):", self.0.len())?;
+        for error in &self.0 {
+            writeln!(f, "  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads variables while collecting the problems with them.
+#[derive(Default)]
+struct Vars {
+    errors: Vec<String>,
+}
+
+impl Vars {
+    fn optional(&self, name: &str) -> Option<String> {
+        env::var(name).ok().filter(|value| !value.trim().is_empty())
+    }
+
+    fn required(&mut self, name: &str, expected: &str) -> Option<String> {
+        let value = self.optional(name);
+        if value.is_none() {
+            self.errors.push(format!(
+                "{} is not set in the environment or .env, it needs to be {}",
+                name, expected
+            ));
+        }
+        value
+    }
+
+    fn check<T, E: fmt::Display>(&mut self, name: &str, result: Result<T, E>) -> Option<T> {
+        result
+            .map_err(|e| self.errors.push(format!("{} is invalid: {}", name, e)))
+            .ok()
+    }
+
+    fn parse_optional<T: FromStr>(&mut self, name: &str, expected: &str) -> Option<T> {
+        let value = self.optional(name)?;
+        match value.trim().parse() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                self.errors.push(format!(
+                    "{} needs to be {}, not {:?}",
+                    name, expected, value
+                ));
+                None
+            }
+        }
+    }
+
+    fn parse<T: FromStr>(&mut self, name: &str, default: T, expected: &str) -> T {
+        self.parse_optional(name, expected).unwrap_or(default)
+    }
+
+    /// How often to run a job, which can't be every 0 seconds.
+    fn interval(&mut self, name: &str, default_secs: u64) -> Duration {
+        let secs = self.parse(name, default_secs, "a positive number of seconds");
+        if secs == 0 {
+            self.errors.push(format!(
+                "{} needs to be a positive number of seconds, not 0",
+                name
+            ));
+            return Duration::from_secs(default_secs);
+        }
+        Duration::from_secs(secs)
+    }
+
+    /// A timeout Postgres takes as well, which disables it with 0 and refuses more than it fits
+    /// in an int.
+    fn timeout_ms(&mut self, name: &str, default_ms: u64) -> u64 {
+        let ms = self.parse(name, default_ms, "a positive number of milliseconds");
+        if ms == 0 || ms > i32::MAX as u64 {
+            self.errors.push(format!(
+                "{} needs to be a number of milliseconds from 1 to {}, not {}",
+                name,
+                i32::MAX,
+                ms
+            ));
+            return default_ms;
+        }
+        ms
+    }
+
+    /// How many days to keep something for, which can't be less than a day.
+    fn days(&mut self, name: &str) -> Option<i32> {
+        let days = self.parse_optional(name, "a positive number of days")?;
+        if days < 1 {
+            self.errors.push(format!(
+                "{} needs to be a positive number of days, not {}",
+                name, days
+            ));
+            return None;
+        }
+        Some(days)
+    }
+
+    fn flag(&mut self, name: &str) -> bool {
+        match self.optional(name).as_deref().map(str::trim) {
+            None | Some("false") | Some("0") => false,
+            Some("true") | Some("1") => true,
+            Some(value) => {
+                self.errors.push(format!(
+                    "{} needs to be true, false, 1 or 0, not {:?}",
+                    name, value
+                ));
+                false
+            }
+        }
+    }
+
+    fn database(&mut self) -> Option<PgConnectOptions> {
+        let url = self.required(
+            "DATABASE_URL",
+            "a Postgres URL like postgres://user@host/db",
+        )?;
+        if !url.starts_with("postgres://") && !url.starts_with("postgresql://") {
+            self.errors.push(format!(
+                "DATABASE_URL needs to start with postgres:// or postgresql://, not {:?}",
+                url.split("://").next().unwrap_or_default()
+            ));
+            return None;
+        }
+        let options = url.parse::<PgConnectOptions>();
+        self.check("DATABASE_URL", options)
+    }
+
+    fn scheme(&mut self) -> String {
+        match self.optional("SCHEME") {
+            None => "http".to_owned(),
+            Some(scheme) if scheme == "http" || scheme == "https" => scheme,
+            Some(scheme) => {
+                self.errors.push(format!(
+                    "SCHEME needs to be http or https, not {:?}",
+                    scheme
+                ));
+                "http".to_owned()
+            }
+        }
+    }
+
+    fn url(&mut self, name: &str) -> Option<Url> {
+        let value = self.optional(name)?;
+        match Url::parse(&value) {
+            Ok(url) if (url.scheme() == "http" || url.scheme() == "https") && url.has_host() => {
+                Some(url)
+            }
+            _ => {
+                self.errors.push(format!(
+                    "{} needs to be an absolute http or https URL, not {:?}",
+                    name, value
+                ));
+                None
+            }
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Config, ConfigErrors> {
+        let mut vars = Vars::default();
+
+        let database = vars.database();
+        let database_timeout_ms = vars.timeout_ms("DATABASE_TIMEOUT_MS", 5000);
+        let slow_query_threshold =
+            vars.parse("SLOW_QUERY_THRESHOLD_MS", 500, "a number of milliseconds");
+        let host = vars
+            .optional("HOST")
+            .unwrap_or_else(|| "127.0.0.1".to_owned());
+        let port = vars.parse("PORT", 8080, "a port in the 0-65535 range");
+        let scheme = vars.scheme();
+        let base_url = vars.url("BASE_URL");
+        let base_path = vars.optional("BASE_PATH");
+        let trust_proxy = vars.flag("TRUST_PROXY");
+        let metrics_addr = vars.optional("METRICS_ADDR");
+        let slack_webhook_url = vars.url("SLACK_WEBHOOK_URL").map(String::from);
+        let events = match vars.optional("EVENTS_BROKER") {
+            Some(broker) => {
+                let broker = broker.parse::<Broker>();
+                let broker = vars.check("EVENTS_BROKER", broker);
+                let url = vars.required("EVENTS_URL", "the address of the event broker");
+                let topic = vars
+                    .optional("EVENTS_TOPIC")
+                    .unwrap_or_else(|| "todos".to_owned());
+                match (broker, url) {
+                    (Some(broker), Some(url)) => Some(EventSettings { broker, url, topic }),
+                    _ => None,
+                }
+            }
+            None => None,
+        };
+        let mqtt = match vars.optional("MQTT_URL") {
+            Some(url) => {
+                let topic = vars
+                    .optional("MQTT_TOPIC")
+                    .unwrap_or_else(|| "todos".to_owned());
+                let settings = MqttSettings::parse(&url, topic);
+                vars.check("MQTT_URL", settings)
+            }
+            None => None,
+        };
+        let telegram = match vars.optional("TELEGRAM_BOT_TOKEN") {
+            Some(token) => {
+                let webhook_secret = vars.required(
+                    "TELEGRAM_WEBHOOK_SECRET",
+                    "the secret_token the bot's webhook was set with",
+                );
+                let chats = vars
+                    .required("TELEGRAM_CHATS", "a comma separated list of chat ids")
+                    .map(|chats| {
+                        chats
+                            .split(',')
+                            .map(|chat| chat.trim().parse::<i64>())
+                            .collect::<Result<Vec<i64>, _>>()
+                    });
+                let chats = match chats {
+                    Some(chats) => vars.check("TELEGRAM_CHATS", chats),
+                    None => None,
+                };
+                match (webhook_secret, chats) {
+                    (Some(webhook_secret), Some(chats)) => Some(TelegramSettings {
+                        token,
+                        webhook_secret,
+                        chats,
+                    }),
+                    _ => None,
+                }
+            }
+            None => None,
+        };
+        let inbound_email = match vars.optional("MAILGUN_SIGNING_KEY") {
+            Some(signing_key) => vars
+                .required(
+                    "MAILGUN_SENDERS",
+                    "a comma separated list of email addresses",
+                )
+                .map(|senders| InboundEmailSettings {
+                    signing_key,
+                    senders: senders
+                        .split(',')
+                        .map(|sender| sender.trim().to_lowercase())
+                        .filter(|sender| !sender.is_empty())
+                        .collect(),
+                }),
+            None => None,
+        };
+        let cache = CacheSettings {
+            max_age: vars.parse("CACHE_MAX_AGE_SECS", 5, "a number of seconds"),
+        };
+        let rebalance_interval = vars.interval("ORDER_REBALANCE_INTERVAL_SECS", 3600);
+        let breaker_threshold = vars.parse(
+            "CIRCUIT_BREAKER_THRESHOLD",
+            5,
+            "a number of failed requests",
+        );
+        let breaker_cool_down =
+            vars.parse("CIRCUIT_BREAKER_COOL_DOWN_SECS", 30, "a number of seconds");
+        let cleanup_completed_after_days = vars.days("CLEANUP_COMPLETED_AFTER_DAYS");
+        let retention = RetentionPolicy {
+            revisions_days: vars.days("RETAIN_REVISIONS_DAYS"),
+            tombstones_days: vars.days("RETAIN_TOMBSTONES_DAYS"),
+        };
+        let dependencies = DependencySettings {
+            enforce: vars.flag("ENFORCE_DEPENDENCIES"),
+        };
+        let workflow = match vars.optional("STATUS_TRANSITIONS") {
+            Some(transitions) => {
+                let workflow = Workflow::parse(&transitions);
+                vars.check("STATUS_TRANSITIONS", workflow)
+                    .unwrap_or_default()
+            }
+            None => Workflow::default(),
+        };
+        let maintenance = vars.flag("MAINTENANCE_MODE");
+        let admin = AdminSettings {
+            token: vars.optional("ADMIN_TOKEN"),
+        };
+        let id_scheme = match vars.optional("ID_SCHEME") {
+            Some(scheme) => {
+                let id_scheme = scheme.parse::<IdScheme>();
+                vars.check("ID_SCHEME", id_scheme)
+                    .unwrap_or(IdScheme::Numeric)
+            }
+            None => IdScheme::Numeric,
+        };
+        let deprecations = match vars.optional("DEPRECATED_ROUTES") {
+            Some(routes) => {
+                let deprecations = Deprecations::parse(&routes);
+                vars.check("DEPRECATED_ROUTES", deprecations)
+                    .unwrap_or_default()
+            }
+            None => Deprecations::default(),
+        };
+        if let Some(key) = vars.optional("TITLE_ENCRYPTION_KEY") {
+            let initialized = crypto::init(&key);
+            vars.check("TITLE_ENCRYPTION_KEY", initialized);
+        }
+        let settings = Settings::load()
+            .map_err(|errors| vars.errors.extend(errors))
+            .ok();
+
+        match (database, settings) {
+            (Some(database), Some(settings)) if vars.errors.is_empty() => Ok(Config {
+                database,
+                database_timeout_ms,
+                slow_query_threshold: Duration::from_millis(slow_query_threshold),
+                host,
+                port,
+                scheme,
+                base_url,
+                base_path,
+                trust_proxy,
+                metrics_addr,
+                slack_webhook_url,
+                events,
+                mqtt,
+                telegram,
+                inbound_email,
+                cache,
+                rebalance_interval,
+                breaker_threshold,
+                breaker_cool_down: Duration::from_secs(breaker_cool_down),
+                cleanup_completed_after_days,
+                retention,
+                dependencies,
+                workflow,
+                maintenance,
+                admin,
+                id_scheme,
+                deprecations,
+                settings,
+            }),
+            _ => Err(ConfigErrors(vars.errors)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads a variable set to `value`, each test using its own names since tests share the
+    /// environment.
+    fn read<T>(
+        name: &str,
+        value: &str,
+        read: impl FnOnce(&mut Vars, &str) -> T,
+    ) -> (T, Vec<String>) {
+        env::set_var(name, value);
+        let mut vars = Vars::default();
+        let result = read(&mut vars, name);
+        env::remove_var(name);
+        (result, vars.errors)
+    }
+
+    #[test]
+    fn days_need_to_be_positive() {
+        for value in ["0", "-30"] {
+            let (days, errors) = read("TEST_NON_POSITIVE_DAYS", value, Vars::days);
+            assert_eq!(days, None);
+            assert_eq!(errors.len(), 1, "{}", value);
+        }
+        let (days, errors) = read("TEST_POSITIVE_DAYS", "30", Vars::days);
+        assert_eq!(days, Some(30));
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn days_need_to_be_numbers() {
+        let (days, errors) = read("TEST_UNPARSABLE_DAYS", "a month", Vars::days);
+        assert_eq!(days, None);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn unset_days_keep_things_forever() {
+        let mut vars = Vars::default();
+        assert_eq!(vars.days("TEST_UNSET_DAYS"), None);
+        assert!(vars.errors.is_empty());
+    }
+
+    #[test]
+    fn timeouts_need_to_fit_postgres() {
+        for value in ["0", "2147483648"] {
+            let (ms, errors) = read("TEST_INVALID_TIMEOUT_MS", value, |vars, name| {
+                vars.timeout_ms(name, 5000)
+            });
+            assert_eq!(ms, 5000);
+            assert_eq!(errors.len(), 1, "{}", value);
+        }
+        let (ms, errors) = read("TEST_VALID_TIMEOUT_MS", "250", |vars, name| {
+            vars.timeout_ms(name, 5000)
+        });
+        assert_eq!(ms, 250);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn intervals_need_to_be_positive() {
+        let (interval, errors) = read("TEST_ZERO_INTERVAL_SECS", "0", |vars, name| {
+            vars.interval(name, 60)
+        });
+        assert_eq!(interval, Duration::from_secs(60));
+        assert_eq!(errors.len(), 1);
+    }
+}
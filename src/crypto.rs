@@ -0,0 +1,90 @@
+//! Optional encryption of todo titles at rest with AES-256-GCM, for deployments that treat the
+//! contents of todos as sensitive. Titles are encrypted right before they're written and
+//! decrypted right after they're read, including the snapshots kept in `todo_revisions`, so the
+//! rest of the code only ever sees plaintext. Titles stored before a key was configured are read
+//! as they are.
+//!
+//! The database can't look into encrypted titles, so searches only match titles still stored in
+//! plaintext.
+
+use crate::Todo;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use serde_json::Value;
+use std::sync::OnceLock;
+
+/// Marks encrypted titles, the version leaves room for changing the scheme later.
+const PREFIX: &str = "enc:v1:";
+const NONCE_LENGTH: usize = 12;
+
+/// Set once at startup, todos are read and written from too many places to pass it around.
+static CIPHER: OnceLock<Aes256Gcm> = OnceLock::new();
+
+/// Enables encryption with a 256 bit key encoded in base64.
+pub fn init(key: &str) -> Result<(), String> {
+    let key = base64::decode(key.trim()).map_err(|error| error.to_string())?;
+    let cipher = Aes256Gcm::new_from_slice(&key)
+        .map_err(|_| format!("the key is {} bytes long instead of 32", key.len()))?;
+    CIPHER
+        .set(cipher)
+        .map_err(|_| "encryption was already initialized".to_owned())
+}
+
+pub fn encrypt_title(title: &str) -> String {
+    let cipher = match CIPHER.get() {
+        Some(cipher) => cipher,
+        None => return title.to_owned(),
+    };
+
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, title.as_bytes())
+        .expect("encrypting with AES-GCM only fails for inputs of gigabytes");
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    format!("{}{}", PREFIX, base64::encode(sealed))
+}
+
+fn decryption_error(reason: &str) -> sqlx::Error {
+    sqlx::Error::Decode(format!("failed to decrypt a title: {}", reason).into())
+}
+
+/// Fails like a column that can't be decoded, so it can be used wherever rows are read.
+pub fn decrypt_title(stored: String) -> Result<String, sqlx::Error> {
+    let sealed = match stored.strip_prefix(PREFIX) {
+        Some(sealed) => sealed,
+        None => return Ok(stored),
+    };
+    let cipher = CIPHER
+        .get()
+        .ok_or_else(|| decryption_error("no encryption key is configured"))?;
+
+    let sealed = base64::decode(sealed).map_err(|_| decryption_error("invalid base64"))?;
+    if sealed.len() < NONCE_LENGTH {
+        return Err(decryption_error("too short"));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| decryption_error("wrong key or tampered with"))?;
+    String::from_utf8(plaintext).map_err(|_| decryption_error("not UTF-8"))
+}
+
+pub fn decrypt(mut todo: Todo) -> Result<Todo, sqlx::Error> {
+    todo.title = decrypt_title(todo.title)?;
+    Ok(todo)
+}
+
+/// Encrypts the title of a todo serialized for `todo_revisions`.
+pub fn encrypt_snapshot(snapshot: &mut Value) {
+    if let Some(Value::String(title)) = snapshot.get_mut("title") {
+        *title = encrypt_title(title);
+    }
+}
+
+pub fn decrypt_snapshot(snapshot: &mut Value) -> Result<(), sqlx::Error> {
+    if let Some(Value::String(title)) = snapshot.get_mut("title") {
+        *title = decrypt_title(std::mem::take(title))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,269 @@
+//! User defined fields, e.g. a "priority" select or an "estimate" number. Definitions are
+//! global and values are stored per todo in its `custom_fields` JSON object.
+
+use crate::error::Error;
+use crate::transaction::Tx;
+use crate::validation::{normalize_title, Validate, ValidationErrors};
+use actix_web::{delete, get, post, web, HttpResponse};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+const MAX_KEY_LENGTH: usize = 50;
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_TEXT_LENGTH: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldKind {
+    Text,
+    Number,
+    Date,
+    Select,
+}
+
+impl FieldKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            FieldKind::Text => "text",
+            FieldKind::Number => "number",
+            FieldKind::Date => "date",
+            FieldKind::Select => "select",
+        }
+    }
+}
+
+impl FromStr for FieldKind {
+    type Err = ();
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind {
+            "text" => Ok(FieldKind::Text),
+            "number" => Ok(FieldKind::Number),
+            "date" => Ok(FieldKind::Date),
+            "select" => Ok(FieldKind::Select),
+            _ => Err(()),
+        }
+    }
+}
+
+struct CustomFieldRow {
+    key: String,
+    name: String,
+    kind: String,
+    options: Value,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct CustomField {
+    key: String,
+    name: String,
+    kind: FieldKind,
+    /// The values a select field can take, empty for the other kinds.
+    options: Vec<String>,
+    created_at: DateTime<Utc>,
+}
+
+impl From<CustomFieldRow> for CustomField {
+    fn from(row: CustomFieldRow) -> Self {
+        CustomField {
+            key: row.key,
+            name: row.name,
+            kind: row.kind.parse().unwrap_or(FieldKind::Text),
+            options: serde_json::from_value(row.options).unwrap_or_default(),
+            created_at: row.created_at,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewCustomField {
+    key: String,
+    name: String,
+    kind: FieldKind,
+    #[serde(default)]
+    options: Vec<String>,
+}
+
+impl Validate for NewCustomField {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+
+        let key_is_valid = self
+            .key
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if self.key.is_empty() {
+            errors.add("key", "blank", &[]);
+        } else if self.key.len() > MAX_KEY_LENGTH {
+            errors.add("key", "too_long", &[("max", MAX_KEY_LENGTH.to_string())]);
+        } else if !key_is_valid {
+            errors.add("key", "invalid_key", &[]);
+        }
+
+        let name = normalize_title(&self.name);
+        if name.is_empty() {
+            errors.add("name", "blank", &[]);
+        } else if name.chars().count() > MAX_NAME_LENGTH {
+            errors.add("name", "too_long", &[("max", MAX_NAME_LENGTH.to_string())]);
+        }
+
+        if self.kind == FieldKind::Select && self.options.iter().all(|o| o.trim().is_empty()) {
+            errors.add("options", "blank", &[]);
+        }
+
+        errors.into_result()
+    }
+}
+
+/// Checks a single value against the definition of its field, `null` removes values and is
+/// always accepted.
+fn validate_value(errors: &mut ValidationErrors, field: &CustomField, value: &Value) {
+    let is_valid = match (field.kind, value) {
+        (_, Value::Null) => true,
+        (FieldKind::Text, Value::String(text)) => text.chars().count() <= MAX_TEXT_LENGTH,
+        (FieldKind::Number, Value::Number(_)) => true,
+        (FieldKind::Date, Value::String(date)) => {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok()
+        }
+        (FieldKind::Select, Value::String(option)) => field.options.contains(option),
+        _ => false,
+    };
+
+    if !is_valid {
+        errors.add(
+            "custom_fields",
+            "invalid_custom_field",
+            &[
+                ("key", field.key.clone()),
+                ("kind", field.kind.as_str().to_owned()),
+            ],
+        );
+    }
+}
+
+/// Validates values sent by a client against the field definitions.
+pub async fn validate_values(
+    tx: &mut Transaction<'_, Postgres>,
+    values: &Map<String, Value>,
+) -> Result<(), Error> {
+    if values.is_empty() {
+        return Ok(());
+    }
+
+    let fields = sqlx::query_as!(CustomFieldRow, r#"SELECT * FROM custom_fields"#)
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(|row| (row.key.clone(), CustomField::from(row)))
+        .collect::<HashMap<String, CustomField>>();
+
+    let mut errors = ValidationErrors::default();
+    for (key, value) in values {
+        match fields.get(key) {
+            Some(field) => validate_value(&mut errors, field, value),
+            None => errors.add(
+                "custom_fields",
+                "unknown_custom_field",
+                &[("key", key.clone())],
+            ),
+        }
+    }
+    errors.into_result()?;
+
+    Ok(())
+}
+
+/// Applies changed values on top of the current ones, `null` values remove the field.
+pub fn merge(current: &Value, changes: &Map<String, Value>) -> Value {
+    let mut merged = current.as_object().cloned().unwrap_or_default();
+    for (key, value) in changes {
+        if value.is_null() {
+            merged.remove(key);
+        } else {
+            merged.insert(key.clone(), value.clone());
+        }
+    }
+    Value::Object(merged)
+}
+
+/// Splits a `key:value` filter on a custom field.
+pub fn parse_filter(filter: &str) -> Result<(String, String), Error> {
+    let mut parts = filter.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(Error::InvalidQuery {
+            reason: "custom_field needs to look like key:value".to_owned(),
+        }),
+    }
+}
+
+#[get("/custom-fields")]
+pub async fn custom_fields_list_handler(pool: web::Data<PgPool>) -> Result<HttpResponse, Error> {
+    let fields = sqlx::query_as!(
+        CustomFieldRow,
+        r#"SELECT * FROM custom_fields ORDER BY created_at, key"#
+    )
+    .fetch_all(pool.get_ref())
+    .await?
+    .into_iter()
+    .map(CustomField::from)
+    .collect::<Vec<CustomField>>();
+
+    Ok(HttpResponse::Ok().json(fields))
+}
+
+#[post("/custom-fields")]
+pub async fn create_custom_field_handler(
+    field: web::Json<NewCustomField>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, Error> {
+    field.validate()?;
+
+    let options = match field.kind {
+        FieldKind::Select => field
+            .options
+            .iter()
+            .map(|option| option.trim().to_owned())
+            .filter(|option| !option.is_empty())
+            .collect(),
+        _ => Vec::new(),
+    };
+    let field = sqlx::query_as!(
+        CustomFieldRow,
+        r#"INSERT INTO custom_fields (key, name, kind, options) VALUES ($1, $2, $3, $4) RETURNING key, name, kind, options, created_at"#,
+        field.key,
+        normalize_title(&field.name),
+        field.kind.as_str(),
+        serde_json::json!(options)
+    )
+    .fetch_one(pool.get_ref())
+    .await?;
+
+    Ok(HttpResponse::Ok().json(CustomField::from(field)))
+}
+
+/// Removes a field definition along with its values on every todo.
+#[delete("/custom-fields/{key}")]
+pub async fn delete_custom_field_handler(
+    key: web::Path<String>,
+    tx: Tx,
+) -> Result<HttpResponse, Error> {
+    let mut tx = tx.lock().await?;
+    let result = sqlx::query!(r#"DELETE FROM custom_fields WHERE key = $1"#, key.as_str())
+        .execute(&mut *tx)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    sqlx::query!(r#"UPDATE todos SET custom_fields = custom_fields - $1, version = version + 1 WHERE custom_fields ? $1"#, key.as_str())
+        .execute(&mut *tx)
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
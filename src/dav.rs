@@ -0,0 +1,534 @@
+//! A minimal CalDAV server, so that native clients like Apple Reminders, Tasks.org and
+//! Thunderbird can sync the todos as tasks. `/dav` is the principal and its calendar home, with
+//! `/dav/todos` as its one calendar, holding every todo as `/dav/todos/<uuid>.ics`.
+//!
+//! Clients notice changes through the calendar's `getctag`, the latest revision, and the
+//! `getetag` of each todo. Only what these clients need is supported: PROPFIND, the
+//! calendar-query and calendar-multiget REPORTs, and GET, PUT and DELETE of single todos.
+//! Properties are returned whether they were asked for or not.
+
+use crate::allow;
+use crate::dependencies;
+use crate::error::Error;
+use crate::history::{self, Action};
+use crate::ical::{self, VTodo};
+use crate::poll;
+use crate::status::Status;
+use crate::transaction::Tx;
+use crate::validation::{normalize_title, validate_title, ValidationErrors};
+use crate::{crypto, next_order, RoutingService, Todo, TodoServices};
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const CALENDAR_PATH: &str = "/dav/todos";
+const CALENDAR_NAME: &str = "Todos";
+const CONTENT_TYPE: &str = "text/calendar; charset=utf-8; component=vtodo";
+const XML_CONTENT_TYPE: &str = "application/xml; charset=utf-8";
+
+fn method(name: &str) -> Method {
+    Method::from_bytes(name.as_bytes()).expect("a valid method name")
+}
+
+/// Registers the DAV resources, methods they don't support are answered like elsewhere.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/.well-known/caldav").to(well_known_handler))
+        .service(
+            web::resource("/dav")
+                .route(web::method(method("PROPFIND")).to(propfind_root_handler))
+                .route(web::method(Method::OPTIONS).to(options_handler))
+                .default_service(web::route().to(allow::fallback_handler)),
+        )
+        .service(
+            web::resource(CALENDAR_PATH)
+                .route(web::method(method("PROPFIND")).to(propfind_calendar_handler))
+                .route(web::method(method("REPORT")).to(report_handler))
+                .route(web::method(Method::OPTIONS).to(options_handler))
+                .default_service(web::route().to(allow::fallback_handler)),
+        )
+        .service(
+            web::resource("/dav/todos/{name}")
+                .route(web::get().to(get_todo_handler))
+                .route(web::put().to(put_todo_handler))
+                .route(web::delete().to(delete_todo_handler))
+                .route(web::method(method("PROPFIND")).to(propfind_todo_handler))
+                .route(web::method(Method::OPTIONS).to(options_handler))
+                .default_service(web::route().to(allow::fallback_handler)),
+        );
+}
+
+/// Todos are stored as `<uuid>.ics`, other names don't exist.
+fn uuid_of(name: &str) -> Option<Uuid> {
+    Uuid::parse_str(name.strip_suffix(".ics")?).ok()
+}
+
+fn href(routing: &RoutingService, path: &str) -> String {
+    format!("{}{}", routing.base_path, path)
+}
+
+fn todo_href(routing: &RoutingService, todo: &Todo) -> String {
+    href(routing, &format!("{}/{}.ics", CALENDAR_PATH, todo.uuid))
+}
+
+/// Differs between todos, and between a todo and one created again with its UUID.
+fn etag(todo: &Todo) -> String {
+    format!("\"{}-{}\"", todo.id, todo.version)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Whether the request asks for the direct members of a collection as well, `infinity` is
+/// treated like 1 since nothing is nested deeper.
+fn includes_members(req: &HttpRequest) -> bool {
+    let depth = req
+        .headers()
+        .get("Depth")
+        .and_then(|value| value.to_str().ok());
+    depth.map(str::trim) != Some("0")
+}
+
+/// Checks `If-Match` and `If-None-Match: *`, with which clients avoid overwriting changes they
+/// haven't seen.
+fn check_preconditions(req: &HttpRequest, existing: Option<&Todo>) -> Result<(), Error> {
+    let value = |name: header::HeaderName| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+    };
+    if let Some(expected) = value(header::IF_MATCH) {
+        let matches = existing.map_or(false, |todo| {
+            let etag = etag(todo);
+            expected
+                .split(',')
+                .any(|tag| tag.trim() == "*" || tag.trim() == etag)
+        });
+        if !matches {
+            return Err(Error::PreconditionFailed);
+        }
+    }
+    if value(header::IF_NONE_MATCH) == Some("*") && existing.is_some() {
+        return Err(Error::PreconditionFailed);
+    }
+    Ok(())
+}
+
+/// The start tags named `name` in any namespace, empty-element ones included, with what follows
+/// them. Enough for the flat bodies clients send, without a full XML parser.
+fn start_tags<'a>(xml: &'a str, name: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+    xml.split('<').skip(1).filter_map(move |part| {
+        let (tag, following) = part.split_once('>')?;
+        let tag_name = tag.split(|c: char| c.is_whitespace() || c == '/').next()?;
+        let local_name = tag_name.rsplit(':').next()?;
+        (local_name == name).then(|| (tag, following))
+    })
+}
+
+/// A 207 response, built up one resource at a time.
+struct Multistatus(String);
+
+impl Multistatus {
+    fn new() -> Self {
+        Multistatus(
+            r#"<?xml version="1.0" encoding="utf-8"?><d:multistatus xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav" xmlns:cs="http://calendarserver.org/ns/">"#
+                .to_owned(),
+        )
+    }
+
+    fn found(&mut self, href: &str, props: &str) {
+        self.0.push_str(&format!(
+            "<d:response><d:href>{}</d:href><d:propstat><d:prop>{}</d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>",
+            escape(href),
+            props
+        ));
+    }
+
+    fn missing(&mut self, href: &str) {
+        self.0.push_str(&format!(
+            "<d:response><d:href>{}</d:href><d:status>HTTP/1.1 404 Not Found</d:status></d:response>",
+            escape(href)
+        ));
+    }
+
+    fn respond(mut self) -> HttpResponse {
+        self.0.push_str("</d:multistatus>");
+        HttpResponse::build(StatusCode::MULTI_STATUS)
+            .content_type(XML_CONTENT_TYPE)
+            .body(self.0)
+    }
+}
+
+fn root_props(routing: &RoutingService) -> String {
+    let root = escape(&href(routing, "/dav/"));
+    format!(
+        "<d:resourcetype><d:collection/><d:principal/></d:resourcetype>\
+         <d:displayname>{name}</d:displayname>\
+         <d:current-user-principal><d:href>{root}</d:href></d:current-user-principal>\
+         <d:principal-URL><d:href>{root}</d:href></d:principal-URL>\
+         <c:calendar-home-set><d:href>{root}</d:href></c:calendar-home-set>",
+        name = CALENDAR_NAME,
+        root = root
+    )
+}
+
+fn calendar_props(ctag: i64) -> String {
+    format!(
+        "<d:resourcetype><d:collection/><c:calendar/></d:resourcetype>\
+         <d:displayname>{}</d:displayname>\
+         <c:supported-calendar-component-set><c:comp name=\"VTODO\"/></c:supported-calendar-component-set>\
+         <d:current-user-privilege-set><d:privilege><d:read/></d:privilege><d:privilege><d:write/></d:privilege></d:current-user-privilege-set>\
+         <cs:getctag>{}</cs:getctag>",
+        CALENDAR_NAME, ctag
+    )
+}
+
+fn todo_props(todo: &Todo, calendar_data: bool) -> String {
+    let mut props = format!(
+        "<d:resourcetype/><d:getcontenttype>{}</d:getcontenttype><d:getetag>{}</d:getetag>",
+        CONTENT_TYPE,
+        escape(&etag(todo))
+    );
+    if calendar_data {
+        props.push_str(&format!(
+            "<c:calendar-data>{}</c:calendar-data>",
+            escape(&ical::to_calendar(todo))
+        ));
+    }
+    props
+}
+
+async fn all_todos(pool: &PgPool) -> Result<Vec<Todo>, sqlx::Error> {
+    sqlx::query_as!(Todo, r#"SELECT * FROM todos ORDER BY "order", id"#)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(crypto::decrypt)
+        .collect()
+}
+
+async fn find_todo(pool: &PgPool, uuid: Uuid) -> Result<Option<Todo>, sqlx::Error> {
+    sqlx::query_as!(Todo, r#"SELECT * FROM todos WHERE uuid = $1"#, uuid)
+        .fetch_optional(pool)
+        .await?
+        .map(crypto::decrypt)
+        .transpose()
+}
+
+/// Sends clients looking for the server (RFC 6764) to the principal.
+async fn well_known_handler(routing: RoutingService) -> HttpResponse {
+    HttpResponse::MovedPermanently()
+        .insert_header((header::LOCATION, href(&routing, "/dav/")))
+        .finish()
+}
+
+async fn options_handler() -> HttpResponse {
+    HttpResponse::NoContent()
+        .insert_header((header::ALLOW, "OPTIONS, GET, PUT, DELETE, PROPFIND, REPORT"))
+        .insert_header(("DAV", "1, calendar-access"))
+        .finish()
+}
+
+async fn propfind_root_handler(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    routing: RoutingService,
+) -> Result<HttpResponse, Error> {
+    let mut multistatus = Multistatus::new();
+    multistatus.found(&href(&routing, "/dav/"), &root_props(&routing));
+    if includes_members(&req) {
+        let ctag = poll::latest_revision(&pool).await?;
+        let calendar = href(&routing, &format!("{}/", CALENDAR_PATH));
+        multistatus.found(&calendar, &calendar_props(ctag));
+    }
+    Ok(multistatus.respond())
+}
+
+async fn propfind_calendar_handler(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    routing: RoutingService,
+) -> Result<HttpResponse, Error> {
+    let ctag = poll::latest_revision(&pool).await?;
+    let mut multistatus = Multistatus::new();
+    let calendar = href(&routing, &format!("{}/", CALENDAR_PATH));
+    multistatus.found(&calendar, &calendar_props(ctag));
+    if includes_members(&req) {
+        for todo in all_todos(&pool).await? {
+            multistatus.found(&todo_href(&routing, &todo), &todo_props(&todo, false));
+        }
+    }
+    Ok(multistatus.respond())
+}
+
+async fn propfind_todo_handler(
+    name: web::Path<String>,
+    pool: web::Data<PgPool>,
+    routing: RoutingService,
+) -> Result<HttpResponse, Error> {
+    let uuid = uuid_of(&name).ok_or(Error::NotFound)?;
+    let todo = find_todo(&pool, uuid).await?.ok_or(Error::NotFound)?;
+    let mut multistatus = Multistatus::new();
+    multistatus.found(&todo_href(&routing, &todo), &todo_props(&todo, false));
+    Ok(multistatus.respond())
+}
+
+/// Answers calendar-multiget with the todos asked for and calendar-query with all of them,
+/// unless the query only asks for other components, like events.
+async fn report_handler(
+    body: web::Bytes,
+    pool: web::Data<PgPool>,
+    routing: RoutingService,
+) -> Result<HttpResponse, Error> {
+    let body = String::from_utf8_lossy(&body);
+    let mut multistatus = Multistatus::new();
+
+    if start_tags(&body, "calendar-multiget").next().is_some() {
+        let hrefs = start_tags(&body, "href").filter(|(tag, _)| !tag.ends_with('/'));
+        for (_, following) in hrefs {
+            let requested = following.split('<').next().unwrap_or_default().trim();
+            let uuid = requested.rsplit('/').next().and_then(uuid_of);
+            let todo = match uuid {
+                Some(uuid) => find_todo(&pool, uuid).await?,
+                None => None,
+            };
+            match todo {
+                Some(todo) => {
+                    multistatus.found(&todo_href(&routing, &todo), &todo_props(&todo, true))
+                }
+                None => multistatus.missing(requested),
+            }
+        }
+    } else if start_tags(&body, "calendar-query").next().is_some() {
+        let components = start_tags(&body, "comp-filter")
+            .map(|(tag, _)| tag)
+            .collect::<Vec<&str>>();
+        let asks_for_others = components
+            .iter()
+            .any(|tag| !tag.contains("VCALENDAR") && !tag.contains("VTODO"));
+        let asks_for_todos = components.iter().any(|tag| tag.contains("VTODO"));
+        if !asks_for_others || asks_for_todos {
+            for todo in all_todos(&pool).await? {
+                multistatus.found(&todo_href(&routing, &todo), &todo_props(&todo, true));
+            }
+        }
+    } else {
+        return Ok(HttpResponse::Forbidden()
+            .content_type(XML_CONTENT_TYPE)
+            .body(r#"<?xml version="1.0" encoding="utf-8"?><d:error xmlns:d="DAV:"><d:supported-report/></d:error>"#));
+    }
+    Ok(multistatus.respond())
+}
+
+async fn get_todo_handler(
+    name: web::Path<String>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, Error> {
+    let uuid = uuid_of(&name).ok_or(Error::NotFound)?;
+    let todo = find_todo(&pool, uuid).await?.ok_or(Error::NotFound)?;
+    Ok(HttpResponse::Ok()
+        .content_type(CONTENT_TYPE)
+        .insert_header((header::ETAG, etag(&todo)))
+        .body(ical::to_calendar(&todo)))
+}
+
+fn malformed(reason: String) -> Error {
+    Error::MalformedBody {
+        reason,
+        field: None,
+        line: None,
+        column: None,
+    }
+}
+
+/// The status a task asks for. Clients only know open and finished tasks, so blocked todos
+/// stay blocked while they're open.
+fn requested_status(vtodo: &VTodo, current: Status) -> Status {
+    let status = match vtodo.status {
+        Some(status) => status,
+        None if vtodo.completed.is_some() => Status::Done,
+        None => current.with_completed(false),
+    };
+    match (status, current) {
+        (Status::Todo, Status::Blocked) => Status::Blocked,
+        (status, _) => status,
+    }
+}
+
+/// Creates or replaces a todo with a task, applying the workflow like `PATCH` does. Responds
+/// with 201 when the todo was created and 204 when it was replaced.
+async fn put_todo_handler(
+    req: HttpRequest,
+    name: web::Path<String>,
+    body: web::Bytes,
+    tx: Tx,
+    routing: RoutingService,
+    services: web::Data<TodoServices>,
+) -> Result<HttpResponse, Error> {
+    let uuid = uuid_of(&name).ok_or_else(|| Error::Conflict {
+        reason: "tasks can only be stored under their UUID, like <uuid>.ics".to_owned(),
+    })?;
+    let calendar = std::str::from_utf8(&body)
+        .map_err(|_| malformed("the calendar needs to be UTF-8".to_owned()))?;
+    let vtodo = VTodo::parse(calendar).map_err(malformed)?;
+    let summary = vtodo.summary.as_deref().unwrap_or_default();
+    let mut errors = ValidationErrors::default();
+    validate_title(&mut errors, summary);
+    errors.into_result()?;
+    let title = normalize_title(summary);
+
+    let mut tx = tx.lock().await?;
+    let existing = sqlx::query_as!(
+        Todo,
+        r#"SELECT * FROM todos WHERE uuid = $1 FOR UPDATE"#,
+        uuid
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .map(crypto::decrypt)
+    .transpose()?;
+    check_preconditions(&req, existing.as_ref())?;
+
+    let (todo, before, created) = match existing {
+        None => {
+            let status = requested_status(&vtodo, Status::Todo);
+            let completed = status == Status::Done;
+            let completed_at = if completed {
+                Some(vtodo.completed.unwrap_or_else(Utc::now))
+            } else {
+                None
+            };
+            let order = next_order(&mut tx).await?;
+            // a concurrent request creating the same todo makes this one conflict
+            let todo = sqlx::query_as!(Todo, r#"INSERT INTO todos (title, "order", due_at, starred, status, completed, completed_at, uuid) VALUES($1, $2, $3, $4, $5, $6, $7, $8) ON CONFLICT (uuid) DO NOTHING RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, crypto::encrypt_title(&title), order, vtodo.due, vtodo.starred(), status.as_str(), completed, completed_at, uuid)
+                .fetch_optional(&mut *tx)
+                .await?
+                .map(crypto::decrypt)
+                .transpose()?
+                .ok_or(Error::PreconditionFailed)?;
+            history::record(&mut tx, Action::Create, None, Some(&todo)).await?;
+            (todo, None, true)
+        }
+        Some(before) => {
+            let status = requested_status(&vtodo, before.status());
+            let mut errors = ValidationErrors::default();
+            services
+                .workflow
+                .validate(&mut errors, before.status(), status);
+            errors.into_result()?;
+
+            let completed = status == Status::Done;
+            if completed && !before.completed && services.dependencies.enforce {
+                dependencies::ensure_unblocked(&mut tx, before.id).await?;
+            }
+            let completed_at = match (completed, before.completed) {
+                (false, _) => None,
+                (true, true) => before.completed_at,
+                (true, false) => Some(vtodo.completed.unwrap_or_else(Utc::now)),
+            };
+
+            // clients put tasks again after syncing, which shouldn't bump the version
+            let unchanged = title == before.title
+                && vtodo.due == before.due_at
+                && vtodo.starred() == before.starred
+                && status == before.status()
+                && completed_at == before.completed_at;
+            if unchanged {
+                (before, None, false)
+            } else {
+                let todo = sqlx::query_as!(Todo, r#"UPDATE todos SET title = $1, due_at = $2, starred = $3, status = $4, completed = $5, completed_at = $6, version = version + 1 WHERE id = $7 RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, crypto::encrypt_title(&title), vtodo.due, vtodo.starred(), status.as_str(), completed, completed_at, before.id)
+                    .fetch_one(&mut *tx)
+                    .await
+                    .and_then(crypto::decrypt)?;
+                history::record(&mut tx, Action::Update, Some(&before), Some(&todo)).await?;
+                (todo, Some(before), false)
+            }
+        }
+    };
+    let etag = etag(&todo);
+    let todo = routing.present(todo);
+    let mut response = if created {
+        services
+            .slack
+            .todo_created(&mut tx, &todo.todo, &todo.url)
+            .await?;
+        HttpResponse::Created()
+    } else {
+        if todo.todo.completed && before.map_or(false, |before| !before.completed) {
+            services
+                .slack
+                .todo_completed(&mut tx, &todo.todo, &todo.url)
+                .await?;
+        }
+        HttpResponse::NoContent()
+    };
+    Ok(response.insert_header((header::ETAG, etag)).finish())
+}
+
+async fn delete_todo_handler(
+    req: HttpRequest,
+    name: web::Path<String>,
+    tx: Tx,
+) -> Result<HttpResponse, Error> {
+    let uuid = uuid_of(&name).ok_or(Error::NotFound)?;
+    let mut tx = tx.lock().await?;
+    let existing = sqlx::query_as!(
+        Todo,
+        r#"SELECT * FROM todos WHERE uuid = $1 FOR UPDATE"#,
+        uuid
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .map(crypto::decrypt)
+    .transpose()?;
+    check_preconditions(&req, existing.as_ref())?;
+    let todo = existing.ok_or(Error::NotFound)?;
+
+    sqlx::query!(r#"DELETE FROM todos WHERE id = $1"#, todo.id)
+        .execute(&mut *tx)
+        .await?;
+    history::record(&mut tx, Action::Delete, Some(&todo), None).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_tags_match_any_namespace() {
+        let xml = r#"<d:prop><D:href>/a</D:href><href>/b</href><d:hrefs>/c</d:hrefs></d:prop>"#;
+        let hrefs = start_tags(xml, "href")
+            .map(|(_, following)| following.split('<').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(hrefs, ["/a", "/b"]);
+    }
+
+    #[test]
+    fn start_tags_keep_their_attributes() {
+        let xml =
+            r#"<c:comp-filter name="VCALENDAR"><c:comp-filter name="VTODO"/></c:comp-filter>"#;
+        let tags = start_tags(xml, "comp-filter")
+            .map(|(tag, _)| tag)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tags,
+            [
+                r#"c:comp-filter name="VCALENDAR""#,
+                r#"c:comp-filter name="VTODO"/"#
+            ]
+        );
+    }
+
+    #[test]
+    fn end_tags_are_not_start_tags() {
+        assert_eq!(start_tags("<d:href>/a</d:href>", "href").count(), 1);
+        assert_eq!(start_tags("</d:href>", "href").count(), 0);
+    }
+}
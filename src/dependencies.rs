@@ -0,0 +1,268 @@
+//! Blocked-by relationships between todos, a todo can't be finished before its blockers when
+//! `DependencySettings::enforce` is set.
+
+use crate::crypto;
+use crate::error::Error;
+use crate::ids::{self, TodoRef};
+use crate::transaction::Tx;
+use crate::validation::ValidationErrors;
+use crate::{RoutingService, Todo, TodoPresenter};
+use actix_web::{delete, get, post, web, HttpResponse};
+use serde::Deserialize;
+use sqlx::{PgPool, Postgres, Transaction};
+
+#[derive(Debug, Clone, Copy)]
+pub struct DependencySettings {
+    /// Refuse completing todos while any of their blockers is still open.
+    pub enforce: bool,
+}
+
+#[derive(Deserialize)]
+pub struct NewDependency {
+    #[serde(deserialize_with = "ids::deserialize_json")]
+    blocker_id: TodoRef,
+}
+
+/// Fails with a conflict listing the open blockers of a todo, if it has any.
+pub async fn ensure_unblocked(tx: &mut Transaction<'_, Postgres>, id: i64) -> Result<(), Error> {
+    let blockers = sqlx::query_scalar!(r#"SELECT todos.id FROM todo_dependencies JOIN todos ON todos.id = todo_dependencies.blocker_id WHERE todo_dependencies.todo_id = $1 AND NOT todos.completed ORDER BY todos.id"#, id)
+        .fetch_all(&mut *tx)
+        .await?;
+
+    if blockers.is_empty() {
+        return Ok(());
+    }
+    let blockers = blockers
+        .iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<String>>()
+        .join(", ");
+    Err(Error::Conflict {
+        reason: format!(
+            "the todo is blocked by open todos ({}), complete them first",
+            blockers
+        ),
+    })
+}
+
+#[get("/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}/dependencies")]
+pub async fn dependencies_list_handler(
+    todo: web::Path<TodoRef>,
+    pool: web::Data<PgPool>,
+    routing: RoutingService,
+) -> Result<HttpResponse, Error> {
+    let id = ids::resolve(pool.get_ref(), *todo).await?;
+    let blockers = sqlx::query_as!(Todo, r#"SELECT todos.* FROM todo_dependencies JOIN todos ON todos.id = todo_dependencies.blocker_id WHERE todo_dependencies.todo_id = $1 ORDER BY todos.id"#, id)
+        .fetch_all(pool.get_ref())
+        .await?
+        .into_iter()
+        .map(crypto::decrypt)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let blockers = blockers
+        .into_iter()
+        .map(|todo| routing.present(todo))
+        .collect::<Vec<TodoPresenter>>();
+    Ok(HttpResponse::Ok().json(blockers))
+}
+
+/// Marks the todo as blocked by another one, refusing links that would make a cycle.
+#[post("/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}/dependencies")]
+pub async fn create_dependency_handler(
+    todo: web::Path<TodoRef>,
+    dependency: web::Json<NewDependency>,
+    tx: Tx,
+    routing: RoutingService,
+) -> Result<HttpResponse, Error> {
+    let mut tx = tx.lock().await?;
+    let id = ids::resolve(&mut *tx, *todo).await?;
+    let blocker_id = match ids::resolve(&mut *tx, dependency.blocker_id).await {
+        Ok(blocker_id) => blocker_id,
+        Err(Error::NotFound) => return Err(invalid_blocker()),
+        Err(error) => return Err(error),
+    };
+    // links are added one at a time, so that concurrent ones can't form a cycle together
+    sqlx::query!(r#"LOCK TABLE todo_dependencies IN SHARE ROW EXCLUSIVE MODE"#)
+        .execute(&mut *tx)
+        .await?;
+    let todos = sqlx::query_scalar!(
+        r#"SELECT id FROM todos WHERE id = $1 OR id = $2"#,
+        id,
+        blocker_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    if !todos.contains(&id) {
+        return Err(Error::NotFound);
+    }
+    if !todos.contains(&blocker_id) || blocker_id == id {
+        return Err(invalid_blocker());
+    }
+
+    // the new link closes a cycle if the blocker already depends on the todo, directly or not
+    let creates_cycle = sqlx::query_scalar!(r#"WITH RECURSIVE blockers(id) AS (SELECT $1::bigint UNION SELECT todo_dependencies.blocker_id FROM todo_dependencies JOIN blockers ON todo_dependencies.todo_id = blockers.id) SELECT EXISTS(SELECT 1 FROM blockers WHERE id = $2) AS "exists!""#, blocker_id, id)
+        .fetch_one(&mut *tx)
+        .await?;
+    if creates_cycle {
+        return Err(Error::Conflict {
+            reason: format!(
+                "todo {} already depends on todo {}, linking them would create a cycle",
+                blocker_id, id
+            ),
+        });
+    }
+
+    sqlx::query!(r#"INSERT INTO todo_dependencies (todo_id, blocker_id) VALUES ($1, $2) ON CONFLICT DO NOTHING"#, id, blocker_id)
+        .execute(&mut *tx)
+        .await?;
+    let blocker = sqlx::query_as!(Todo, r#"SELECT * FROM todos WHERE id = $1"#, blocker_id)
+        .fetch_one(&mut *tx)
+        .await
+        .and_then(crypto::decrypt)?;
+
+    Ok(HttpResponse::Ok().json(routing.present(blocker)))
+}
+
+fn invalid_blocker() -> Error {
+    let mut errors = ValidationErrors::default();
+    errors.add("blocker_id", "invalid_blocker", &[]);
+    errors.into()
+}
+
+#[delete("/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}/dependencies/{blocker_id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}")]
+pub async fn delete_dependency_handler(
+    path: web::Path<(TodoRef, TodoRef)>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, Error> {
+    let (todo, blocker) = path.into_inner();
+    let id = ids::resolve(pool.get_ref(), todo).await?;
+    let blocker_id = ids::resolve(pool.get_ref(), blocker).await?;
+    let result = sqlx::query!(
+        r#"DELETE FROM todo_dependencies WHERE todo_id = $1 AND blocker_id = $2"#,
+        id,
+        blocker_id
+    )
+    .execute(pool.get_ref())
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{routes, test_support};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+
+    #[actix_rt::test]
+    #[ignore = "needs a database, see test_support"]
+    async fn links_forming_a_cycle_are_refused() {
+        let pool = test_support::pool().await;
+        let app = test::init_service(
+            App::new()
+                .configure(test_support::app_data(pool))
+                .configure(routes),
+        )
+        .await;
+
+        let mut ids = Vec::new();
+        for title in ["Buy milk", "Go to the shop", "Find the wallet"] {
+            let req = test::TestRequest::post()
+                .uri("/todos")
+                .set_json(&json!({ "title": title }))
+                .to_request();
+            let todo: Value = test::read_body_json(test::call_service(&app, req).await).await;
+            ids.push(todo["id"].as_i64().unwrap());
+        }
+        let link = |id: i64, blocker_id: i64| {
+            test::TestRequest::post()
+                .uri(&format!("/todos/{}/dependencies", id))
+                .set_json(&json!({ "blocker_id": blocker_id }))
+                .to_request()
+        };
+
+        // a todo can't block itself
+        let res = test::call_service(&app, link(ids[0], ids[0])).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let res = test::call_service(&app, link(ids[0], ids[1])).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        // the blocker can't in turn be blocked by the todo
+        let res = test::call_service(&app, link(ids[1], ids[0])).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let res = test::call_service(&app, link(ids[1], ids[2])).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        // nor through another todo
+        let res = test::call_service(&app, link(ids[2], ids[0])).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs a database, see test_support"]
+    async fn blocked_todos_cant_be_completed() {
+        let pool = test_support::pool().await;
+        let app = test::init_service(
+            App::new()
+                .configure(test_support::with_dependencies(
+                    pool,
+                    DependencySettings { enforce: true },
+                ))
+                .configure(routes),
+        )
+        .await;
+
+        let mut todos = Vec::new();
+        for title in ["Buy milk", "Go to the shop"] {
+            let req = test::TestRequest::post()
+                .uri("/todos")
+                .set_json(&json!({ "title": title }))
+                .to_request();
+            let todo: Value = test::read_body_json(test::call_service(&app, req).await).await;
+            todos.push(todo);
+        }
+        let (todo, blocker) = (&todos[0], &todos[1]);
+        let req = test::TestRequest::post()
+            .uri(&format!("/todos/{}/dependencies", todo["id"]))
+            .set_json(&json!({ "blocker_id": blocker["id"] }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let complete = |id: &Value| {
+            test::TestRequest::patch()
+                .uri(&format!("/todos/{}", id))
+                .set_json(&json!({ "completed": true }))
+                .to_request()
+        };
+        let res = test::call_service(&app, complete(&todo["id"])).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        // nor through an offline update
+        let req = test::TestRequest::post()
+            .uri("/sync")
+            .set_json(&json!({
+                "operations": [{
+                    "op": "update",
+                    "id": todo["id"],
+                    "base_version": todo["version"],
+                    "fields": { "completed": true },
+                }]
+            }))
+            .to_request();
+        let response: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        let result = &response["results"][0];
+        assert_eq!(result["status"], "rejected");
+        assert_eq!(result["todo"]["completed"], false);
+        assert!(result["reason"].is_string());
+
+        let res = test::call_service(&app, complete(&blocker["id"])).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = test::call_service(&app, complete(&todo["id"])).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
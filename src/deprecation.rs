@@ -0,0 +1,115 @@
+//! Routes on their way out, configured in `DEPRECATED_ROUTES`. Responses of deprecated routes
+//! carry a `Deprecation` header (RFC 9745) and, once a removal date is set, a `Sunset` header
+//! (RFC 8594), so that clients get notice well before the routes are gone.
+
+use actix_web::dev::{ResourceDef, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::HttpRequest;
+use chrono::{NaiveDate, TimeZone, Utc};
+
+struct DeprecatedRoute {
+    /// Any method when `None`.
+    method: Option<Method>,
+    resource: ResourceDef,
+    deprecation: HeaderValue,
+    sunset: Option<HeaderValue>,
+    sunset_on: Option<NaiveDate>,
+}
+
+#[derive(Default)]
+pub struct Deprecations(Vec<DeprecatedRoute>);
+
+fn parse_date(date: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d")
+        .map_err(|_| format!("{} needs to be a date like 2026-06-30", date.trim()))
+}
+
+impl Deprecations {
+    /// Parses comma separated routes like `GET /todos/changes=2026-01-01..2026-06-30`, each
+    /// being an optional method, the path as registered, the date the route was deprecated on
+    /// and optionally the date it's going to be removed on.
+    pub fn parse(routes: &str) -> Result<Self, String> {
+        let mut deprecations = Vec::new();
+        for route in routes
+            .split(',')
+            .map(str::trim)
+            .filter(|route| !route.is_empty())
+        {
+            let (route, dates) = route
+                .split_once('=')
+                .ok_or_else(|| format!("{} needs a date like {}=2026-01-01", route, route))?;
+            let (method, path) = match route.trim().split_once(' ') {
+                Some((method, path)) => {
+                    let method = method
+                        .parse::<Method>()
+                        .map_err(|_| format!("{} isn't an HTTP method", method))?;
+                    (Some(method), path.trim())
+                }
+                None => (None, route.trim()),
+            };
+            let (deprecated_on, sunset_on) = match dates.split_once("..") {
+                Some((deprecated_on, sunset_on)) => {
+                    (parse_date(deprecated_on)?, Some(parse_date(sunset_on)?))
+                }
+                None => (parse_date(dates)?, None),
+            };
+
+            let deprecated_at = Utc.from_utc_datetime(&deprecated_on.and_hms_opt(0, 0, 0).unwrap());
+            let sunset = sunset_on.map(|sunset_on| {
+                let sunset_at = Utc.from_utc_datetime(&sunset_on.and_hms_opt(0, 0, 0).unwrap());
+                let http_date = sunset_at.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+                HeaderValue::from_str(&http_date).unwrap()
+            });
+            deprecations.push(DeprecatedRoute {
+                method,
+                resource: ResourceDef::new(path),
+                deprecation: HeaderValue::from_str(&format!("@{}", deprecated_at.timestamp()))
+                    .unwrap(),
+                sunset,
+                sunset_on,
+            });
+        }
+        Ok(Deprecations(deprecations))
+    }
+
+    fn find(&self, req: &HttpRequest) -> Option<&DeprecatedRoute> {
+        self.0.iter().find(|route| {
+            route
+                .method
+                .as_ref()
+                .map_or(true, |method| method == req.method())
+                && route.resource.is_match(req.path())
+        })
+    }
+
+    /// Adds the headers to responses of deprecated routes and logs their use.
+    pub fn mark<B>(&self, res: &mut ServiceResponse<B>) {
+        let route = match self.find(res.request()) {
+            Some(route) => route,
+            None => return,
+        };
+
+        match route.sunset_on {
+            Some(sunset_on) => warn!(
+                "Deprecated route {} {} was called, it's going to be removed on {}",
+                res.request().method(),
+                res.request().path(),
+                sunset_on
+            ),
+            None => warn!(
+                "Deprecated route {} {} was called",
+                res.request().method(),
+                res.request().path()
+            ),
+        }
+        let headers = res.headers_mut();
+        headers.insert(
+            HeaderName::from_static("deprecation"),
+            route.deprecation.clone(),
+        );
+        if let Some(sunset) = &route.sunset {
+            headers.insert(HeaderName::from_static("sunset"), sunset.clone());
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use crate::crypto;
+use crate::error::Error;
+use crate::pagination::Page;
+use crate::validation::ValidationErrors;
+use crate::{todos_etag, RoutingService, Todo, TodoPresenter, TodosList};
+use actix_web::{get, route, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use chrono_english::Dialect;
+use chrono_tz::Tz;
+use futures_util::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+/// Header clients can send their IANA timezone in, instead of the `tz` query parameter.
+const TIME_ZONE_HEADER: &str = "Time-Zone";
+
+#[derive(Deserialize)]
+pub struct DueParams {
+    tz: Option<String>,
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+/// The client's timezone from the `tz` query parameter or the `Time-Zone` header, UTC when
+/// neither is given.
+pub fn timezone(req: &HttpRequest, param: Option<&str>) -> Result<Tz, Error> {
+    let name = param.or_else(|| {
+        req.headers()
+            .get(TIME_ZONE_HEADER)
+            .and_then(|value| value.to_str().ok())
+    });
+
+    match name {
+        Some(name) => name.trim().parse::<Tz>().map_err(|_| Error::InvalidQuery {
+            reason: format!(
+                "unknown timezone `{}`, expected a name like Europe/Berlin",
+                name
+            ),
+        }),
+        None => Ok(Tz::UTC),
+    }
+}
+
+#[derive(Deserialize)]
+struct TimezoneParams {
+    tz: Option<String>,
+}
+
+/// The client's timezone for handlers that don't otherwise parse the query string.
+pub fn request_timezone(req: &HttpRequest) -> Result<Tz, Error> {
+    let params = web::Query::<TimezoneParams>::from_query(req.query_string()).ok();
+    timezone(req, params.as_ref().and_then(|params| params.tz.as_deref()))
+}
+
+/// Parses a due date written like "tomorrow 5pm" or "next friday", relative to the current
+/// time in the client's timezone.
+pub fn parse_due(errors: &mut ValidationErrors, due: &str, tz: &Tz) -> Option<DateTime<Utc>> {
+    let now = Utc::now().with_timezone(tz);
+    match chrono_english::parse_date_string(due.trim(), now, Dialect::Uk) {
+        Ok(due_at) => Some(due_at.with_timezone(&Utc)),
+        Err(_) => {
+            errors.add("due", "unparsable_date", &[]);
+            None
+        }
+    }
+}
+
+/// The instant a day starts in the given timezone.
+pub fn start_of_day(tz: &Tz, date: NaiveDate) -> DateTime<Utc> {
+    // midnight is skipped on days DST starts at midnight, the day then starts an hour later
+    let midnight = date.and_hms(0, 0, 0);
+    tz.from_local_datetime(&midnight)
+        .earliest()
+        .or_else(|| {
+            tz.from_local_datetime(&(midnight + Duration::hours(1)))
+                .earliest()
+        })
+        .map(|start| start.with_timezone(&Utc))
+        .unwrap_or_else(|| DateTime::from_utc(midnight, Utc))
+}
+
+/// Streams the open todos due before `to`, and not before `from` when given, soonest first.
+async fn due_todos(
+    pool: &PgPool,
+    routing: RoutingService,
+    from: Option<DateTime<Utc>>,
+    to: DateTime<Utc>,
+    page: Option<Page>,
+) -> Result<TodosList, Error> {
+    let total = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "total!" FROM todos WHERE NOT completed AND ($1::timestamptz IS NULL OR due_at >= $1) AND due_at < $2"#, from, to)
+        .fetch_one(pool)
+        .await?;
+    // the lists also change without any revision, as time passes: a day's list is told apart by
+    // its bounds, which the timezone decides, and the overdue one only grows until the next
+    // revision, so its length tells whether a due time passed
+    let bounds = match from {
+        Some(from) => format!("{}-{}", from.timestamp(), to.timestamp()),
+        None => "overdue".to_owned(),
+    };
+    let etag = format!("{}-{}-{}", todos_etag(pool).await?, bounds, total);
+
+    let pool = pool.clone();
+    let limit = page.map(|page| page.limit());
+    let offset = page.map(|page| page.offset()).unwrap_or(0);
+    let todos = Box::pin(async_stream::stream! {
+        let mut rows = sqlx::query_as!(Todo, r#"SELECT * FROM todos WHERE NOT completed AND ($1::timestamptz IS NULL OR due_at >= $1) AND due_at < $2 ORDER BY due_at, id LIMIT $3 OFFSET $4"#, from, to, limit, offset)
+            .fetch(&pool);
+        while let Some(todo) = rows.next().await {
+            yield todo.and_then(crypto::decrypt);
+        }
+    });
+
+    Ok(TodosList {
+        highlighter: None,
+        routing,
+        etag,
+        total,
+        page,
+        todos,
+    })
+}
+
+/// Open todos due today in the client's timezone, including the ones already past due today.
+#[route("/todos/today", method = "GET", method = "HEAD")]
+pub async fn today_todos_handler(
+    req: HttpRequest,
+    params: web::Query<DueParams>,
+    pool: web::Data<PgPool>,
+    routing: RoutingService,
+) -> Result<TodosList, Error> {
+    let page = Page::from_params(params.page, params.per_page)?;
+    let tz = timezone(&req, params.tz.as_deref())?;
+    let today = Utc::now().with_timezone(&tz).date().naive_local();
+    let from = start_of_day(&tz, today);
+    let to = start_of_day(&tz, today.succ());
+
+    due_todos(pool.get_ref(), routing, Some(from), to, page).await
+}
+
+/// Open todos whose due date has passed.
+#[route("/todos/overdue", method = "GET", method = "HEAD")]
+pub async fn overdue_todos_handler(
+    params: web::Query<DueParams>,
+    pool: web::Data<PgPool>,
+    routing: RoutingService,
+) -> Result<TodosList, Error> {
+    let page = Page::from_params(params.page, params.per_page)?;
+
+    due_todos(pool.get_ref(), routing, None, Utc::now(), page).await
+}
+
+/// The longest range the calendar can be requested for.
+const MAX_CALENDAR_DAYS: i64 = 366;
+
+#[derive(Deserialize)]
+pub struct CalendarParams {
+    from: NaiveDate,
+    to: NaiveDate,
+    tz: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CalendarDay {
+    date: NaiveDate,
+    count: usize,
+    completed: usize,
+    todos: Vec<TodoPresenter>,
+}
+
+/// Todos grouped by the day they're due in the client's timezone, for every day from `from` to
+/// `to` inclusive, days without todos included.
+#[get("/todos/calendar")]
+pub async fn calendar_handler(
+    req: HttpRequest,
+    params: web::Query<CalendarParams>,
+    pool: web::Data<PgPool>,
+    routing: RoutingService,
+) -> Result<HttpResponse, Error> {
+    let CalendarParams { from, to, tz } = params.into_inner();
+    let tz = timezone(&req, tz.as_deref())?;
+    let days = (to - from).num_days() + 1;
+    if days < 1 || days > MAX_CALENDAR_DAYS {
+        return Err(Error::InvalidQuery {
+            reason: format!(
+                "to needs to be on or after from and the range can span at most {} days",
+                MAX_CALENDAR_DAYS
+            ),
+        });
+    }
+
+    let todos = sqlx::query_as!(
+        Todo,
+        r#"SELECT * FROM todos WHERE due_at >= $1 AND due_at < $2 ORDER BY due_at, id"#,
+        start_of_day(&tz, from),
+        start_of_day(&tz, to.succ())
+    )
+    .fetch_all(pool.get_ref())
+    .await?
+    .into_iter()
+    .map(crypto::decrypt)
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let mut calendar = (0..days)
+        .map(|offset| CalendarDay {
+            date: from + Duration::days(offset),
+            count: 0,
+            completed: 0,
+            todos: Vec::new(),
+        })
+        .collect::<Vec<CalendarDay>>();
+    for todo in todos {
+        let due = match todo.due_at {
+            Some(due_at) => due_at.with_timezone(&tz).date().naive_local(),
+            None => continue,
+        };
+        if let Some(day) = calendar.get_mut((due - from).num_days() as usize) {
+            day.count += 1;
+            if todo.completed {
+                day.completed += 1;
+            }
+            day.todos.push(routing.present(todo));
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(calendar))
+}
+
+/// Minutes of work a day is expected to fit unless the client says otherwise, a working day.
+const DEFAULT_CAPACITY_MINUTES: i64 = 8 * 60;
+const DEFAULT_WORKLOAD_DAYS: i64 = 7;
+
+#[derive(Deserialize)]
+pub struct WorkloadParams {
+    /// The first day of the report, today when not given.
+    date: Option<NaiveDate>,
+    days: Option<i64>,
+    capacity_minutes: Option<i64>,
+    tz: Option<String>,
+}
+
+#[derive(Serialize)]
+struct WorkloadDay {
+    date: NaiveDate,
+    estimate_minutes: i64,
+    todos: usize,
+    /// Todos due this day without an estimate, their effort is unknown.
+    unestimated: usize,
+    overcommitted: bool,
+}
+
+/// Sums the estimates of open todos per due day, flagging days with more work than capacity.
+#[get("/todos/workload")]
+pub async fn workload_handler(
+    req: HttpRequest,
+    params: web::Query<WorkloadParams>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, Error> {
+    let tz = timezone(&req, params.tz.as_deref())?;
+    let from = params
+        .date
+        .unwrap_or_else(|| Utc::now().with_timezone(&tz).date().naive_local());
+    let days = params.days.unwrap_or(DEFAULT_WORKLOAD_DAYS);
+    let capacity_minutes = params.capacity_minutes.unwrap_or(DEFAULT_CAPACITY_MINUTES);
+    if days < 1 || days > MAX_CALENDAR_DAYS {
+        return Err(Error::InvalidQuery {
+            reason: format!("days needs to be between 1 and {}", MAX_CALENDAR_DAYS),
+        });
+    }
+
+    let to = from + Duration::days(days);
+    let todos = sqlx::query!(r#"SELECT due_at AS "due_at!", estimate_minutes FROM todos WHERE NOT completed AND due_at >= $1 AND due_at < $2"#, start_of_day(&tz, from), start_of_day(&tz, to))
+        .fetch_all(pool.get_ref())
+        .await?;
+
+    let mut workload = (0..days)
+        .map(|offset| WorkloadDay {
+            date: from + Duration::days(offset),
+            estimate_minutes: 0,
+            todos: 0,
+            unestimated: 0,
+            overcommitted: false,
+        })
+        .collect::<Vec<WorkloadDay>>();
+    for todo in todos {
+        let due = todo.due_at.with_timezone(&tz).date().naive_local();
+        if let Some(day) = workload.get_mut((due - from).num_days() as usize) {
+            day.todos += 1;
+            match todo.estimate_minutes {
+                Some(estimate_minutes) => day.estimate_minutes += i64::from(estimate_minutes),
+                None => day.unestimated += 1,
+            }
+        }
+    }
+    for day in &mut workload {
+        day.overcommitted = day.estimate_minutes > capacity_minutes;
+    }
+
+    Ok(HttpResponse::Ok().json(workload))
+}
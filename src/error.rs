@@ -0,0 +1,351 @@
+use crate::i18n::Locale;
+use crate::reporting;
+use crate::request_id;
+use crate::validation::ValidationErrors;
+use actix_web::{
+    dev::ServiceResponse, error, error::JsonPayloadError, http::header, http::StatusCode,
+    HttpRequest, HttpResponse, HttpResponseBuilder,
+};
+use derive_more::{Display, Error as DeriveError};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Postgres SQLSTATE reported when a unique constraint is violated.
+const UNIQUE_VIOLATION: &str = "23505";
+/// Postgres SQLSTATE reported when a statement is cancelled, e.g. by `statement_timeout`.
+const QUERY_CANCELED: &str = "57014";
+
+#[derive(Debug, Display, DeriveError)]
+pub enum Error {
+    #[display(fmt = "internal error")]
+    InternalError,
+
+    #[display(fmt = "bad request")]
+    BadClientData,
+
+    #[display(fmt = "timeout")]
+    Timeout,
+
+    #[display(fmt = "service unavailable")]
+    ServiceUnavailable { retry_after: u64 },
+
+    #[display(fmt = "under maintenance")]
+    Maintenance,
+
+    #[display(fmt = "unauthorized")]
+    Unauthorized,
+
+    #[display(fmt = "not found")]
+    NotFound,
+
+    #[display(fmt = "method not allowed")]
+    MethodNotAllowed { allow: &'static str },
+
+    #[display(fmt = "precondition failed")]
+    PreconditionFailed,
+
+    #[display(fmt = "conflict")]
+    Conflict { reason: String },
+
+    #[display(fmt = "validation failed")]
+    ValidationFailed { errors: ValidationErrors },
+
+    #[display(fmt = "sync token expired")]
+    SyncTokenExpired,
+
+    #[display(fmt = "invalid query string")]
+    InvalidQuery { reason: String },
+
+    #[display(fmt = "malformed request body")]
+    MalformedBody {
+        reason: String,
+        field: Option<String>,
+        line: Option<usize>,
+        column: Option<usize>,
+    },
+}
+
+/// An RFC 7807 problem details document, rendered as `application/problem+json` for every
+/// error response.
+#[derive(Serialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    type_: String,
+    title: String,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<String>,
+    #[serde(flatten)]
+    extensions: Map<String, Value>,
+}
+
+impl Error {
+    /// A stable, machine-readable identifier of the error kind.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Error::InternalError => "internal_error",
+            Error::BadClientData => "bad_request",
+            Error::Timeout => "timeout",
+            Error::ServiceUnavailable { .. } => "service_unavailable",
+            Error::Maintenance => "maintenance",
+            Error::Unauthorized => "unauthorized",
+            Error::NotFound => "not_found",
+            Error::MethodNotAllowed { .. } => "method_not_allowed",
+            Error::PreconditionFailed => "precondition_failed",
+            Error::Conflict { .. } => "conflict",
+            Error::ValidationFailed { .. } => "validation_failed",
+            Error::SyncTokenExpired => "sync_token_expired",
+            Error::InvalidQuery { .. } => "invalid_query",
+            Error::MalformedBody { .. } => "malformed_body",
+        }
+    }
+
+    pub fn title(&self, locale: Locale) -> String {
+        locale
+            .message(&format!("error.{}.title", self.code()))
+            .map(|title| title.to_owned())
+            .unwrap_or_else(|| self.to_string())
+    }
+
+    pub fn detail(&self, locale: Locale) -> Option<String> {
+        match self {
+            Error::Conflict { reason } => Some(reason.clone()),
+            Error::ValidationFailed { .. }
+            | Error::SyncTokenExpired
+            | Error::ServiceUnavailable { .. }
+            | Error::Maintenance => locale
+                .message(&format!("error.{}.detail", self.code()))
+                .map(|detail| detail.to_owned()),
+            Error::InvalidQuery { reason } => Some(reason.clone()),
+            Error::MalformedBody { reason, .. } => Some(reason.clone()),
+            _ => None,
+        }
+    }
+
+    /// Problem-specific members added next to the standard RFC 7807 ones.
+    pub fn extensions(&self, locale: Locale) -> Map<String, Value> {
+        let mut extensions = Map::new();
+        extensions.insert("code".to_owned(), Value::from(self.code()));
+        match self {
+            Error::ValidationFailed { errors } => {
+                if let Ok(errors) = serde_json::to_value(errors.localized(locale)) {
+                    extensions.insert("errors".to_owned(), errors);
+                }
+            }
+            Error::MalformedBody {
+                field,
+                line,
+                column,
+                ..
+            } => {
+                if let Some(field) = field {
+                    extensions.insert("field".to_owned(), Value::from(field.as_str()));
+                }
+                if let Some(line) = line {
+                    extensions.insert("line".to_owned(), Value::from(*line));
+                }
+                if let Some(column) = column {
+                    extensions.insert("column".to_owned(), Value::from(*column));
+                }
+            }
+            _ => {}
+        }
+        extensions
+    }
+
+    /// Renders the error as a problem document, `instance` being the path of the failed request
+    /// and `request_id` the id users can quote when reporting it.
+    pub fn problem_response(
+        &self,
+        instance: Option<String>,
+        request_id: Option<String>,
+        locale: Locale,
+    ) -> HttpResponse {
+        let status = error::ResponseError::status_code(self);
+        let mut extensions = self.extensions(locale);
+        if let Some(request_id) = request_id {
+            extensions.insert("request_id".to_owned(), Value::from(request_id));
+        }
+        let problem = Problem {
+            type_: format!("/problems/{}", self.code().replace('_', "-")),
+            title: self.title(locale),
+            status: status.as_u16(),
+            detail: self.detail(locale),
+            instance,
+            extensions,
+        };
+
+        let mut response = HttpResponseBuilder::new(status);
+        response
+            .content_type("application/problem+json")
+            .insert_header((header::CONTENT_LANGUAGE, locale.tag()));
+        match self {
+            Error::MethodNotAllowed { allow } => {
+                response.insert_header((header::ALLOW, *allow));
+            }
+            Error::ServiceUnavailable { retry_after } => {
+                response.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+            }
+            Error::Unauthorized => {
+                response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+            }
+            _ => {}
+        }
+        response.body(serde_json::to_string(&problem).unwrap_or_default())
+    }
+}
+
+impl error::ResponseError for Error {
+    fn error_response(&self) -> HttpResponse {
+        self.problem_response(None, None, Locale::En)
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match *self {
+            Error::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::BadClientData => StatusCode::BAD_REQUEST,
+            Error::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            Error::ServiceUnavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Maintenance => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::NotFound => StatusCode::NOT_FOUND,
+            Error::MethodNotAllowed { .. } => StatusCode::METHOD_NOT_ALLOWED,
+            Error::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            Error::Conflict { .. } => StatusCode::CONFLICT,
+            Error::ValidationFailed { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::SyncTokenExpired => StatusCode::GONE,
+            Error::InvalidQuery { .. } => StatusCode::BAD_REQUEST,
+            Error::MalformedBody { .. } => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(error: sqlx::Error) -> Self {
+        match error {
+            sqlx::Error::RowNotFound => Error::NotFound,
+            sqlx::Error::PoolTimedOut => Error::Timeout,
+            sqlx::Error::Database(e) if e.code().as_deref() == Some(QUERY_CANCELED) => {
+                Error::Timeout
+            }
+            sqlx::Error::Database(e) if e.code().as_deref() == Some(UNIQUE_VIOLATION) => {
+                Error::Conflict {
+                    reason: "the request conflicts with an existing todo".to_owned(),
+                }
+            }
+            error => {
+                // the cause isn't part of the response, this is the only place it's visible
+                error!("Unexpected database error: {}", error);
+                reporting::record_cause(&error);
+                Error::InternalError
+            }
+        }
+    }
+}
+
+impl From<ValidationErrors> for Error {
+    fn from(errors: ValidationErrors) -> Self {
+        Error::ValidationFailed { errors }
+    }
+}
+
+impl From<JsonPayloadError> for Error {
+    fn from(error: JsonPayloadError) -> Self {
+        match error {
+            JsonPayloadError::Deserialize(error) => {
+                // serde_json appends the position to its messages, it's reported separately
+                let position = format!(" at line {} column {}", error.line(), error.column());
+                let message = error.to_string();
+                let reason = message.trim_end_matches(&position).to_owned();
+                let (line, column) = if error.line() > 0 {
+                    (Some(error.line()), Some(error.column()))
+                } else {
+                    (None, None)
+                };
+
+                Error::MalformedBody {
+                    field: field_name(&reason),
+                    reason,
+                    line,
+                    column,
+                }
+            }
+            error => Error::MalformedBody {
+                reason: error.to_string(),
+                field: None,
+                line: None,
+                column: None,
+            },
+        }
+    }
+}
+
+impl From<rmp_serde::decode::Error> for Error {
+    fn from(error: rmp_serde::decode::Error) -> Self {
+        let reason = error.to_string();
+        Error::MalformedBody {
+            field: field_name(&reason),
+            reason,
+            line: None,
+            column: None,
+        }
+    }
+}
+
+impl From<prost::DecodeError> for Error {
+    fn from(error: prost::DecodeError) -> Self {
+        Error::MalformedBody {
+            reason: error.to_string(),
+            field: None,
+            line: None,
+            column: None,
+        }
+    }
+}
+
+/// Extracts the field name from serde messages like "missing field `title`".
+fn field_name(reason: &str) -> Option<String> {
+    if !reason.contains(" field `") {
+        return None;
+    }
+
+    reason.split('`').nth(1).map(|field| field.to_owned())
+}
+
+/// Error handler for `web::JsonConfig`, replacing actix's default plain 400 response.
+pub fn json_error_handler(error: JsonPayloadError, _: &HttpRequest) -> actix_web::Error {
+    Error::from(error).into()
+}
+
+/// Error handler for `web::PathConfig`, so unparsable ids are reported like missing todos.
+pub fn path_error_handler(_: error::PathError, _: &HttpRequest) -> actix_web::Error {
+    Error::NotFound.into()
+}
+
+/// Error handler for `web::QueryConfig`, reporting which query parameter couldn't be parsed.
+pub fn query_error_handler(error: error::QueryPayloadError, _: &HttpRequest) -> actix_web::Error {
+    Error::InvalidQuery {
+        reason: error.to_string(),
+    }
+    .into()
+}
+
+/// Re-renders problem responses produced by `Error` with the request path as their `instance`
+/// and the request id, in the locale negotiated from the request's `Accept-Language`.
+/// The response keeps its error, for the middleware further out to log and report.
+pub fn with_instance(res: ServiceResponse, instance: String, locale: Locale) -> ServiceResponse {
+    let request_id = request_id::of(res.request());
+    let problem = match res.response().error().and_then(|e| e.as_error::<Error>()) {
+        Some(error) => error.problem_response(Some(instance), request_id, locale),
+        None => return res,
+    };
+
+    let (problem, body) = problem.into_parts();
+    res.map_body(move |head, _| {
+        head.status = problem.status();
+        head.headers = problem.headers().clone();
+        body
+    })
+}
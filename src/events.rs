@@ -0,0 +1,143 @@
+//! Change events for other services, published to a Kafka topic or to NATS subjects when
+//! `EVENTS_BROKER` is set. Every revision recorded also goes into the outbox as a change event,
+//! so events are only published for committed changes and at least once, like notifications.
+//!
+//! Events are JSON like `{"type": "todo.updated", "todo_id": 1, "todo": {...}}`, `todo` being
+//! the todo after the change, or before it for deletions. Kafka gets them on partition 0 of the
+//! topic, keyed by the todo id, which keeps them in order. NATS gets them on `<subject>.created`,
+//! `<subject>.updated` and `<subject>.deleted`.
+
+use crate::history::Action;
+use anyhow::{Context, Result};
+use rskafka::client::partition::{Compression, PartitionClient, UnknownTopicHandling};
+use rskafka::client::ClientBuilder;
+use rskafka::record::Record;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set once a publisher is connected, before that no change events are written to the outbox.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Broker {
+    Kafka,
+    Nats,
+}
+
+impl FromStr for Broker {
+    type Err = String;
+
+    fn from_str(broker: &str) -> Result<Self, Self::Err> {
+        match broker.trim() {
+            "kafka" => Ok(Broker::Kafka),
+            "nats" => Ok(Broker::Nats),
+            broker => Err(format!("needs to be kafka or nats, not {:?}", broker)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EventSettings {
+    pub broker: Broker,
+    /// Comma separated `host:port` bootstrap brokers for Kafka, a `nats://` URL for NATS.
+    pub url: String,
+    /// The Kafka topic, or the prefix of the NATS subjects.
+    pub topic: String,
+}
+
+pub fn action_name(action: Action) -> &'static str {
+    match action {
+        Action::Create => "created",
+        Action::Update => "updated",
+        Action::Delete => "deleted",
+    }
+}
+
+#[derive(Serialize)]
+struct Published<'a> {
+    #[serde(rename = "type")]
+    type_: String,
+    todo_id: i64,
+    todo: &'a Value,
+}
+
+pub enum Publisher {
+    Kafka(PartitionClient),
+    Nats {
+        client: async_nats::Client,
+        subject: String,
+    },
+}
+
+impl Publisher {
+    pub async fn connect(settings: &EventSettings) -> Result<Publisher> {
+        let publisher = match settings.broker {
+            Broker::Kafka => {
+                let brokers = settings
+                    .url
+                    .split(',')
+                    .map(|broker| broker.trim().to_owned());
+                let client = ClientBuilder::new(brokers.collect())
+                    .build()
+                    .await
+                    .context("Failed to connect to Kafka")?;
+                let partition = client
+                    .partition_client(settings.topic.clone(), 0, UnknownTopicHandling::Retry)
+                    .await
+                    .with_context(|| {
+                        format!("Failed to open the Kafka topic {}", settings.topic)
+                    })?;
+                Publisher::Kafka(partition)
+            }
+            Broker::Nats => {
+                let client = async_nats::connect(settings.url.as_str())
+                    .await
+                    .context("Failed to connect to NATS")?;
+                Publisher::Nats {
+                    client,
+                    subject: settings.topic.clone(),
+                }
+            }
+        };
+        ENABLED.store(true, Ordering::Relaxed);
+        Ok(publisher)
+    }
+
+    /// Publishes a change event of the outbox, waiting for the broker to have it.
+    pub async fn publish(&self, action: &str, todo_id: i64, todo: &Value) -> Result<()> {
+        let event = Published {
+            type_: format!("todo.{}", action),
+            todo_id,
+            todo,
+        };
+        let payload = serde_json::to_vec(&event)?;
+
+        match self {
+            Publisher::Kafka(partition) => {
+                let record = Record {
+                    key: Some(todo_id.to_string().into_bytes()),
+                    value: Some(payload),
+                    headers: BTreeMap::new(),
+                    timestamp: chrono::Utc::now(),
+                };
+                partition
+                    .produce(vec![record], Compression::NoCompression)
+                    .await?;
+            }
+            Publisher::Nats { client, subject } => {
+                client
+                    .publish(format!("{}.{}", subject, action), payload.into())
+                    .await?;
+                client.flush().await?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+use crate::crypto;
+use crate::error::Error;
+use crate::reporting;
+use crate::Todo;
+use actix_web::http::header;
+use actix_web::{get, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use rust_xlsxwriter::{Format, Workbook, Worksheet, XlsxError};
+use sqlx::PgPool;
+
+const XLSX_CONTENT_TYPE: &str = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+
+/// Days between Excel's epoch (1899-12-30) and the Unix epoch.
+const EXCEL_UNIX_EPOCH_DAYS: f64 = 25569.0;
+
+/// Excel stores times as fractional days since its epoch.
+fn excel_time(time: DateTime<Utc>) -> f64 {
+    EXCEL_UNIX_EPOCH_DAYS + time.timestamp() as f64 / 86400.0
+}
+
+impl From<XlsxError> for Error {
+    fn from(error: XlsxError) -> Self {
+        error!("Failed to generate a spreadsheet: {}", error);
+        reporting::record_cause(&error);
+        Error::InternalError
+    }
+}
+
+struct Formats {
+    header: Format,
+    time: Format,
+    hours: Format,
+}
+
+fn write_header(
+    sheet: &mut Worksheet,
+    formats: &Formats,
+    columns: &[(&str, f64)],
+) -> Result<(), XlsxError> {
+    for (column, (title, width)) in columns.iter().enumerate() {
+        sheet.write_string_with_format(0, column as u16, *title, &formats.header)?;
+        sheet.set_column_width(column as u16, *width)?;
+    }
+    sheet.set_freeze_panes(1, 0)?;
+    Ok(())
+}
+
+fn write_time(
+    sheet: &mut Worksheet,
+    row: u32,
+    column: u16,
+    time: Option<DateTime<Utc>>,
+    formats: &Formats,
+) -> Result<(), XlsxError> {
+    if let Some(time) = time {
+        sheet.write_number_with_format(row, column, excel_time(time), &formats.time)?;
+    }
+    Ok(())
+}
+
+fn todos_sheet(sheet: &mut Worksheet, todos: &[Todo], formats: &Formats) -> Result<(), XlsxError> {
+    sheet.set_name("Todos")?;
+    write_header(
+        sheet,
+        formats,
+        &[
+            ("Title", 48.0),
+            ("Status", 12.0),
+            ("Starred", 9.0),
+            ("Due (UTC)", 18.0),
+            ("Completed at (UTC)", 20.0),
+            ("Estimate (h)", 13.0),
+            ("Tracked (h)", 13.0),
+        ],
+    )?;
+
+    for (index, todo) in todos.iter().enumerate() {
+        let row = index as u32 + 1;
+        sheet.write_string(row, 0, &todo.title)?;
+        sheet.write_string(row, 1, todo.status().as_str())?;
+        sheet.write_boolean(row, 2, todo.starred)?;
+        write_time(sheet, row, 3, todo.due_at, formats)?;
+        write_time(sheet, row, 4, todo.completed_at, formats)?;
+        if let Some(estimate_minutes) = todo.estimate_minutes {
+            let hours = f64::from(estimate_minutes) / 60.0;
+            sheet.write_number_with_format(row, 5, hours, &formats.hours)?;
+        }
+        let tracked_hours = todo.tracked_seconds as f64 / 3600.0;
+        sheet.write_number_with_format(row, 6, tracked_hours, &formats.hours)?;
+    }
+    if !todos.is_empty() {
+        sheet.autofilter(0, 0, todos.len() as u32, 6)?;
+    }
+    Ok(())
+}
+
+fn summary_sheet(
+    sheet: &mut Worksheet,
+    todos: &[Todo],
+    formats: &Formats,
+) -> Result<(), XlsxError> {
+    sheet.set_name("Summary")?;
+    write_header(sheet, formats, &[("", 22.0), ("Todos", 10.0)])?;
+
+    let now = Utc::now();
+    let completed = todos.iter().filter(|todo| todo.completed).count();
+    let overdue = todos
+        .iter()
+        .filter(|todo| !todo.completed && todo.due_at.map_or(false, |due_at| due_at < now))
+        .count();
+    let rows = [
+        ("Total", todos.len()),
+        ("Completed", completed),
+        ("Open", todos.len() - completed),
+        ("Overdue", overdue),
+    ];
+    for (index, (label, count)) in rows.iter().enumerate() {
+        let row = index as u32 + 1;
+        sheet.write_string_with_format(row, 0, *label, &formats.header)?;
+        sheet.write_number(row, 1, *count as f64)?;
+    }
+    let exported_row = rows.len() as u32 + 2;
+    sheet.write_string_with_format(exported_row, 0, "Exported at (UTC)", &formats.header)?;
+    sheet.write_number_with_format(exported_row, 1, excel_time(now), &formats.time)?;
+    Ok(())
+}
+
+fn workbook(todos: &[Todo]) -> Result<Vec<u8>, XlsxError> {
+    let formats = Formats {
+        header: Format::new().set_bold(),
+        time: Format::new().set_num_format("yyyy-mm-dd hh:mm"),
+        hours: Format::new().set_num_format("0.00"),
+    };
+
+    let mut workbook = Workbook::new();
+    summary_sheet(workbook.add_worksheet(), todos, &formats)?;
+    todos_sheet(workbook.add_worksheet(), todos, &formats)?;
+    workbook.save_to_buffer()
+}
+
+/// Exports all todos as a spreadsheet with a summary sheet, for sharing the list with people
+/// who don't use the app.
+#[get("/todos/export.xlsx")]
+pub async fn export_xlsx_handler(pool: web::Data<PgPool>) -> Result<HttpResponse, Error> {
+    let todos = sqlx::query_as!(
+        Todo,
+        r#"SELECT * FROM todos ORDER BY starred DESC, "order", id"#
+    )
+    .fetch_all(pool.get_ref())
+    .await?
+    .into_iter()
+    .map(crypto::decrypt)
+    .collect::<Result<Vec<_>, _>>()?;
+    let body = workbook(&todos)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(XLSX_CONTENT_TYPE)
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"todos.xlsx\"",
+        ))
+        .body(body))
+}
@@ -0,0 +1,122 @@
+//! Feature flags for rolling experimental endpoints out gradually. Each flag has a rollout
+//! percentage, requests are assigned to a bucket so that a client sending the same
+//! `X-Client-Id` consistently sees the same set of features.
+
+use actix_web::dev::ResourceDef;
+use actix_web::HttpRequest;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Feature {
+    Sync,
+    Import,
+}
+
+impl Feature {
+    const ALL: &'static [Feature] = &[Feature::Sync, Feature::Import];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Feature::Sync => "sync",
+            Feature::Import => "import",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Feature> {
+        Feature::ALL
+            .iter()
+            .copied()
+            .find(|feature| feature.as_str() == name)
+    }
+}
+
+/// Routes served only when their feature is enabled for the request.
+const GATED_ROUTES: &[(&str, Feature)] = &[
+    ("/todos/changes", Feature::Sync),
+    ("/sync", Feature::Sync),
+    ("/import/todoist", Feature::Import),
+    ("/import/trello", Feature::Import),
+];
+
+pub struct FeatureFlags {
+    /// Percentage of requests each feature is enabled for, features not listed are fully on.
+    rollout: HashMap<Feature, u8>,
+    routes: Vec<(ResourceDef, Feature)>,
+}
+
+impl FeatureFlags {
+    /// Parses flags like `sync=25,import=off`, where a flag is a percentage, `on` or `off`.
+    pub fn parse(flags: &str) -> Result<Self, String> {
+        let mut rollout = HashMap::new();
+        for flag in flags
+            .split(',')
+            .map(str::trim)
+            .filter(|flag| !flag.is_empty())
+        {
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => (flag, "on"),
+            };
+            let feature =
+                Feature::parse(name).ok_or_else(|| format!("unknown feature flag {}", name))?;
+            let percentage = match value {
+                "on" => 100,
+                "off" => 0,
+                value => value
+                    .trim_end_matches('%')
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|percentage| *percentage <= 100)
+                    .ok_or_else(|| {
+                        format!("invalid rollout {} for feature flag {}", value, name)
+                    })?,
+            };
+            rollout.insert(feature, percentage);
+        }
+
+        Ok(FeatureFlags {
+            rollout,
+            routes: GATED_ROUTES
+                .iter()
+                .map(|(path, feature)| (ResourceDef::new(*path), *feature))
+                .collect(),
+        })
+    }
+
+    pub fn is_enabled(&self, feature: Feature, req: &HttpRequest) -> bool {
+        match self.rollout.get(&feature) {
+            None | Some(100) => true,
+            Some(0) => false,
+            Some(percentage) => bucket(feature, req) < *percentage,
+        }
+    }
+
+    /// The feature a request needs, if its path is gated and the feature is off for it.
+    pub fn disabled_for(&self, req: &HttpRequest) -> Option<Feature> {
+        self.routes
+            .iter()
+            .find(|(resource, _)| resource.is_match(req.path()))
+            .map(|(_, feature)| *feature)
+            .filter(|feature| !self.is_enabled(*feature, req))
+    }
+}
+
+/// Places the request in one of 100 buckets, stable per client when it sends `X-Client-Id`.
+/// Anonymous requests are bucketed randomly.
+fn bucket(feature: Feature, req: &HttpRequest) -> u8 {
+    let client_id = req
+        .headers()
+        .get("X-Client-Id")
+        .and_then(|client_id| client_id.to_str().ok());
+    match client_id {
+        Some(client_id) => {
+            let mut hasher = DefaultHasher::new();
+            feature.as_str().hash(&mut hasher);
+            client_id.hash(&mut hasher);
+            (hasher.finish() % 100) as u8
+        }
+        None => rand::random::<u8>() % 100,
+    }
+}
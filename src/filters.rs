@@ -0,0 +1,168 @@
+use crate::error::Error;
+use crate::i18n::Locale;
+use crate::pagination::Page;
+use crate::query;
+use crate::search::matching_todos;
+use crate::validation::{normalize_title, Validate, ValidationErrors};
+use crate::{RoutingService, TodosList};
+use actix_web::{delete, get, post, route, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+const MAX_FILTER_NAME_LENGTH: usize = 100;
+
+/// A named search query, so that lists like "Overdue at work" don't have to be rebuilt by
+/// clients on every request.
+#[derive(Serialize)]
+struct SavedFilter {
+    id: i64,
+    name: String,
+    query: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct SavedFilterPresenter {
+    #[serde(flatten)]
+    filter: SavedFilter,
+    url: String,
+    todos_url: String,
+}
+
+impl SavedFilterPresenter {
+    fn new(filter: SavedFilter, routing: &RoutingService) -> Self {
+        let url = routing.url(&format!("/filters/{}", filter.id));
+        let todos_url = format!("{}/todos", url);
+        SavedFilterPresenter {
+            filter,
+            url,
+            todos_url,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewSavedFilter {
+    name: String,
+    query: String,
+}
+
+impl Validate for NewSavedFilter {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+
+        let name = normalize_title(&self.name);
+        if name.is_empty() {
+            errors.add("name", "blank", &[]);
+        } else if name.chars().count() > MAX_FILTER_NAME_LENGTH {
+            errors.add(
+                "name",
+                "too_long",
+                &[("max", MAX_FILTER_NAME_LENGTH.to_string())],
+            );
+        }
+
+        if let Err(error) = query::parse(&self.query).and_then(|query| query.compile(1)) {
+            let reason = error.detail(Locale::En).unwrap_or_default();
+            errors.add("query", "invalid", &[("reason", reason)]);
+        }
+
+        errors.into_result()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PageParams {
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+#[get("/filters")]
+pub async fn filters_list_handler(
+    pool: web::Data<PgPool>,
+    routing: RoutingService,
+) -> Result<HttpResponse, Error> {
+    let filters = sqlx::query_as!(SavedFilter, r#"SELECT * FROM saved_filters ORDER BY id"#)
+        .fetch_all(pool.get_ref())
+        .await?;
+
+    let filters = filters
+        .into_iter()
+        .map(|filter| SavedFilterPresenter::new(filter, &routing))
+        .collect::<Vec<SavedFilterPresenter>>();
+    Ok(HttpResponse::Ok().json(filters))
+}
+
+#[post("/filters")]
+pub async fn create_filter_handler(
+    filter: web::Json<NewSavedFilter>,
+    pool: web::Data<PgPool>,
+    routing: RoutingService,
+) -> Result<HttpResponse, Error> {
+    filter.validate()?;
+
+    let filter = sqlx::query_as!(
+        SavedFilter,
+        r#"INSERT INTO saved_filters (name, query) VALUES ($1, $2) RETURNING id, name, query, created_at"#,
+        normalize_title(&filter.name),
+        filter.query.trim()
+    )
+    .fetch_one(pool.get_ref())
+    .await?;
+
+    Ok(HttpResponse::Ok().json(SavedFilterPresenter::new(filter, &routing)))
+}
+
+#[get("/filters/{id:\\d+}")]
+pub async fn show_filter_handler(
+    id: web::Path<i64>,
+    pool: web::Data<PgPool>,
+    routing: RoutingService,
+) -> Result<HttpResponse, Error> {
+    let filter = sqlx::query_as!(
+        SavedFilter,
+        r#"SELECT * FROM saved_filters WHERE id = $1"#,
+        *id
+    )
+    .fetch_one(pool.get_ref())
+    .await?;
+
+    Ok(HttpResponse::Ok().json(SavedFilterPresenter::new(filter, &routing)))
+}
+
+#[delete("/filters/{id:\\d+}")]
+pub async fn delete_filter_handler(
+    id: web::Path<i64>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, Error> {
+    let result = sqlx::query!(r#"DELETE FROM saved_filters WHERE id = $1"#, *id)
+        .execute(pool.get_ref())
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Lists the todos currently matching a saved filter.
+#[route("/filters/{id:\\d+}/todos", method = "GET", method = "HEAD")]
+pub async fn filter_todos_handler(
+    id: web::Path<i64>,
+    params: web::Query<PageParams>,
+    pool: web::Data<PgPool>,
+    routing: RoutingService,
+) -> Result<TodosList, Error> {
+    let page = Page::from_params(params.page, params.per_page)?;
+    let filter = sqlx::query_as!(
+        SavedFilter,
+        r#"SELECT * FROM saved_filters WHERE id = $1"#,
+        *id
+    )
+    .fetch_one(pool.get_ref())
+    .await?;
+    let query = query::parse(&filter.query)?;
+
+    matching_todos(pool.get_ref(), routing, &query, page).await
+}
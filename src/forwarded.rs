@@ -0,0 +1,81 @@
+//! The original scheme and host of requests coming through a reverse proxy, as reported by the
+//! `Forwarded` header or its `X-Forwarded-*` predecessors. Only meaningful when the proxy is
+//! trusted to set them, clients can send anything.
+
+use actix_web::HttpRequest;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Forwarded {
+    pub scheme: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+}
+
+fn header_value<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        // proxies append to the list, the first entry is the one the client connected to
+        .and_then(|value| value.split(',').next())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+/// The `proto` and `host` pairs of the first element of an RFC 7239 `Forwarded` header.
+fn forwarded_pairs(req: &HttpRequest) -> (Option<String>, Option<String>) {
+    let element = match header_value(req, "Forwarded") {
+        Some(element) => element,
+        None => return (None, None),
+    };
+
+    let mut proto = None;
+    let mut host = None;
+    for pair in element.split(';') {
+        let (name, value) = match pair.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim().trim_matches('"')),
+            None => continue,
+        };
+        if name.eq_ignore_ascii_case("proto") {
+            proto = Some(value.to_owned());
+        } else if name.eq_ignore_ascii_case("host") {
+            host = Some(value.to_owned());
+        }
+    }
+    (proto, host)
+}
+
+/// Splits `example.com:8443` or `[::1]:8443` into the host and the port.
+fn split_port(host: &str) -> (String, Option<u16>) {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !port.ends_with(']') => match port.parse() {
+            Ok(port) => (name.to_owned(), Some(port)),
+            Err(_) => (host.to_owned(), None),
+        },
+        _ => (host.to_owned(), None),
+    }
+}
+
+impl Forwarded {
+    /// Reads `Forwarded`, falling back to `X-Forwarded-Proto`, `X-Forwarded-Host` and
+    /// `X-Forwarded-Port` for whatever it doesn't specify.
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let (proto, host) = forwarded_pairs(req);
+        let scheme = proto
+            .or_else(|| header_value(req, "X-Forwarded-Proto").map(str::to_owned))
+            .map(|scheme| scheme.to_ascii_lowercase())
+            .filter(|scheme| scheme == "http" || scheme == "https");
+        let host = host.or_else(|| header_value(req, "X-Forwarded-Host").map(str::to_owned));
+
+        let (host, port) = match host {
+            Some(host) => {
+                let (host, port) = split_port(&host);
+                (Some(host), port)
+            }
+            None => (None, None),
+        };
+        let port = port
+            .or_else(|| header_value(req, "X-Forwarded-Port").and_then(|port| port.parse().ok()));
+
+        Forwarded { scheme, host, port }
+    }
+}
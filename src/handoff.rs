@@ -0,0 +1,110 @@
+//! Listening sockets and zero-downtime upgrades. Sockets are inherited through the listenfd
+//! protocol (HTTP first, then the optional metrics socket) or bound on startup. On SIGUSR2 the
+//! current binary is started again with the sockets passed down the same way, and this process
+//! stops accepting connections and exits once the open ones are finished.
+
+use actix_web::dev::Server;
+use actix_web::rt;
+use actix_web::rt::signal::unix::{signal, SignalKind};
+use listenfd::ListenFd;
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// Inherited sockets start at this descriptor, as in the systemd socket activation protocol.
+const LISTEN_FDS_START: RawFd = 3;
+/// Sockets are moved above this descriptor before being put in place in the new process, so
+/// that moving one doesn't close another.
+const SCRATCH_FD_MIN: RawFd = 64;
+
+pub struct Listeners {
+    pub http: TcpListener,
+    pub metrics: Option<TcpListener>,
+}
+
+impl Listeners {
+    /// Takes the inherited sockets, binding the addresses of the ones that weren't passed.
+    pub fn take(
+        listenfd: &mut ListenFd,
+        http_addr: &str,
+        metrics_addr: Option<&str>,
+    ) -> io::Result<Self> {
+        let http = match listenfd.take_tcp_listener(0)? {
+            Some(listener) => listener,
+            None => TcpListener::bind(http_addr)?,
+        };
+        let metrics = match listenfd.take_tcp_listener(1)? {
+            Some(listener) => Some(listener),
+            None => metrics_addr.map(TcpListener::bind).transpose()?,
+        };
+
+        Ok(Listeners { http, metrics })
+    }
+
+    /// Descriptors in the order they are passed on, matching `take`.
+    pub fn raw_fds(&self) -> Vec<RawFd> {
+        let mut fds = vec![self.http.as_raw_fd()];
+        fds.extend(self.metrics.as_ref().map(AsRawFd::as_raw_fd));
+        fds
+    }
+}
+
+/// Starts the current binary with the same arguments, handing it the listening sockets.
+fn spawn_successor(fds: &[RawFd]) -> io::Result<u32> {
+    let fds = fds.to_vec();
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .args(env::args_os().skip(1))
+        .env("LISTEN_FDS", fds.len().to_string())
+        .env_remove("LISTEN_PID");
+    unsafe {
+        command.pre_exec(move || {
+            let mut scratch = Vec::with_capacity(fds.len());
+            for fd in &fds {
+                let copy = libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, SCRATCH_FD_MIN);
+                if copy < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                scratch.push(copy);
+            }
+            // dup2 clears close-on-exec, so only the sockets in their final place are inherited
+            for (offset, fd) in scratch.into_iter().enumerate() {
+                if libc::dup2(fd, LISTEN_FDS_START + offset as RawFd) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+
+    Ok(command.spawn()?.id())
+}
+
+/// Hands the sockets over to a new process on SIGUSR2, then gracefully stops the servers.
+pub fn upgrade_on_signal(fds: Vec<RawFd>, servers: Vec<Server>) {
+    rt::spawn(async move {
+        let mut upgrades = match signal(SignalKind::user_defined2()) {
+            Ok(upgrades) => upgrades,
+            Err(e) => {
+                error!("Failed to listen for SIGUSR2, upgrades are disabled: {}", e);
+                return;
+            }
+        };
+
+        while upgrades.recv().await.is_some() {
+            match spawn_successor(&fds) {
+                Ok(pid) => {
+                    info!("Handed the sockets over to process {}, shutting down", pid);
+                    for server in &servers {
+                        server.stop(true).await;
+                    }
+                    return;
+                }
+                Err(e) => error!("Failed to start the new process, keeping on serving: {}", e),
+            }
+        }
+    });
+}
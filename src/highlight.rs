@@ -0,0 +1,106 @@
+//! Highlighting of the parts of titles that matched a search, so clients don't need their own
+//! tokenization. Matching folds case and accents like the `search_text` SQL function does.
+
+use serde::Serialize;
+use unicode_normalization::char::{decompose_canonical, is_combining_mark};
+
+/// A match in a title, in characters.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Match {
+    pub start: usize,
+    pub length: usize,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Highlight {
+    /// The HTML-escaped title with matches wrapped in `<em>`.
+    pub snippet: String,
+    pub matches: Vec<Match>,
+}
+
+/// Lowercases and strips accents, remembering for each folded character the index of the
+/// character it came from.
+fn fold(text: &str) -> (Vec<char>, Vec<usize>) {
+    let mut folded = Vec::new();
+    let mut origins = Vec::new();
+    for (index, c) in text.chars().enumerate() {
+        let mut base = Vec::new();
+        decompose_canonical(c, |c| {
+            if !is_combining_mark(c) {
+                base.push(c);
+            }
+        });
+        for c in base.into_iter().flat_map(char::to_lowercase) {
+            folded.push(c);
+            origins.push(index);
+        }
+    }
+    (folded, origins)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[derive(Debug, Clone)]
+pub struct Highlighter {
+    terms: Vec<Vec<char>>,
+}
+
+impl Highlighter {
+    /// Returns `None` without any terms, as there would never be anything to highlight.
+    pub fn new(terms: Vec<String>) -> Option<Self> {
+        let terms: Vec<Vec<char>> = terms
+            .iter()
+            .map(|term| fold(term).0)
+            .filter(|term| !term.is_empty())
+            .collect();
+        if terms.is_empty() {
+            None
+        } else {
+            Some(Highlighter { terms })
+        }
+    }
+
+    pub fn highlight(&self, title: &str) -> Highlight {
+        let chars: Vec<char> = title.chars().collect();
+        let (folded, origins) = fold(title);
+
+        // overlapping matches of different terms are merged into one
+        let mut covered = vec![false; chars.len()];
+        for term in &self.terms {
+            for start in 0..folded.len().saturating_sub(term.len() - 1) {
+                if folded[start..start + term.len()] == term[..] {
+                    for origin in &origins[start..start + term.len()] {
+                        covered[*origin] = true;
+                    }
+                }
+            }
+        }
+
+        let mut snippet = String::new();
+        let mut matches = Vec::new();
+        let mut index = 0;
+        while index < chars.len() {
+            let end = (index..chars.len())
+                .find(|end| covered[*end] != covered[index])
+                .unwrap_or(chars.len());
+            let part: String = chars[index..end].iter().collect();
+            if covered[index] {
+                snippet.push_str(&format!("<em>{}</em>", escape_html(&part)));
+                matches.push(Match {
+                    start: index,
+                    length: end - index,
+                });
+            } else {
+                snippet.push_str(&escape_html(&part));
+            }
+            index = end;
+        }
+
+        Highlight { snippet, matches }
+    }
+}
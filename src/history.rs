@@ -0,0 +1,441 @@
+use crate::crypto;
+use crate::error::Error;
+use crate::events;
+use crate::ids::{self, TodoRef};
+use crate::outbox::{self, Event};
+use crate::transaction::Tx;
+use crate::{make_room_for_order, RoutingService, Todo};
+use actix_web::{get, post, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::HashMap;
+
+/// Fields that change on every mutation and would only add noise to the diffs.
+const IGNORED_FIELDS: &[&str] = &["id", "version"];
+
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    Create,
+    Update,
+    Delete,
+}
+
+impl Action {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Action::Create => "create",
+            Action::Update => "update",
+            Action::Delete => "delete",
+        }
+    }
+}
+
+/// A snapshot of a todo before and after a single mutation.
+struct Revision {
+    id: i64,
+    todo_id: i64,
+    action: String,
+    before: Option<Value>,
+    after: Option<Value>,
+    created_at: DateTime<Utc>,
+}
+
+impl Revision {
+    fn decrypt(mut self) -> Result<Self, sqlx::Error> {
+        for snapshot in self.before.iter_mut().chain(self.after.iter_mut()) {
+            crypto::decrypt_snapshot(snapshot)?;
+        }
+        Ok(self)
+    }
+}
+
+#[derive(Serialize)]
+struct RevisionPresenter {
+    id: i64,
+    todo_id: i64,
+    action: String,
+    changes: Map<String, Value>,
+    created_at: DateTime<Utc>,
+}
+
+impl From<Revision> for RevisionPresenter {
+    fn from(revision: Revision) -> Self {
+        let changes = diff(revision.before.as_ref(), revision.after.as_ref());
+        RevisionPresenter {
+            id: revision.id,
+            todo_id: revision.todo_id,
+            action: revision.action,
+            changes,
+            created_at: revision.created_at,
+        }
+    }
+}
+
+/// Stores a revision of a todo, it should be called in the same transaction as the change itself.
+pub async fn record(
+    tx: &mut Transaction<'_, Postgres>,
+    action: Action,
+    before: Option<&Todo>,
+    after: Option<&Todo>,
+) -> Result<(), sqlx::Error> {
+    let todo_id = match after.or(before) {
+        Some(todo) => todo.id,
+        None => return Ok(()),
+    };
+    let snapshot = |todo: &Todo| {
+        let mut snapshot = serde_json::to_value(todo).ok()?;
+        crypto::encrypt_snapshot(&mut snapshot);
+        Some(snapshot)
+    };
+    let before = before.and_then(snapshot);
+    let after = after.and_then(snapshot);
+    if events::is_enabled() {
+        if let Some(todo) = after.as_ref().or_else(|| before.as_ref()) {
+            let event = Event::Change {
+                action: events::action_name(action).to_owned(),
+                todo_id,
+                todo: todo.clone(),
+            };
+            outbox::enqueue(tx, event).await?;
+        }
+    }
+
+    sqlx::query!(
+        r#"INSERT INTO todo_revisions (todo_id, action, before, after) VALUES ($1, $2, $3, $4)"#,
+        todo_id,
+        action.as_str(),
+        before,
+        after
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Stores an update revision for each todo of a bulk update, pairing the rows read before it
+/// with the ones it returned by id.
+pub async fn record_updates(
+    tx: &mut Transaction<'_, Postgres>,
+    before: Vec<Todo>,
+    after: Vec<Todo>,
+) -> Result<(), sqlx::Error> {
+    let mut before = before
+        .into_iter()
+        .map(|todo| (todo.id, todo))
+        .collect::<HashMap<i64, Todo>>();
+    for todo in &after {
+        if let Some(before) = before.remove(&todo.id) {
+            record(tx, Action::Update, Some(&before), Some(todo)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Lists the fields that differ between two snapshots as `{ "field": { "from": .., "to": .. } }`.
+pub fn diff(before: Option<&Value>, after: Option<&Value>) -> Map<String, Value> {
+    let empty = Map::new();
+    let before = before.and_then(Value::as_object).unwrap_or(&empty);
+    let after = after.and_then(Value::as_object).unwrap_or(&empty);
+
+    let mut changes = Map::new();
+    for key in before.keys().chain(after.keys()) {
+        if IGNORED_FIELDS.contains(&key.as_str()) || changes.contains_key(key) {
+            continue;
+        }
+
+        let from = before.get(key).cloned().unwrap_or(Value::Null);
+        let to = after.get(key).cloned().unwrap_or(Value::Null);
+        if from != to {
+            changes.insert(key.clone(), serde_json::json!({ "from": from, "to": to }));
+        }
+    }
+    changes
+}
+
+#[get("/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}/history")]
+pub async fn todo_history_handler(
+    todo: web::Path<TodoRef>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, Error> {
+    let id = ids::resolve(pool.get_ref(), *todo).await?;
+    let revisions = sqlx::query_as!(
+        Revision,
+        r#"SELECT id, todo_id, action, before, after, created_at FROM todo_revisions WHERE todo_id = $1 ORDER BY id"#,
+        id
+    )
+    .fetch_all(pool.get_ref())
+    .await?
+    .into_iter()
+    .map(Revision::decrypt)
+    .collect::<Result<Vec<_>, _>>()?;
+
+    if revisions.is_empty() {
+        return Err(Error::NotFound);
+    }
+
+    let revisions = revisions
+        .into_iter()
+        .map(RevisionPresenter::from)
+        .collect::<Vec<RevisionPresenter>>();
+    Ok(HttpResponse::Ok().json(revisions))
+}
+
+/// Reverses a single revision and records the reversal as a new revision, so reverting twice
+/// re-applies the original change. Returns the restored todo, or `None` when it was removed.
+async fn revert(
+    tx: &mut Transaction<'_, Postgres>,
+    revision: Revision,
+) -> Result<Option<Todo>, Error> {
+    let before = match revision.before {
+        Some(before) => {
+            Some(serde_json::from_value::<Todo>(before).map_err(|_| Error::InternalError)?)
+        }
+        None => None,
+    };
+    let current = sqlx::query_as!(
+        Todo,
+        r#"SELECT * FROM todos WHERE id = $1 FOR UPDATE"#,
+        revision.todo_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .map(crypto::decrypt)
+    .transpose()?;
+
+    match (before, current) {
+        // the todo was created, reverting removes it again
+        (None, Some(current)) => {
+            sqlx::query!(r#"DELETE FROM todos WHERE id = $1"#, current.id)
+                .execute(&mut *tx)
+                .await?;
+            record(tx, Action::Delete, Some(&current), None).await?;
+            Ok(None)
+        }
+        // the todo was updated, reverting restores the previous values
+        (Some(before), Some(current)) => {
+            if before.order != current.order {
+                make_room_for_order(tx, before.order, Some(current.id)).await?;
+            }
+            let todo = sqlx::query_as!(Todo, r#"UPDATE todos SET title = $1, completed = $2, "order" = $3, completed_at = $4, starred = $5, color = $6, due_at = $7, status = $8, custom_fields = $9, estimate_minutes = $10, latitude = $11, longitude = $12, place_name = $13, version = version + 1 WHERE id = $14 RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, crypto::encrypt_title(&before.title), before.completed, before.order, before.completed_at, before.starred, before.color, before.due_at, before.status().as_str(), before.custom_fields, before.estimate_minutes, before.latitude, before.longitude, before.place_name, current.id)
+                .fetch_one(&mut *tx)
+                .await
+                .and_then(crypto::decrypt)?;
+            record(tx, Action::Update, Some(&current), Some(&todo)).await?;
+            Ok(Some(todo))
+        }
+        // the todo was deleted, reverting brings it back with the same id
+        (Some(before), None) => {
+            make_room_for_order(tx, before.order, Some(before.id)).await?;
+            let todo = sqlx::query_as!(Todo, r#"INSERT INTO todos (id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid) VALUES ($1, $2, $3, $4, $5 + 1, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17) RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, before.id, crypto::encrypt_title(&before.title), before.completed, before.order, before.version, before.completed_at, before.starred, before.color, before.due_at, before.status().as_str(), before.custom_fields, before.tracked_seconds, before.estimate_minutes, before.latitude, before.longitude, before.place_name, before.uuid)
+                .fetch_one(&mut *tx)
+                .await
+                .and_then(crypto::decrypt)?;
+            record(tx, Action::Create, None, Some(&todo)).await?;
+            Ok(Some(todo))
+        }
+        (None, None) => Err(Error::Conflict {
+            reason: "the todo has changed since this revision and can't be reverted".to_owned(),
+        }),
+    }
+}
+
+async fn revert_response(
+    tx: &mut Transaction<'_, Postgres>,
+    revision: Revision,
+    routing: &RoutingService,
+) -> Result<HttpResponse, Error> {
+    match revert(tx, revision).await? {
+        Some(todo) => Ok(HttpResponse::Ok().json(routing.present(todo))),
+        None => Ok(HttpResponse::NoContent().finish()),
+    }
+}
+
+/// Reverts the most recent change of a single todo.
+#[post("/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}/revert")]
+pub async fn revert_todo_handler(
+    todo: web::Path<TodoRef>,
+    tx: Tx,
+    routing: RoutingService,
+) -> Result<HttpResponse, Error> {
+    let mut tx = tx.lock().await?;
+    let id = ids::resolve(&mut *tx, *todo).await?;
+    let revision = sqlx::query_as!(
+        Revision,
+        r#"SELECT id, todo_id, action, before, after, created_at FROM todo_revisions WHERE todo_id = $1 ORDER BY id DESC LIMIT 1"#,
+        id
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .and_then(Revision::decrypt)?;
+
+    let response = revert_response(&mut tx, revision, &routing).await?;
+    Ok(response)
+}
+
+/// Reverts the most recent change of any todo.
+#[post("/undo")]
+pub async fn undo_handler(tx: Tx, routing: RoutingService) -> Result<HttpResponse, Error> {
+    let mut tx = tx.lock().await?;
+    let revision = sqlx::query_as!(
+        Revision,
+        r#"SELECT id, todo_id, action, before, after, created_at FROM todo_revisions ORDER BY id DESC LIMIT 1"#
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .and_then(Revision::decrypt)?;
+
+    let response = revert_response(&mut tx, revision, &routing).await?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{routes, test_support};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::json;
+
+    #[test]
+    fn diffs_list_only_the_changed_fields() {
+        let before = json!({ "id": 1, "version": 1, "title": "Buy milk", "completed": false });
+        let after = json!({ "id": 1, "version": 2, "title": "Buy oat milk", "completed": false });
+        let changes = diff(Some(&before), Some(&after));
+        assert_eq!(
+            Value::Object(changes),
+            json!({ "title": { "from": "Buy milk", "to": "Buy oat milk" } })
+        );
+    }
+
+    #[test]
+    fn missing_snapshots_diff_from_and_to_null() {
+        let todo = json!({ "id": 1, "version": 1, "title": "Buy milk" });
+        assert_eq!(
+            Value::Object(diff(None, Some(&todo))),
+            json!({ "title": { "from": null, "to": "Buy milk" } })
+        );
+        assert_eq!(
+            Value::Object(diff(Some(&todo), None)),
+            json!({ "title": { "from": "Buy milk", "to": null } })
+        );
+    }
+
+    #[test]
+    fn fields_missing_on_one_side_are_null() {
+        let before = json!({ "title": "Buy milk" });
+        let after = json!({ "title": "Buy milk", "color": "#ff0000" });
+        assert_eq!(
+            Value::Object(diff(Some(&before), Some(&after))),
+            json!({ "color": { "from": null, "to": "#ff0000" } })
+        );
+    }
+
+    #[test]
+    fn unchanged_snapshots_have_no_diff() {
+        let todo = json!({ "id": 1, "version": 1, "title": "Buy milk" });
+        assert!(diff(Some(&todo), Some(&todo)).is_empty());
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs a database, see test_support"]
+    async fn reverting_restores_the_previous_values() {
+        let pool = test_support::pool().await;
+        let app = test::init_service(
+            App::new()
+                .configure(test_support::app_data(pool))
+                .configure(routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .set_json(&json!({ "title": "Buy milk" }))
+            .to_request();
+        let todo: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        let url = format!("/todos/{}", todo["id"]);
+        let req = test::TestRequest::patch()
+            .uri(&url)
+            .set_json(&json!({ "title": "Buy oat milk" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("{}/revert", url))
+            .to_request();
+        let reverted: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(reverted["title"], "Buy milk");
+
+        // the revert is a revision itself, reverting it re-applies the change
+        let req = test::TestRequest::post()
+            .uri(&format!("{}/revert", url))
+            .to_request();
+        let reverted: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(reverted["title"], "Buy oat milk");
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs a database, see test_support"]
+    async fn reverting_a_creation_removes_the_todo() {
+        let pool = test_support::pool().await;
+        let app = test::init_service(
+            App::new()
+                .configure(test_support::app_data(pool))
+                .configure(routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .set_json(&json!({ "title": "Buy milk" }))
+            .to_request();
+        let todo: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        let url = format!("/todos/{}", todo["id"]);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("{}/revert", url))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NO_CONTENT
+        );
+        let req = test::TestRequest::delete().uri(&url).to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs a database, see test_support"]
+    async fn reverting_a_deletion_brings_the_todo_back() {
+        let pool = test_support::pool().await;
+        let app = test::init_service(
+            App::new()
+                .configure(test_support::app_data(pool))
+                .configure(routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .set_json(&json!({ "title": "Buy milk" }))
+            .to_request();
+        let todo: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        let url = format!("/todos/{}", todo["id"]);
+        let req = test::TestRequest::delete().uri(&url).to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NO_CONTENT
+        );
+
+        let req = test::TestRequest::post()
+            .uri(&format!("{}/revert", url))
+            .to_request();
+        let restored: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(restored["id"], todo["id"]);
+        assert_eq!(restored["title"], "Buy milk");
+    }
+}
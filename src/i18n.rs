@@ -0,0 +1,231 @@
+use actix_web::http::header;
+use actix_web::HttpRequest;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Locale {
+    En,
+    De,
+}
+
+/// English messages, also used whenever a key is missing from another catalog.
+const EN: &[(&str, &str)] = &[
+    ("error.internal_error.title", "internal error"),
+    ("error.bad_request.title", "bad request"),
+    ("error.timeout.title", "timeout"),
+    ("error.service_unavailable.title", "service unavailable"),
+    (
+        "error.service_unavailable.detail",
+        "the database is currently unavailable, try again after the Retry-After period",
+    ),
+    ("error.maintenance.title", "under maintenance"),
+    (
+        "error.maintenance.detail",
+        "the service is under maintenance, only reading is possible right now",
+    ),
+    ("error.unauthorized.title", "unauthorized"),
+    ("error.not_found.title", "not found"),
+    ("error.method_not_allowed.title", "method not allowed"),
+    ("error.precondition_failed.title", "precondition failed"),
+    ("error.conflict.title", "conflict"),
+    ("error.validation_failed.title", "validation failed"),
+    (
+        "error.validation_failed.detail",
+        "one or more fields are invalid",
+    ),
+    ("error.sync_token_expired.title", "sync token expired"),
+    (
+        "error.sync_token_expired.detail",
+        "the sync token expired or its history was pruned, sync again without a token",
+    ),
+    ("error.invalid_query.title", "invalid query string"),
+    ("error.malformed_body.title", "malformed request body"),
+    ("validation.blank", "{field} can't be blank"),
+    (
+        "validation.too_long",
+        "{field} can't be longer than {max} characters",
+    ),
+    (
+        "validation.out_of_range",
+        "{field} must be between {min} and {max}",
+    ),
+    ("validation.invalid", "{field} is invalid: {reason}"),
+    (
+        "validation.invalid_color",
+        "{field} must be a hex color like #1e90ff or one of {palette}",
+    ),
+    (
+        "validation.invalid_transition",
+        "{field} can't change from {from} to {to}",
+    ),
+    (
+        "validation.invalid_key",
+        "{field} can only contain lowercase letters, digits and underscores",
+    ),
+    (
+        "validation.invalid_custom_field",
+        "{field} has an invalid value for {key}, expected a {kind}",
+    ),
+    (
+        "validation.unknown_custom_field",
+        "{field} has no field called {key}",
+    ),
+    (
+        "validation.invalid_blocker",
+        "{field} needs to be the id of another existing todo",
+    ),
+    (
+        "validation.unparsable_date",
+        "{field} needs to be a date like \"tomorrow 5pm\" or \"next friday\"",
+    ),
+    (
+        "validation.invalid_coordinates",
+        "{field} needs a latitude between -90 and 90 and a longitude between -180 and 180",
+    ),
+];
+
+const DE: &[(&str, &str)] = &[
+    ("error.internal_error.title", "interner Fehler"),
+    ("error.bad_request.title", "ungültige Anfrage"),
+    ("error.timeout.title", "Zeitüberschreitung"),
+    ("error.service_unavailable.title", "Dienst nicht verfügbar"),
+    (
+        "error.service_unavailable.detail",
+        "die Datenbank ist gerade nicht verfügbar, versuche es nach Ablauf von Retry-After erneut",
+    ),
+    ("error.maintenance.title", "Wartungsarbeiten"),
+    (
+        "error.maintenance.detail",
+        "der Dienst wird gerade gewartet, momentan ist nur Lesen möglich",
+    ),
+    ("error.unauthorized.title", "nicht autorisiert"),
+    ("error.not_found.title", "nicht gefunden"),
+    ("error.method_not_allowed.title", "Methode nicht erlaubt"),
+    ("error.precondition_failed.title", "Vorbedingung fehlgeschlagen"),
+    ("error.conflict.title", "Konflikt"),
+    ("error.validation_failed.title", "Validierung fehlgeschlagen"),
+    ("error.validation_failed.detail", "ein oder mehrere Felder sind ungültig"),
+    ("error.sync_token_expired.title", "Sync-Token abgelaufen"),
+    (
+        "error.sync_token_expired.detail",
+        "das Sync-Token ist abgelaufen oder sein Verlauf wurde bereinigt, synchronisiere erneut ohne Token",
+    ),
+    ("error.invalid_query.title", "ungültiger Query-String"),
+    ("error.malformed_body.title", "fehlerhafter Anfrageinhalt"),
+    ("validation.blank", "{field} darf nicht leer sein"),
+    (
+        "validation.too_long",
+        "{field} darf nicht länger als {max} Zeichen sein",
+    ),
+    ("validation.out_of_range", "{field} muss zwischen {min} und {max} liegen"),
+    ("validation.invalid", "{field} ist ungültig: {reason}"),
+    (
+        "validation.invalid_color",
+        "{field} muss eine Hex-Farbe wie #1e90ff oder eine von {palette} sein",
+    ),
+    (
+        "validation.invalid_transition",
+        "{field} kann nicht von {from} zu {to} wechseln",
+    ),
+    (
+        "validation.invalid_key",
+        "{field} darf nur Kleinbuchstaben, Ziffern und Unterstriche enthalten",
+    ),
+    (
+        "validation.invalid_custom_field",
+        "{field} hat einen ungültigen Wert für {key}, erwartet wird {kind}",
+    ),
+    ("validation.unknown_custom_field", "{field} hat kein Feld namens {key}"),
+    (
+        "validation.invalid_blocker",
+        "{field} muss die ID eines anderen vorhandenen Todos sein",
+    ),
+    (
+        "validation.unparsable_date",
+        "{field} muss ein Datum wie \"tomorrow 5pm\" oder \"next friday\" sein",
+    ),
+    (
+        "validation.invalid_coordinates",
+        "{field} braucht einen Breitengrad zwischen -90 und 90 und einen Längengrad zwischen -180 und 180",
+    ),
+];
+
+impl Locale {
+    /// Locales in the order of server preference, used to break ties between equal qualities.
+    const ALL: &'static [Locale] = &[Locale::En, Locale::De];
+
+    pub fn tag(self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::De => "de",
+        }
+    }
+
+    fn catalog(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Locale::En => EN,
+            Locale::De => DE,
+        }
+    }
+
+    /// Picks the best supported locale from the `Accept-Language` header, defaulting to English.
+    pub fn from_request(req: &HttpRequest) -> Locale {
+        let accept_language = match req
+            .headers()
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+        {
+            Some(accept_language) => accept_language,
+            None => return Locale::En,
+        };
+
+        let mut best = Locale::En;
+        let mut best_quality = 0.0;
+        for range in accept_language.split(',') {
+            let mut params = range.split(';');
+            // only the primary subtag matters, "de-AT" is served the "de" catalog
+            let language = params
+                .next()
+                .unwrap_or("")
+                .trim()
+                .split('-')
+                .next()
+                .unwrap_or("");
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            let locale = Locale::ALL
+                .iter()
+                .find(|locale| locale.tag().eq_ignore_ascii_case(language));
+            if let Some(locale) = locale {
+                if quality > best_quality {
+                    best = *locale;
+                    best_quality = quality;
+                }
+            }
+        }
+        best
+    }
+
+    /// Looks up a message, falling back to the English catalog.
+    pub fn message(self, key: &str) -> Option<&'static str> {
+        let lookup = |catalog: &'static [(&'static str, &'static str)]| {
+            catalog
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, message)| *message)
+        };
+
+        lookup(self.catalog()).or_else(|| lookup(EN))
+    }
+
+    /// Looks up a message and replaces its `{name}` placeholders with the given arguments.
+    pub fn format(self, key: &str, args: &[(&str, String)]) -> Option<String> {
+        let mut message = self.message(key)?.to_owned();
+        for (name, value) in args {
+            message = message.replace(&format!("{{{}}}", name), value);
+        }
+        Some(message)
+    }
+}
@@ -0,0 +1,369 @@
+//! Todos as iCalendar VTODO components (RFC 5545), with just the properties that map onto their
+//! fields: SUMMARY, STATUS, DUE, COMPLETED and PRIORITY for starred todos.
+
+use crate::status::Status;
+use crate::Todo;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+
+const PRODID: &str = "-//todo-backend//CalDAV//EN";
+/// Content lines longer than this many octets are folded.
+const MAX_LINE_LENGTH: usize = 75;
+const DATE_TIME_FORMAT: &str = "%Y%m%dT%H%M%S";
+/// Starred todos get the highest priority, and tasks with a high one (1 to 4) become starred.
+const STARRED_PRIORITY: u8 = 1;
+
+/// The properties of a VTODO sent by a client.
+#[derive(Debug, Default)]
+pub struct VTodo {
+    pub summary: Option<String>,
+    pub status: Option<Status>,
+    pub due: Option<DateTime<Utc>>,
+    pub completed: Option<DateTime<Utc>>,
+    pub priority: Option<u8>,
+}
+
+impl VTodo {
+    pub fn starred(&self) -> bool {
+        matches!(self.priority, Some(1..=4))
+    }
+
+    /// Parses the first VTODO of a calendar, ignoring the components nested in it.
+    pub fn parse(calendar: &str) -> Result<VTodo, String> {
+        let unfolded = calendar
+            .replace("\r\n", "\n")
+            .replace("\n ", "")
+            .replace("\n\t", "");
+
+        let mut vtodo: Option<VTodo> = None;
+        let mut nested = 0;
+        let mut finished = false;
+        for line in unfolded.lines() {
+            let property = match Property::parse(line) {
+                Some(property) => property,
+                None => continue,
+            };
+            let todo = match &mut vtodo {
+                Some(todo) => todo,
+                None => {
+                    if property.name == "BEGIN" && property.value.eq_ignore_ascii_case("VTODO") {
+                        vtodo = Some(VTodo::default());
+                    }
+                    continue;
+                }
+            };
+            match property.name.as_str() {
+                "BEGIN" => nested += 1,
+                "END" if nested > 0 => nested -= 1,
+                "END" => {
+                    finished = true;
+                    break;
+                }
+                // properties of alarms and the like
+                _ if nested > 0 => {}
+                "SUMMARY" => todo.summary = Some(unescape(&property.value)),
+                "STATUS" => todo.status = Some(parse_status(&property.value)?),
+                "DUE" => todo.due = Some(property.date_time()?),
+                "COMPLETED" => todo.completed = Some(property.date_time()?),
+                "PRIORITY" => todo.priority = property.value.trim().parse().ok(),
+                _ => {}
+            }
+        }
+
+        match vtodo {
+            Some(vtodo) if finished => Ok(vtodo),
+            _ => Err("the calendar needs to contain a VTODO".to_owned()),
+        }
+    }
+}
+
+/// A content line like `DUE;TZID=Europe/Berlin:20240102T090000`.
+struct Property {
+    name: String,
+    params: Vec<(String, String)>,
+    value: String,
+}
+
+impl Property {
+    fn parse(line: &str) -> Option<Property> {
+        // parameter values can be quoted and contain colons
+        let mut quoted = false;
+        let colon = line.char_indices().find_map(|(i, c)| match c {
+            '"' => {
+                quoted = !quoted;
+                None
+            }
+            ':' if !quoted => Some(i),
+            _ => None,
+        })?;
+
+        let mut parts = line[..colon].split(';');
+        let name = parts.next()?.trim().to_ascii_uppercase();
+        let params = parts
+            .filter_map(|param| {
+                let (key, value) = param.split_once('=')?;
+                Some((
+                    key.trim().to_ascii_uppercase(),
+                    value.trim_matches('"').to_owned(),
+                ))
+            })
+            .collect();
+        Some(Property {
+            name,
+            params,
+            value: line[colon + 1..].to_owned(),
+        })
+    }
+
+    fn param(&self, key: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Dates are taken as midnight UTC and floating times as UTC, times in an unknown time zone
+    /// as well.
+    fn date_time(&self) -> Result<DateTime<Utc>, String> {
+        let value = self.value.trim();
+        let invalid = || format!("{} has an invalid date: {:?}", self.name, value);
+
+        if let Ok(date) = NaiveDate::parse_from_str(value, "%Y%m%d") {
+            let midnight = date.and_hms_opt(0, 0, 0).ok_or_else(invalid)?;
+            return Ok(Utc.from_utc_datetime(&midnight));
+        }
+        if let Some(utc) = value.strip_suffix('Z') {
+            let time =
+                NaiveDateTime::parse_from_str(utc, DATE_TIME_FORMAT).map_err(|_| invalid())?;
+            return Ok(Utc.from_utc_datetime(&time));
+        }
+        let local =
+            NaiveDateTime::parse_from_str(value, DATE_TIME_FORMAT).map_err(|_| invalid())?;
+        match self.param("TZID").and_then(|tzid| tzid.parse::<Tz>().ok()) {
+            Some(tz) => tz
+                .from_local_datetime(&local)
+                .earliest()
+                .map(|time| time.with_timezone(&Utc))
+                .ok_or_else(invalid),
+            None => Ok(Utc.from_utc_datetime(&local)),
+        }
+    }
+}
+
+/// Clients only know open and finished tasks, cancelled ones count as finished.
+fn parse_status(status: &str) -> Result<Status, String> {
+    match status.trim().to_ascii_uppercase().as_str() {
+        "NEEDS-ACTION" => Ok(Status::Todo),
+        "IN-PROCESS" => Ok(Status::InProgress),
+        "COMPLETED" | "CANCELLED" => Ok(Status::Done),
+        status => Err(format!("unknown STATUS {:?}", status)),
+    }
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    format!("{}Z", time.format(DATE_TIME_FORMAT))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) | ('\\', Some('N')) => {
+                chars.next();
+                unescaped.push('\n');
+            }
+            ('\\', Some(escaped @ ('\\' | ';' | ','))) => {
+                chars.next();
+                unescaped.push(escaped);
+            }
+            (c, _) => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// Ends the line with CRLF, folding it without splitting characters.
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + 8);
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > MAX_LINE_LENGTH {
+            folded.push_str("\r\n ");
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+/// A calendar holding the todo as its only VTODO.
+pub fn to_calendar(todo: &Todo) -> String {
+    let status = match todo.status() {
+        Status::Done => "COMPLETED",
+        Status::InProgress => "IN-PROCESS",
+        Status::Todo | Status::Blocked => "NEEDS-ACTION",
+    };
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_owned(),
+        "VERSION:2.0".to_owned(),
+        format!("PRODID:{}", PRODID),
+        "BEGIN:VTODO".to_owned(),
+        format!("UID:{}", todo.uuid),
+        format!("DTSTAMP:{}", format_time(Utc::now())),
+        format!("SUMMARY:{}", escape(&todo.title)),
+        format!("STATUS:{}", status),
+    ];
+    if let Some(due_at) = todo.due_at {
+        lines.push(format!("DUE:{}", format_time(due_at)));
+    }
+    if let Some(completed_at) = todo.completed_at {
+        lines.push(format!("COMPLETED:{}", format_time(completed_at)));
+    }
+    if todo.starred {
+        lines.push(format!("PRIORITY:{}", STARRED_PRIORITY));
+    }
+    lines.push("END:VTODO".to_owned());
+    lines.push("END:VCALENDAR".to_owned());
+
+    lines.iter().map(|line| fold(line)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    fn calendar(vtodo: &str) -> String {
+        format!(
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\n{}END:VCALENDAR\r\n",
+            vtodo
+        )
+    }
+
+    #[test]
+    fn vtodos_are_parsed() {
+        let vtodo = VTodo::parse(&calendar(
+            "BEGIN:VTODO\r\nUID:1\r\nSUMMARY:Buy milk\r\nSTATUS:COMPLETED\r\nDUE:20240102T090000Z\r\nCOMPLETED:20240101\r\nPRIORITY:3\r\nEND:VTODO\r\n",
+        ))
+        .unwrap();
+        assert_eq!(vtodo.summary.as_deref(), Some("Buy milk"));
+        assert_eq!(vtodo.status, Some(Status::Done));
+        assert_eq!(vtodo.due, Some(utc("2024-01-02T09:00:00Z")));
+        assert_eq!(vtodo.completed, Some(utc("2024-01-01T00:00:00Z")));
+        assert!(vtodo.starred());
+    }
+
+    #[test]
+    fn folded_lines_are_unfolded() {
+        let vtodo = VTodo::parse(&calendar(
+            "BEGIN:VTODO\r\nSUMMARY:Buy milk\r\n  and\r\n\t bread\r\nEND:VTODO\r\n",
+        ))
+        .unwrap();
+        assert_eq!(vtodo.summary.as_deref(), Some("Buy milk and bread"));
+    }
+
+    #[test]
+    fn folded_titles_round_trip() {
+        let title = "é".repeat(100);
+        let line = fold(&format!("SUMMARY:{}", escape(&title)));
+        assert!(line.split("\r\n").all(|line| line.len() <= MAX_LINE_LENGTH));
+        let vtodo =
+            VTodo::parse(&calendar(&format!("BEGIN:VTODO\r\n{}END:VTODO\r\n", line))).unwrap();
+        assert_eq!(vtodo.summary, Some(title));
+    }
+
+    #[test]
+    fn properties_of_nested_components_are_ignored() {
+        let vtodo = VTodo::parse(&calendar(
+            "BEGIN:VTODO\r\nSUMMARY:Buy milk\r\nBEGIN:VALARM\r\nACTION:DISPLAY\r\nSUMMARY:Reminder\r\nEND:VALARM\r\nPRIORITY:9\r\nEND:VTODO\r\n",
+        ))
+        .unwrap();
+        assert_eq!(vtodo.summary.as_deref(), Some("Buy milk"));
+        assert_eq!(vtodo.priority, Some(9));
+        assert!(!vtodo.starred());
+    }
+
+    #[test]
+    fn times_are_taken_in_their_time_zone() {
+        let vtodo = VTodo::parse(&calendar(
+            "BEGIN:VTODO\r\nDUE;TZID=Europe/Berlin:20240102T090000\r\nEND:VTODO\r\n",
+        ))
+        .unwrap();
+        assert_eq!(vtodo.due, Some(utc("2024-01-02T08:00:00Z")));
+    }
+
+    #[test]
+    fn quoted_parameters_may_contain_colons() {
+        let vtodo = VTodo::parse(&calendar(
+            "BEGIN:VTODO\r\nDUE;TZID=\"Europe/Berlin\";X-NOTE=\"a:b\":20240102T090000\r\nEND:VTODO\r\n",
+        ))
+        .unwrap();
+        assert_eq!(vtodo.due, Some(utc("2024-01-02T08:00:00Z")));
+    }
+
+    #[test]
+    fn floating_times_and_unknown_time_zones_are_utc() {
+        for due in [
+            "DUE:20240102T090000",
+            "DUE;TZID=Mars/Olympus:20240102T090000",
+        ] {
+            let vtodo = VTodo::parse(&calendar(&format!(
+                "BEGIN:VTODO\r\n{}\r\nEND:VTODO\r\n",
+                due
+            )))
+            .unwrap();
+            assert_eq!(vtodo.due, Some(utc("2024-01-02T09:00:00Z")), "{}", due);
+        }
+    }
+
+    #[test]
+    fn invalid_dates_are_rejected() {
+        let calendar = calendar("BEGIN:VTODO\r\nDUE:tomorrow\r\nEND:VTODO\r\n");
+        assert!(VTodo::parse(&calendar).is_err());
+    }
+
+    #[test]
+    fn calendars_need_a_complete_vtodo() {
+        let event = calendar("BEGIN:VEVENT\r\nSUMMARY:Party\r\nEND:VEVENT\r\n");
+        assert!(VTodo::parse(&event).is_err());
+        assert!(VTodo::parse("BEGIN:VCALENDAR\r\nBEGIN:VTODO\r\nSUMMARY:Buy milk\r\n").is_err());
+    }
+
+    #[test]
+    fn statuses_map_onto_the_workflow() {
+        assert_eq!(parse_status("NEEDS-ACTION"), Ok(Status::Todo));
+        assert_eq!(parse_status("in-process"), Ok(Status::InProgress));
+        assert_eq!(parse_status("COMPLETED"), Ok(Status::Done));
+        assert_eq!(parse_status(" CANCELLED "), Ok(Status::Done));
+        assert!(parse_status("TENTATIVE").is_err());
+    }
+
+    #[test]
+    fn escaped_text_is_unescaped() {
+        assert_eq!(
+            unescape(r"Milk\, bread\; eggs\nand \\ more\N"),
+            "Milk, bread; eggs\nand \\ more\n"
+        );
+        // unknown escapes and a trailing backslash are kept as they are
+        assert_eq!(unescape(r"C:\temp\"), r"C:\temp\");
+    }
+
+    #[test]
+    fn escaping_round_trips() {
+        let text = "Milk, bread; eggs\nand \\n more";
+        assert_eq!(unescape(&escape(text)), text);
+    }
+}
@@ -0,0 +1,165 @@
+//! Todos are identified by their numeric id, or by a UUID that clients may pick themselves when
+//! creating todos offline, so they can refer to them before the server assigned an id.
+//!
+//! With the `ulid` id scheme the UUIDs are generated as ULIDs and exposed in their base32 form
+//! instead of the numeric ids, giving sortable ids that don't reveal how many todos there are.
+
+use crate::error::Error;
+use serde::{de, Deserialize, Deserializer};
+use sqlx::{Executor, Postgres};
+use std::str::FromStr;
+use ulid::Ulid;
+use uuid::Uuid;
+
+/// Which id todos are exposed by in their representations and URLs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IdScheme {
+    Numeric,
+    Ulid,
+}
+
+impl FromStr for IdScheme {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "numeric" => Ok(IdScheme::Numeric),
+            "ulid" => Ok(IdScheme::Ulid),
+            _ => Err(format!("unknown id scheme {}", value)),
+        }
+    }
+}
+
+impl IdScheme {
+    /// The id to expose instead of the numeric one, if any.
+    pub fn public_id(self, todo: &crate::Todo) -> Option<String> {
+        match self {
+            IdScheme::Numeric => None,
+            IdScheme::Ulid => Some(Ulid::from(todo.uuid.as_u128()).to_string()),
+        }
+    }
+}
+
+/// Either form of todo identifier, as taken from a path.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TodoRef {
+    Id(i64),
+    Uuid(Uuid),
+}
+
+impl FromStr for TodoRef {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = value.parse() {
+            return Ok(TodoRef::Id(id));
+        }
+        if let Ok(uuid) = Uuid::parse_str(value) {
+            return Ok(TodoRef::Uuid(uuid));
+        }
+        Ulid::from_string(value)
+            .map(|ulid| TodoRef::Uuid(Uuid::from_u128(ulid.into())))
+            .map_err(|_| format!("{} is neither a todo id, a UUID nor a ULID", value))
+    }
+}
+
+impl<'de> Deserialize<'de> for TodoRef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Deserializes a todo reference from JSON, where numeric ids are numbers and the others
+/// strings.
+pub fn deserialize_json<'de, D: Deserializer<'de>>(deserializer: D) -> Result<TodoRef, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Json {
+        Id(i64),
+        Text(String),
+    }
+
+    match Json::deserialize(deserializer)? {
+        Json::Id(id) => Ok(TodoRef::Id(id)),
+        Json::Text(value) => value.parse().map_err(de::Error::custom),
+    }
+}
+
+/// Looks up the numeric id of the referenced todo, on the pool or on the transaction of the
+/// handler.
+pub async fn resolve<'e, E>(executor: E, todo: TodoRef) -> Result<i64, Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    match todo {
+        TodoRef::Id(id) => Ok(id),
+        TodoRef::Uuid(uuid) => {
+            let id = sqlx::query_scalar!(r#"SELECT id FROM todos WHERE uuid = $1"#, uuid)
+                .fetch_one(executor)
+                .await?;
+            Ok(id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numeric_refs_are_ids() {
+        assert_eq!("42".parse(), Ok(TodoRef::Id(42)));
+    }
+
+    #[test]
+    fn uuid_refs_are_uuids() {
+        let uuid = "0f8e1c3a-6a3b-4c59-9a43-2f0a6b8e4d21";
+        assert_eq!(
+            uuid.parse(),
+            Ok(TodoRef::Uuid(Uuid::parse_str(uuid).unwrap()))
+        );
+    }
+
+    #[test]
+    fn other_refs_are_rejected() {
+        assert!("milk".parse::<TodoRef>().is_err());
+        assert!("".parse::<TodoRef>().is_err());
+    }
+
+    #[test]
+    fn ulid_refs_are_the_uuids_they_encode() {
+        let ulid = Ulid::new();
+        assert_eq!(
+            ulid.to_string().parse(),
+            Ok(TodoRef::Uuid(Uuid::from_u128(ulid.into())))
+        );
+    }
+
+    #[test]
+    fn id_schemes_are_parsed_by_name() {
+        assert_eq!("numeric".parse(), Ok(IdScheme::Numeric));
+        assert_eq!("ulid".parse(), Ok(IdScheme::Ulid));
+        assert!("uuid".parse::<IdScheme>().is_err());
+    }
+
+    #[test]
+    fn json_refs_are_numbers_or_strings() {
+        #[derive(Deserialize)]
+        struct Body {
+            #[serde(deserialize_with = "deserialize_json")]
+            id: TodoRef,
+        }
+        let parse = |json: &str| serde_json::from_str::<Body>(json).map(|body| body.id);
+
+        assert_eq!(parse(r#"{ "id": 42 }"#).ok(), Some(TodoRef::Id(42)));
+        assert_eq!(parse(r#"{ "id": "42" }"#).ok(), Some(TodoRef::Id(42)));
+        let uuid = "0f8e1c3a-6a3b-4c59-9a43-2f0a6b8e4d21";
+        assert_eq!(
+            parse(&format!(r#"{{ "id": "{}" }}"#, uuid)).ok(),
+            Some(TodoRef::Uuid(Uuid::parse_str(uuid).unwrap()))
+        );
+        assert!(parse(r#"{ "id": "milk" }"#).is_err());
+        assert!(parse(r#"{ "id": 4.2 }"#).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use crate::crypto;
+use crate::error::Error;
+use crate::history::{self, Action};
+use crate::transaction::Tx;
+use crate::validation::{normalize_title, MAX_TITLE_LENGTH};
+use crate::{next_order, Todo};
+use actix_web::{post, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+
+#[derive(Serialize, Default)]
+struct ImportSummary {
+    imported: usize,
+    skipped: usize,
+}
+
+/// The parts of a Todoist sync export that map onto todos. Projects have no local equivalent
+/// yet, so all tasks end up in the single list.
+#[derive(Deserialize)]
+struct TodoistExport {
+    items: Vec<TodoistItem>,
+}
+
+#[derive(Deserialize)]
+struct TodoistItem {
+    content: String,
+    #[serde(default)]
+    checked: bool,
+    #[serde(default)]
+    is_deleted: bool,
+    #[serde(default)]
+    child_order: i64,
+}
+
+/// The parts of a Trello board export that map onto todos. Cards become todos and archived
+/// cards or lists are skipped. Trello lists and checklists have no local equivalent yet.
+#[derive(Deserialize)]
+struct TrelloBoard {
+    lists: Vec<TrelloList>,
+    cards: Vec<TrelloCard>,
+}
+
+#[derive(Deserialize)]
+struct TrelloList {
+    id: String,
+    #[serde(default)]
+    closed: bool,
+    #[serde(default)]
+    pos: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TrelloCard {
+    name: String,
+    id_list: String,
+    #[serde(default)]
+    closed: bool,
+    #[serde(default)]
+    due_complete: bool,
+    #[serde(default)]
+    pos: f64,
+}
+
+/// Appends an imported todo to the end of the list, or returns `None` if its title isn't valid.
+async fn import_todo(
+    tx: &mut Transaction<'_, Postgres>,
+    title: &str,
+    completed: bool,
+) -> Result<Option<Todo>, sqlx::Error> {
+    let title = normalize_title(title);
+    if title.is_empty() || title.chars().count() > MAX_TITLE_LENGTH {
+        return Ok(None);
+    }
+
+    let order = next_order(tx).await?;
+    let todo = sqlx::query_as!(Todo, r#"INSERT INTO todos (title, completed, completed_at, status, "order") VALUES($1, $2, CASE WHEN $2 THEN now() END, CASE WHEN $2 THEN 'done' ELSE 'todo' END, $3) RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, crypto::encrypt_title(&title), completed, order)
+        .fetch_one(&mut *tx)
+        .await
+        .and_then(crypto::decrypt)?;
+    history::record(tx, Action::Create, None, Some(&todo)).await?;
+
+    Ok(Some(todo))
+}
+
+#[post("/import/todoist")]
+pub async fn import_todoist_handler(
+    tx: Tx,
+    export: web::Json<TodoistExport>,
+) -> Result<HttpResponse, Error> {
+    let mut items = export.into_inner().items;
+    items.sort_by_key(|item| item.child_order);
+
+    let mut summary = ImportSummary::default();
+    let mut tx = tx.lock().await?;
+    for item in items {
+        if item.is_deleted {
+            summary.skipped += 1;
+            continue;
+        }
+
+        match import_todo(&mut tx, &item.content, item.checked).await? {
+            Some(_) => summary.imported += 1,
+            None => summary.skipped += 1,
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+#[post("/import/trello")]
+pub async fn import_trello_handler(
+    tx: Tx,
+    board: web::Json<TrelloBoard>,
+) -> Result<HttpResponse, Error> {
+    let board = board.into_inner();
+    let list_pos = |id: &str| {
+        board
+            .lists
+            .iter()
+            .find(|list| list.id == id)
+            .map(|list| (list.closed, list.pos))
+    };
+
+    // cards keep the order they have on the board, list by list
+    let mut cards = board
+        .cards
+        .iter()
+        .map(|card| (list_pos(&card.id_list), card))
+        .collect::<Vec<_>>();
+    cards.sort_by(|(a_list, a), (b_list, b)| {
+        let a_key = (a_list.map(|(_, pos)| pos).unwrap_or(0.0), a.pos);
+        let b_key = (b_list.map(|(_, pos)| pos).unwrap_or(0.0), b.pos);
+        a_key
+            .partial_cmp(&b_key)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    let mut summary = ImportSummary::default();
+    let mut tx = tx.lock().await?;
+    for (list, card) in cards {
+        let list_closed = list.map(|(closed, _)| closed).unwrap_or(false);
+        if card.closed || list_closed {
+            summary.skipped += 1;
+            continue;
+        }
+
+        match import_todo(&mut tx, &card.name, card.due_complete).await? {
+            Some(_) => summary.imported += 1,
+            None => summary.skipped += 1,
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(summary))
+}
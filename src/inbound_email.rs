@@ -0,0 +1,197 @@
+//! Turns emails into todos, through Mailgun's inbound routes. A route forwarding to
+//! `POST /integrations/mailgun` posts every email it matches, signed with the key in
+//! `MAILGUN_SIGNING_KEY`. The subject becomes the title of the todo, or the first line of the
+//! text when there's no subject.
+//!
+//! Without accounts there's nobody to address an email to, so the todos are added to the one
+//! list, and only emails from the senders in `MAILGUN_SENDERS` are taken.
+
+use crate::admin::constant_time_eq;
+use crate::error::Error;
+use crate::history::{self, Action};
+use crate::notifications::SlackNotifier;
+use crate::transaction::Tx;
+use crate::validation::{normalize_title, MAX_TITLE_LENGTH};
+use crate::{crypto, next_order, RoutingService, Todo};
+use actix_web::{post, web, HttpResponse};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+/// Older requests are rejected, so that a captured one can't be replayed later on.
+const MAX_AGE_SECS: i64 = 5 * 60;
+
+pub struct InboundEmailSettings {
+    pub signing_key: String,
+    /// Lowercased addresses.
+    pub senders: Vec<String>,
+}
+
+/// Absent when inbound email isn't configured.
+pub struct InboundEmail(pub Option<InboundEmailSettings>);
+
+#[derive(Deserialize)]
+struct MailgunEmail {
+    timestamp: String,
+    token: String,
+    signature: String,
+    sender: String,
+    #[serde(default)]
+    subject: String,
+    /// The text without quoted replies and the signature.
+    #[serde(rename = "stripped-text", default)]
+    stripped_text: String,
+}
+
+impl MailgunEmail {
+    /// Checks that Mailgun signed the request: the signature is the hex HMAC-SHA256 of the
+    /// timestamp followed by the token.
+    fn verify(&self, signing_key: &str) -> bool {
+        let fresh = self
+            .timestamp
+            .parse::<i64>()
+            .map(|timestamp| (Utc::now().timestamp() - timestamp).abs() <= MAX_AGE_SECS)
+            .unwrap_or(false);
+        if !fresh {
+            return false;
+        }
+
+        let mut mac = match Hmac::<Sha256>::new_from_slice(signing_key.as_bytes()) {
+            Ok(mac) => mac,
+            Err(_) => return false,
+        };
+        mac.update(self.timestamp.as_bytes());
+        mac.update(self.token.as_bytes());
+        let expected = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        constant_time_eq(expected.as_bytes(), self.signature.as_bytes())
+    }
+
+    /// The subject, or the first line of the text, shortened to fit a title.
+    fn title(&self) -> Option<String> {
+        let title = Some(normalize_title(&self.subject))
+            .filter(|subject| !subject.is_empty())
+            .or_else(|| {
+                self.stripped_text
+                    .lines()
+                    .map(normalize_title)
+                    .find(|line| !line.is_empty())
+            })?;
+        if title.chars().count() <= MAX_TITLE_LENGTH {
+            return Some(title);
+        }
+        let mut title = title.chars().take(MAX_TITLE_LENGTH - 1).collect::<String>();
+        title.push('…');
+        Some(title)
+    }
+}
+
+/// Adds a todo for an email. Emails that can't become todos are still accepted, since Mailgun
+/// keeps retrying rejected ones.
+#[post("/integrations/mailgun")]
+pub async fn mailgun_inbound_handler(
+    email: web::Form<MailgunEmail>,
+    inbound: web::Data<InboundEmail>,
+    tx: Tx,
+    routing: RoutingService,
+    slack: web::Data<SlackNotifier>,
+) -> Result<HttpResponse, Error> {
+    let settings = inbound.0.as_ref().ok_or(Error::NotFound)?;
+    if !email.verify(&settings.signing_key) {
+        return Err(Error::Unauthorized);
+    }
+    if !settings
+        .senders
+        .contains(&email.sender.trim().to_lowercase())
+    {
+        info!("Ignored an email from {}", email.sender);
+        return Ok(HttpResponse::Ok().finish());
+    }
+    let title = match email.title() {
+        Some(title) => title,
+        None => {
+            info!(
+                "Ignored an email from {} without a subject or text",
+                email.sender
+            );
+            return Ok(HttpResponse::Ok().finish());
+        }
+    };
+
+    let mut tx = tx.lock().await?;
+    let order = next_order(&mut tx).await?;
+    let todo = sqlx::query_as!(Todo, r#"INSERT INTO todos (title, "order") VALUES($1, $2) RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, crypto::encrypt_title(&title), order)
+        .fetch_one(&mut *tx)
+        .await
+        .and_then(crypto::decrypt)?;
+    history::record(&mut tx, Action::Create, None, Some(&todo)).await?;
+
+    let todo = routing.present(todo);
+    slack.todo_created(&mut tx, &todo.todo, &todo.url).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "todo": todo.url })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "key-3ax6xnjp29jd6fds4gc373sgvjxteol0";
+
+    /// An email sent at `timestamp`, signed with `key` the way Mailgun signs them.
+    fn signed(timestamp: i64, key: &str) -> MailgunEmail {
+        let timestamp = timestamp.to_string();
+        let token = "5ad7a2d1cb7d4a1e8b5c0d3f9e6a4b2c".to_owned();
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(timestamp.as_bytes());
+        mac.update(token.as_bytes());
+        let signature = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        MailgunEmail {
+            timestamp,
+            token,
+            signature,
+            sender: "bob@example.com".to_owned(),
+            subject: "Buy milk".to_owned(),
+            stripped_text: String::new(),
+        }
+    }
+
+    #[test]
+    fn recently_signed_emails_are_verified() {
+        assert!(signed(Utc::now().timestamp(), KEY).verify(KEY));
+    }
+
+    #[test]
+    fn emails_signed_with_another_key_are_rejected() {
+        assert!(!signed(Utc::now().timestamp(), "another-key").verify(KEY));
+    }
+
+    #[test]
+    fn tampered_emails_are_rejected() {
+        let mut email = signed(Utc::now().timestamp(), KEY);
+        email.token.push('0');
+        assert!(!email.verify(KEY));
+    }
+
+    #[test]
+    fn stale_emails_are_rejected() {
+        let timestamp = Utc::now().timestamp() - MAX_AGE_SECS - 60;
+        assert!(!signed(timestamp, KEY).verify(KEY));
+    }
+
+    #[test]
+    fn emails_without_a_numeric_timestamp_are_rejected() {
+        let mut email = signed(Utc::now().timestamp(), KEY);
+        email.timestamp = "yesterday".to_owned();
+        assert!(!email.verify(KEY));
+    }
+}
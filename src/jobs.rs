@@ -0,0 +1,269 @@
+use crate::crypto;
+use crate::events::Publisher;
+use crate::history::{self, Action};
+use crate::metrics::Metrics;
+use crate::notifications::SlackNotifier;
+use crate::outbox;
+use crate::retention::{self, RetentionPolicy};
+use crate::retry;
+use crate::scheduler::Scheduler;
+use crate::sync;
+use crate::{lock_orders, RoutingService, Todo};
+use sqlx::PgPool;
+use std::rc::Rc;
+use std::time::Duration;
+
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const SYNC_TOKEN_CLEANUP_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const BUSINESS_METRICS_INTERVAL: Duration = Duration::from_secs(60);
+const OUTBOX_INTERVAL: Duration = Duration::from_secs(2);
+const OVERDUE_INTERVAL: Duration = Duration::from_secs(60);
+/// Overdue todos reported by a single run, the rest are picked up by the next ones.
+const OVERDUE_BATCH_SIZE: i64 = 100;
+
+/// Orders closer than this can't be reliably split anymore and trigger a rebalance.
+const MIN_ORDER_GAP: f64 = 1e-6;
+
+/// Todos are ranked with fractional orders, so dropping one between two others only needs the
+/// midpoint of their orders. Repeated splits eventually run out of precision, at which point
+/// the orders are renumbered back to consecutive whole numbers. The renumbering takes the same
+/// lock as placing a todo, and records a revision for every todo it moves.
+pub async fn rebalance_orders(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let mut tx = retry::begin(pool).await?;
+    lock_orders(&mut tx).await?;
+    let min_gap = sqlx::query_scalar!(
+        r#"SELECT MIN(gap) FROM (SELECT "order" - LAG("order") OVER (ORDER BY "order") AS gap FROM todos) gaps"#
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    match min_gap {
+        Some(gap) if gap < MIN_ORDER_GAP => {}
+        _ => return Ok(0),
+    }
+
+    let before = sqlx::query_as!(Todo, r#"SELECT * FROM todos FOR UPDATE"#)
+        .fetch_all(&mut tx)
+        .await?
+        .into_iter()
+        .map(crypto::decrypt)
+        .collect::<Result<Vec<_>, _>>()?;
+    let after = sqlx::query_as!(Todo, r#"UPDATE todos SET "order" = ranked.position, version = todos.version + 1 FROM (SELECT id, ROW_NUMBER() OVER (ORDER BY "order", id) AS position FROM todos) ranked WHERE todos.id = ranked.id AND todos."order" <> ranked.position RETURNING todos.id, todos.title, todos.completed, todos."order", todos.version, todos.completed_at, todos.starred, todos.color, todos.due_at, todos.status, todos.custom_fields, todos.tracked_seconds, todos.estimate_minutes, todos.latitude, todos.longitude, todos.place_name, todos.uuid"#)
+        .fetch_all(&mut tx)
+        .await?
+        .into_iter()
+        .map(crypto::decrypt)
+        .collect::<Result<Vec<_>, _>>()?;
+    let moved = after.len() as u64;
+    history::record_updates(&mut tx, before, after).await?;
+    tx.commit().await?;
+
+    Ok(moved)
+}
+
+/// Deletes todos that were completed more than `after_days` days ago.
+pub async fn cleanup_completed(pool: &PgPool, after_days: i32) -> Result<usize, sqlx::Error> {
+    let mut tx = retry::begin(pool).await?;
+    let todos = sqlx::query_as!(Todo, r#"DELETE FROM todos WHERE completed AND completed_at < now() - $1::integer * INTERVAL '1 day' RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, after_days)
+        .fetch_all(&mut tx)
+        .await?
+        .into_iter()
+        .map(crypto::decrypt)
+        .collect::<Result<Vec<_>, _>>()?;
+    for todo in &todos {
+        history::record(&mut tx, Action::Delete, Some(todo), None).await?;
+    }
+    tx.commit().await?;
+
+    Ok(todos.len())
+}
+
+/// Posts open todos whose due date has passed to Slack. A todo is reported once for each due
+/// date, so it's only reported again after being rescheduled. Todos are locked while they're
+/// reported, so instances running the job at the same time skip each other's.
+pub async fn notify_overdue(
+    pool: &PgPool,
+    slack: &SlackNotifier,
+    routing: &RoutingService,
+) -> Result<usize, sqlx::Error> {
+    let mut tx = retry::begin(pool).await?;
+    let todos = sqlx::query_as!(Todo, r#"SELECT todos.id, todos.title, todos.completed, todos."order", todos.version, todos.completed_at, todos.starred, todos.color, todos.due_at, todos.status, todos.custom_fields, todos.tracked_seconds, todos.estimate_minutes, todos.latitude, todos.longitude, todos.place_name, todos.uuid FROM todos LEFT JOIN overdue_notifications ON overdue_notifications.todo_id = todos.id WHERE NOT todos.completed AND todos.due_at < now() AND overdue_notifications.due_at IS DISTINCT FROM todos.due_at ORDER BY todos.due_at LIMIT $1 FOR UPDATE OF todos SKIP LOCKED"#, OVERDUE_BATCH_SIZE)
+        .fetch_all(&mut tx)
+        .await?
+        .into_iter()
+        .map(crypto::decrypt)
+        .collect::<Result<Vec<_>, _>>()?;
+    if todos.is_empty() {
+        return Ok(0);
+    }
+
+    for todo in &todos {
+        slack
+            .todo_overdue(&mut tx, todo, &routing.todo_url(todo))
+            .await?;
+    }
+    let ids: Vec<i64> = todos.iter().map(|todo| todo.id).collect();
+    sqlx::query!(r#"INSERT INTO overdue_notifications (todo_id, due_at) SELECT id, due_at FROM todos WHERE id = ANY($1) ON CONFLICT (todo_id) DO UPDATE SET due_at = excluded.due_at, notified_at = now()"#, &ids)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(todos.len())
+}
+
+/// Refreshes the gauges describing the todos themselves, so dashboards show product health
+/// and not just HTTP traffic. Todos don't keep a creation time, their revisions do.
+pub async fn refresh_business_metrics(pool: &PgPool, metrics: &Metrics) -> Result<(), sqlx::Error> {
+    let counts = sqlx::query!(r#"SELECT (SELECT COUNT(*) FROM todos) AS "total!", (SELECT COUNT(*) FROM todos WHERE completed) AS "completed!", (SELECT COUNT(*) FROM todo_revisions WHERE action = $1 AND created_at > now() - INTERVAL '1 hour') AS "created_last_hour!""#, Action::Create.as_str())
+        .fetch_one(pool)
+        .await?;
+
+    let completed_ratio = if counts.total > 0 {
+        counts.completed as f64 / counts.total as f64
+    } else {
+        0.0
+    };
+    metrics.set_gauge("todos_total", "Number of todos.", counts.total as f64);
+    metrics.set_gauge(
+        "todos_completed_ratio",
+        "Share of todos that are completed.",
+        completed_ratio,
+    );
+    metrics.set_gauge(
+        "todos_created_last_hour",
+        "Todos created within the last hour.",
+        counts.created_last_hour as f64,
+    );
+    Ok(())
+}
+
+/// How the configurable jobs run.
+pub struct JobsConfig {
+    pub rebalance_every: Duration,
+    /// Completed todos are kept for good when `None`.
+    pub cleanup_completed_after_days: Option<i32>,
+    pub retention: RetentionPolicy,
+}
+
+/// Registers the built-in jobs, the cleanups of completed todos and old revisions being opt-in,
+/// and the overdue notifications only running with a Slack webhook configured.
+pub fn register(
+    scheduler: &mut Scheduler,
+    pool: &PgPool,
+    config: JobsConfig,
+    metrics: &Metrics,
+    slack: &SlackNotifier,
+    routing: &RoutingService,
+    publisher: Option<Rc<Publisher>>,
+) {
+    let JobsConfig {
+        rebalance_every,
+        cleanup_completed_after_days,
+        retention,
+    } = config;
+    let rebalance_pool = pool.clone();
+    scheduler.every("order_rebalance", rebalance_every, move || {
+        let pool = rebalance_pool.clone();
+        async move {
+            let count = rebalance_orders(&pool).await?;
+            if count > 0 {
+                info!("Rebalanced the order of {} todos", count);
+            }
+            Ok(())
+        }
+    });
+
+    let tokens_pool = pool.clone();
+    scheduler.every(
+        "sync_token_cleanup",
+        SYNC_TOKEN_CLEANUP_INTERVAL,
+        move || {
+            let pool = tokens_pool.clone();
+            async move {
+                let count = sync::delete_expired_tokens(&pool).await?;
+                if count > 0 {
+                    info!("Deleted {} expired sync tokens", count);
+                }
+                Ok(())
+            }
+        },
+    );
+
+    if slack.is_enabled() {
+        let overdue_pool = pool.clone();
+        let slack = slack.clone();
+        let routing = routing.clone();
+        scheduler.every("overdue_notifications", OVERDUE_INTERVAL, move || {
+            let pool = overdue_pool.clone();
+            let slack = slack.clone();
+            let routing = routing.clone();
+            async move {
+                let count = notify_overdue(&pool, &slack, &routing).await?;
+                if count > 0 {
+                    info!("Notified about {} overdue todos", count);
+                }
+                Ok(())
+            }
+        });
+    }
+
+    let outbox_pool = pool.clone();
+    let slack = slack.clone();
+    scheduler.every("outbox_dispatch", OUTBOX_INTERVAL, move || {
+        let pool = outbox_pool.clone();
+        let slack = slack.clone();
+        let publisher = publisher.clone();
+        async move {
+            let dispatched = outbox::dispatch(&pool, &slack, publisher.as_deref()).await?;
+            if dispatched.delivered > 0 || dispatched.failed > 0 {
+                info!(
+                    "Delivered {} events, {} failed",
+                    dispatched.delivered, dispatched.failed
+                );
+            }
+            Ok(())
+        }
+    });
+
+    let metrics_pool = pool.clone();
+    let metrics = metrics.clone();
+    scheduler.every("business_metrics", BUSINESS_METRICS_INTERVAL, move || {
+        let pool = metrics_pool.clone();
+        let metrics = metrics.clone();
+        async move {
+            refresh_business_metrics(&pool, &metrics).await?;
+            Ok(())
+        }
+    });
+
+    if let Some(after_days) = cleanup_completed_after_days {
+        let cleanup_pool = pool.clone();
+        scheduler.every("completed_cleanup", CLEANUP_INTERVAL, move || {
+            let pool = cleanup_pool.clone();
+            async move {
+                let count = cleanup_completed(&pool, after_days).await?;
+                if count > 0 {
+                    info!("Cleaned up {} completed todos", count);
+                }
+                Ok(())
+            }
+        });
+    }
+
+    if retention.is_enabled() {
+        let retention_pool = pool.clone();
+        scheduler.every("retention", CLEANUP_INTERVAL, move || {
+            let pool = retention_pool.clone();
+            async move {
+                let deleted = retention::enforce(&pool, retention).await?;
+                if deleted.revisions > 0 || deleted.tombstones > 0 {
+                    info!(
+                        "Deleted {} expired revisions and {} expired tombstones",
+                        deleted.revisions, deleted.tombstones
+                    );
+                }
+                Ok(())
+            }
+        });
+    }
+}
@@ -0,0 +1,122 @@
+use crate::crypto;
+use crate::error::Error;
+use crate::validation::ValidationErrors;
+use crate::{RoutingService, Todo, TodoPresenter};
+use actix_web::{get, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
+const DEFAULT_RADIUS_METERS: f64 = 1000.0;
+const MAX_RADIUS_METERS: f64 = 50_000.0;
+const MAX_PLACE_NAME_LENGTH: usize = 255;
+
+/// Where a todo is meant to be done, sent by clients as a single object so a location is
+/// always complete.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub place_name: Option<String>,
+}
+
+impl Location {
+    pub fn validate(&self, errors: &mut ValidationErrors) {
+        validate_coordinates(errors, self.latitude, self.longitude);
+        if let Some(place_name) = &self.place_name {
+            if place_name.trim().chars().count() > MAX_PLACE_NAME_LENGTH {
+                errors.add(
+                    "location",
+                    "too_long",
+                    &[("max", MAX_PLACE_NAME_LENGTH.to_string())],
+                );
+            }
+        }
+    }
+
+    pub fn place_name(&self) -> Option<String> {
+        self.place_name
+            .as_deref()
+            .map(str::trim)
+            .filter(|place_name| !place_name.is_empty())
+            .map(str::to_owned)
+    }
+}
+
+fn validate_coordinates(errors: &mut ValidationErrors, latitude: f64, longitude: f64) {
+    if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+        errors.add("location", "invalid_coordinates", &[]);
+    }
+}
+
+/// The great-circle distance between two points, matching `haversine_distance` in the database.
+fn haversine_distance(latitude1: f64, longitude1: f64, latitude2: f64, longitude2: f64) -> f64 {
+    let d_latitude = (latitude2 - latitude1).to_radians();
+    let d_longitude = (longitude2 - longitude1).to_radians();
+    let a = (d_latitude / 2.0).sin().powi(2)
+        + latitude1.to_radians().cos()
+            * latitude2.to_radians().cos()
+            * (d_longitude / 2.0).sin().powi(2);
+    // rounding can push the square root just above 1 for antipodal points
+    2.0 * EARTH_RADIUS_METERS * a.sqrt().min(1.0).asin()
+}
+
+#[derive(Deserialize)]
+pub struct NearbyParams {
+    lat: f64,
+    lng: f64,
+    /// In meters.
+    radius: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct NearbyTodo {
+    #[serde(flatten)]
+    todo: TodoPresenter,
+    distance_meters: f64,
+}
+
+/// Open todos located within `radius` meters of a point, closest first.
+#[get("/todos/nearby")]
+pub async fn nearby_todos_handler(
+    params: web::Query<NearbyParams>,
+    pool: web::Data<PgPool>,
+    routing: RoutingService,
+) -> Result<HttpResponse, Error> {
+    let NearbyParams { lat, lng, radius } = params.into_inner();
+    let radius = radius.unwrap_or(DEFAULT_RADIUS_METERS);
+    let mut errors = ValidationErrors::default();
+    validate_coordinates(&mut errors, lat, lng);
+    if !errors.is_empty() || !(0.0..=MAX_RADIUS_METERS).contains(&radius) {
+        return Err(Error::InvalidQuery {
+            reason: format!(
+                "lat needs to be within -90 and 90, lng within -180 and 180 and radius within 0 and {} meters",
+                MAX_RADIUS_METERS
+            ),
+        });
+    }
+
+    let todos = sqlx::query_as!(Todo, r#"SELECT * FROM todos WHERE NOT completed AND latitude IS NOT NULL AND longitude IS NOT NULL AND haversine_distance($1, $2, latitude, longitude) <= $3 ORDER BY haversine_distance($1, $2, latitude, longitude), id"#, lat, lng, radius)
+        .fetch_all(pool.get_ref())
+        .await?
+        .into_iter()
+        .map(crypto::decrypt)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let todos = todos
+        .into_iter()
+        .map(|todo| {
+            let distance_meters = match (todo.latitude, todo.longitude) {
+                (Some(latitude), Some(longitude)) => {
+                    haversine_distance(lat, lng, latitude, longitude)
+                }
+                _ => 0.0,
+            };
+            NearbyTodo {
+                todo: routing.present(todo),
+                distance_meters,
+            }
+        })
+        .collect::<Vec<NearbyTodo>>();
+    Ok(HttpResponse::Ok().json(todos))
+}
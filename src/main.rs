@@ -1,204 +1,989 @@
 #[macro_use]
 extern crate log;
 
+mod admin;
+mod allow;
+mod bench;
+mod breaker;
+mod caching;
+mod config;
+mod crypto;
+mod custom_fields;
+mod dav;
+mod dependencies;
+mod deprecation;
+mod due;
+mod error;
+mod events;
+mod export;
+mod features;
+mod filters;
+mod forwarded;
+mod handoff;
+mod highlight;
+mod history;
+mod i18n;
+mod ical;
+mod ids;
+mod import;
+mod inbound_email;
+mod jobs;
+mod location;
+mod maintenance;
+mod metrics;
+mod mqtt;
+mod negotiation;
+mod notifications;
+mod outbox;
+mod pagination;
+mod poll;
+mod proto;
+mod query;
+mod reporting;
+mod request_id;
+mod retention;
+mod retry;
+mod scheduler;
+mod search;
+mod settings;
+mod status;
+mod sync;
+mod telegram;
+#[cfg(test)]
+mod test_support;
+mod time_tracking;
+mod transaction;
+mod validation;
+mod version;
+
 use actix_cors::Cors;
+use actix_web::body::{AnyBody, MessageBody};
+use actix_web::http::Method;
+use actix_web::middleware::{NormalizePath, TrailingSlash};
 use actix_web::{
-    delete, error, get, http::header, http::StatusCode, patch, post, web, App, HttpResponse,
-    HttpResponseBuilder, HttpServer, Responder, HttpRequest
+    delete, dev::Payload, dev::Service, dev::ServiceResponse, get, http::header, http::StatusCode,
+    patch, post, put, route, web, web::Bytes, App, FromRequest, HttpRequest, HttpResponse,
+    HttpServer, Responder,
 };
-use anyhow::Result;
-use derive_more::{Display, Error as DeriveError};
+use allow::AllowedMethods;
+use anyhow::{Context, Result};
+use breaker::CircuitBreaker;
+use chrono::{DateTime, Utc};
+use config::Config;
+use dependencies::DependencySettings;
+use error::Error;
+use events::Publisher;
+use forwarded::Forwarded;
+use futures_util::future::{self, FutureExt, TryFutureExt};
+use futures_util::stream::{self, LocalBoxStream, StreamExt, TryStreamExt};
+use highlight::{Highlight, Highlighter};
+use history::Action;
+use i18n::Locale;
+use ids::{IdScheme, TodoRef};
+use inbound_email::InboundEmail;
+use jobs::JobsConfig;
 use listenfd::ListenFd;
+use location::Location;
+use log::LevelFilter;
+use maintenance::MaintenanceMode;
+use metrics::Metrics;
+use negotiation::{Body, Format};
+use notifications::SlackNotifier;
+use pagination::Page;
+use poll::ChangeFeed;
+use prost::Message;
+use scheduler::Scheduler;
 use serde::{Deserialize, Serialize};
 use sqlx::postgres::PgPoolOptions;
-use sqlx::PgPool;
+use sqlx::{ConnectOptions, Executor, PgPool, Postgres, Transaction};
+use status::{Status, Workflow};
 use std::env;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::process;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use telegram::TelegramBot;
+use transaction::Tx;
+use uuid::Uuid;
+use validation::{
+    normalize_color, normalize_title, validate_color, validate_estimate, validate_order,
+    validate_title, Validate, ValidationErrors, MAX_TITLE_LENGTH,
+};
+
+const COPY_SUFFIX: &str = " (copy)";
+/// The advisory lock taken while placing todos, "order" in ASCII.
+const ORDER_LOCK_KEY: i64 = 0x6f72646572;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, sqlx::FromRow)]
 struct Todo {
     id: i64,
     title: String,
     completed: bool,
-    order: i64,
+    order: f64,
+    version: i64,
+    completed_at: Option<DateTime<Utc>>,
+    /// Starred todos are listed before the others.
+    #[serde(default)]
+    starred: bool,
+    color: Option<String>,
+    due_at: Option<DateTime<Utc>>,
+    /// One of the `Status` values, `completed` is kept in sync with it for older clients.
+    #[serde(default)]
+    status: String,
+    /// Values of the fields defined through `custom_fields`, keyed by field.
+    #[serde(default = "empty_object")]
+    custom_fields: serde_json::Value,
+    /// Time tracked with stopped timers, running ones aren't included yet.
+    #[serde(default)]
+    tracked_seconds: i64,
+    estimate_minutes: Option<i32>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    place_name: Option<String>,
+    /// Picked by the client or generated, revisions recorded before UUIDs existed get a new one.
+    #[serde(default = "Uuid::new_v4")]
+    uuid: Uuid,
+}
+
+fn empty_object() -> serde_json::Value {
+    serde_json::Value::Object(serde_json::Map::new())
+}
+
+impl Todo {
+    /// Revisions recorded before statuses existed only know about `completed`.
+    fn status(&self) -> Status {
+        self.status
+            .parse::<Status>()
+            .unwrap_or(Status::Todo)
+            .with_completed(self.completed)
+    }
 }
 
 #[derive(Deserialize)]
 struct NewTodo {
+    /// Lets clients refer to todos they created offline before knowing their ids.
+    uuid: Option<Uuid>,
     title: String,
-    order: Option<i64>,
+    order: Option<f64>,
+    color: Option<String>,
+    due_at: Option<DateTime<Utc>>,
+    /// A due date in plain words, like "tomorrow 5pm", takes precedence over `due_at`.
+    due: Option<String>,
+    estimate_minutes: Option<i32>,
+    location: Option<Location>,
+    #[serde(default)]
+    custom_fields: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Deserialize)]
 struct UpdateTodo {
     title: Option<String>,
     completed: Option<bool>,
-    order: Option<i64>,
+    status: Option<Status>,
+    order: Option<f64>,
+    starred: Option<bool>,
+    /// `null` removes the color, leaving the field out keeps it.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    color: Option<Option<String>>,
+    /// `null` removes the due date, leaving the field out keeps it.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    due_at: Option<Option<DateTime<Utc>>>,
+    /// `null` removes the estimate, leaving the field out keeps it.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    estimate_minutes: Option<Option<i32>>,
+    /// A due date in plain words, like "tomorrow 5pm", takes precedence over `due_at`.
+    due: Option<String>,
+    /// `null` removes the location, leaving the field out keeps it.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    location: Option<Option<Location>>,
+    /// Merged into the current values, `null` removes a value.
+    custom_fields: Option<serde_json::Map<String, serde_json::Value>>,
+    /// The version the client based its changes on, stale versions are rejected.
+    version: Option<i64>,
+}
+
+/// Tells a field explicitly set to `null` apart from a missing one, which `#[serde(default)]`
+/// turns into `None`.
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+impl Validate for NewTodo {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        validate_title(&mut errors, &self.title);
+        if let Some(order) = self.order {
+            validate_order(&mut errors, order);
+        }
+        if let Some(color) = &self.color {
+            validate_color(&mut errors, color);
+        }
+        if let Some(estimate_minutes) = self.estimate_minutes {
+            validate_estimate(&mut errors, estimate_minutes);
+        }
+        if let Some(location) = &self.location {
+            location.validate(&mut errors);
+        }
+        errors.into_result()
+    }
+}
+
+impl Validate for UpdateTodo {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if let Some(title) = &self.title {
+            validate_title(&mut errors, title);
+        }
+        if let Some(order) = self.order {
+            validate_order(&mut errors, order);
+        }
+        if let Some(Some(color)) = &self.color {
+            validate_color(&mut errors, color);
+        }
+        if let Some(Some(estimate_minutes)) = self.estimate_minutes {
+            validate_estimate(&mut errors, estimate_minutes);
+        }
+        if let Some(Some(location)) = &self.location {
+            location.validate(&mut errors);
+        }
+        errors.into_result()
+    }
 }
 
-#[derive(Serialize)]
 struct TodoPresenter {
-    #[serde(flatten)]
     todo: Todo,
     url: String,
+    /// Replaces the numeric id in the representation, see `IdScheme`.
+    public_id: Option<String>,
+}
+
+impl Serialize for TodoPresenter {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut todo = serde_json::to_value(&self.todo).map_err(serde::ser::Error::custom)?;
+        if let Some(fields) = todo.as_object_mut() {
+            if let Some(public_id) = &self.public_id {
+                fields.insert("id".to_owned(), serde_json::Value::from(public_id.as_str()));
+            }
+            fields.insert("url".to_owned(), serde_json::Value::from(self.url.as_str()));
+        }
+        todo.serialize(serializer)
+    }
 }
 
+#[derive(Deserialize)]
+struct TodosFilter {
+    completed_after: Option<DateTime<Utc>>,
+    completed_before: Option<DateTime<Utc>>,
+    starred: Option<bool>,
+    /// A custom field value to match, as `key:value`.
+    custom_field: Option<String>,
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct DeleteFilter {
+    completed: Option<bool>,
+    /// Only todos completed before this time, which never matches todos that aren't completed.
+    completed_before: Option<DateTime<Utc>>,
+    /// A custom field value to match, as `key:value`.
+    custom_field: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DeletedTodos {
+    deleted: usize,
+}
+
+#[derive(Serialize)]
+struct TodoStats {
+    total: i64,
+    completed: i64,
+    active: i64,
+}
+
+/// Todos streamed straight from the database, so that memory usage doesn't grow with the
+/// size of the list.
 struct TodosList {
-    todos: Vec<Todo>,
+    todos: LocalBoxStream<'static, Result<Todo, sqlx::Error>>,
+    etag: String,
+    total: i64,
+    page: Option<Page>,
     routing: RoutingService,
+    /// Set for searches, adding a `highlight` of the matches to each todo.
+    highlighter: Option<Highlighter>,
+}
+
+/// A todo found by a search.
+#[derive(Serialize)]
+struct SearchHit {
+    #[serde(flatten)]
+    todo: TodoPresenter,
+    highlight: Highlight,
+}
+
+/// A todo of a list, with a highlight of the matches for searches.
+fn list_item(
+    routing: &RoutingService,
+    highlighter: Option<&Highlighter>,
+    todo: Todo,
+) -> Result<serde_json::Value, Error> {
+    let item = match highlighter {
+        Some(highlighter) => {
+            let highlight = highlighter.highlight(&todo.title);
+            let todo = routing.present(todo);
+            serde_json::to_value(&SearchHit { todo, highlight })
+        }
+        None => serde_json::to_value(&routing.present(todo)),
+    };
+    item.map_err(|_| Error::InternalError)
 }
 
-#[derive(Debug, Display, DeriveError)]
-enum Error {
-    #[display(fmt = "internal error")]
-    InternalError,
+impl Responder for TodosList {
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        let format = negotiation::preferred_format(req);
+        let etag = match format {
+            Format::Json => caching::quote_etag(&self.etag),
+            Format::MessagePack => caching::quote_etag(&format!("{}-msgpack", self.etag)),
+            Format::Protobuf => caching::quote_etag(&format!("{}-protobuf", self.etag)),
+            Format::PlainText => caching::quote_etag(&format!("{}-text", self.etag)),
+        };
+        if let Some(response) = caching::not_modified(req, &etag) {
+            return response;
+        }
 
-    #[display(fmt = "bad request")]
-    BadClientData,
+        let mut response = HttpResponse::Ok();
+        response
+            .insert_header((header::VARY, "Accept"))
+            .insert_header((header::ETAG, etag))
+            .insert_header(("X-Total-Count", self.total.to_string()));
+        if let Some(page) = self.page {
+            let base_url = self.routing.url(req.path());
+            let link = pagination::link_header(req, &base_url, page, self.total);
+            response.insert_header((header::LINK, link));
+        }
 
-    #[display(fmt = "timeout")]
-    Timeout,
+        let routing = self.routing;
+        let highlighter = self.highlighter;
+        match format {
+            Format::Json => {
+                let todos = self.todos.enumerate().map(move |(index, todo)| {
+                    let todo = list_item(&routing, highlighter.as_ref(), todo?)?;
+                    let mut chunk = if index == 0 { Vec::new() } else { vec![b','] };
+                    serde_json::to_writer(&mut chunk, &todo).map_err(|_| Error::InternalError)?;
+                    Ok::<_, Error>(Bytes::from(chunk))
+                });
+                let body = stream::once(future::ok(Bytes::from_static(b"[")))
+                    .chain(todos)
+                    .chain(stream::once(future::ok(Bytes::from_static(b"]"))));
 
-    #[display(fmt = "not found")]
-    NotFound,
-}
+                response.content_type("application/json").streaming(body)
+            }
+            Format::MessagePack => {
+                // MessagePack arrays start with their length, so the list is encoded as a whole
+                let todos = self.todos;
+                let body = async move {
+                    let todos = todos
+                        .map(|todo| list_item(&routing, highlighter.as_ref(), todo?))
+                        .try_collect::<Vec<_>>()
+                        .await?;
+                    Ok::<_, Error>(Bytes::from(negotiation::to_message_pack(&todos)?))
+                };
 
-impl error::ResponseError for Error {
-    fn error_response(&self) -> HttpResponse {
-        HttpResponseBuilder::new(self.status_code())
-            .set_header(header::CONTENT_TYPE, "text/html; charset=utf-8")
-            .body(self.to_string())
-    }
+                response
+                    .content_type(negotiation::MESSAGE_PACK)
+                    .streaming(stream::once(body.boxed_local()))
+            }
+            Format::Protobuf => {
+                // an encoded list is just its encoded items one after another, so the todos
+                // can be streamed as lists of one
+                let todos = self.todos.map(move |todo| {
+                    let todo = todo?;
+                    let highlight = highlighter
+                        .as_ref()
+                        .map(|highlighter| highlighter.highlight(&todo.title).snippet);
+                    let mut todo = proto::Todo::from(&routing.present(todo));
+                    todo.highlight = highlight;
+                    let list = proto::TodoList { todos: vec![todo] };
+                    Ok::<_, Error>(Bytes::from(list.encode_to_vec()))
+                });
+
+                response
+                    .content_type(negotiation::PROTOBUF)
+                    .streaming(todos)
+            }
+            Format::PlainText => {
+                let lines = self.todos.map(|todo| {
+                    let todo = todo?;
+                    let mark = if todo.completed { "x" } else { " " };
+                    Ok::<_, Error>(Bytes::from(format!("[{}] {}\n", mark, todo.title)))
+                });
 
-    fn status_code(&self) -> StatusCode {
-        match *self {
-            Error::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
-            Error::BadClientData => StatusCode::BAD_REQUEST,
-            Error::Timeout => StatusCode::GATEWAY_TIMEOUT,
-            Error::NotFound => StatusCode::NOT_FOUND,
+                response
+                    .content_type("text/plain; charset=utf-8")
+                    .streaming(lines)
+            }
         }
     }
 }
 
-impl From<sqlx::Error> for Error {
-    fn from(error: sqlx::Error) -> Self {
-        match error {
-            sqlx::Error::RowNotFound => Error::NotFound,
-            _ => Error::InternalError
+impl Responder for TodoPresenter {
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        // every change bumps the version, so it identifies the representation well enough
+        let etag = match &self.public_id {
+            Some(public_id) => format!("{}-{}", public_id, self.todo.version),
+            None => format!("{}-{}", self.todo.id, self.todo.version),
+        };
+        if negotiation::preferred_format(req) == Format::Protobuf {
+            return caching::protobuf_with_etag(req, &etag, &proto::Todo::from(&self));
         }
+        caching::respond_with_etag(req, &etag, &self)
     }
 }
 
-impl Responder for TodosList {
-    fn respond_to(self, _: &HttpRequest) -> HttpResponse {
-        let routing = self.routing.clone();
-        let result = self.todos.into_iter()
-        .map(|todo| {
-            let url = routing.todo_url(todo.id);
-            TodoPresenter { todo, url }
-        })
-        .collect::<Vec<TodoPresenter>>();
-        HttpResponse::Ok().json(result)
+/// Renders a streamed body as a whole, so that the response gets a `Content-Length`. Clients
+/// send `HEAD` for that length, which isn't known while streaming.
+async fn with_content_length(response: HttpResponse) -> HttpResponse {
+    let (response, mut body) = response.into_parts();
+    let mut bytes = web::BytesMut::new();
+    while let Some(chunk) = future::poll_fn(|cx| Pin::new(&mut body).poll_next(cx)).await {
+        match chunk {
+            Ok(chunk) => bytes.extend_from_slice(&chunk),
+            Err(e) => return HttpResponse::from_error(e),
+        }
     }
+    response.set_body(AnyBody::Bytes(bytes.freeze()))
 }
 
-impl Responder for TodoPresenter {
-    fn respond_to(self, _: &HttpRequest) -> HttpResponse {
-        HttpResponse::Ok().json(self)
+#[route("/todos", method = "GET", method = "HEAD")]
+async fn todos_list_handler(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    filter: web::Query<TodosFilter>,
+    routing: RoutingService,
+) -> Result<HttpResponse, Error> {
+    let TodosFilter {
+        completed_after,
+        completed_before,
+        starred,
+        custom_field,
+        page,
+        per_page,
+    } = filter.into_inner();
+    let page = Page::from_params(page, per_page)?;
+    let (field_key, field_value) = match custom_field.as_deref() {
+        Some(filter) => {
+            let (key, value) = custom_fields::parse_filter(filter)?;
+            (Some(key), Some(value))
+        }
+        None => (None, None),
+    };
+
+    // every change either writes a revision or bumps a version, which makes for an ETag that
+    // doesn't require reading the whole list
+    let summary = retry::retry(|| {
+        sqlx::query!(r#"SELECT (SELECT COALESCE(MAX(id), 0) FROM todo_revisions) AS "revision!", (SELECT COALESCE(SUM(version), 0)::bigint FROM todos) AS "versions!", (SELECT COUNT(*) FROM todos WHERE ($1::timestamptz IS NULL OR completed_at >= $1) AND ($2::timestamptz IS NULL OR completed_at < $2) AND ($3::boolean IS NULL OR starred = $3) AND ($4::text IS NULL OR custom_fields ->> $4 = $5)) AS "total!""#, completed_after, completed_before, starred, field_key, field_value)
+            .fetch_one(pool.get_ref())
+    })
+    .await?;
+    let etag = format!("{}-{}", summary.revision, summary.versions);
+
+    let pool = pool.get_ref().clone();
+    let limit = page.map(|page| page.limit());
+    let offset = page.map(|page| page.offset()).unwrap_or(0);
+    let todos = Box::pin(async_stream::stream! {
+        let mut rows = sqlx::query_as!(Todo, r#"SELECT * FROM todos WHERE ($1::timestamptz IS NULL OR completed_at >= $1) AND ($2::timestamptz IS NULL OR completed_at < $2) AND ($3::boolean IS NULL OR starred = $3) AND ($4::text IS NULL OR custom_fields ->> $4 = $5) ORDER BY starred DESC, id LIMIT $6 OFFSET $7"#, completed_after, completed_before, starred, field_key, field_value, limit, offset)
+            .fetch(&pool);
+        while let Some(todo) = rows.next().await {
+            yield todo.and_then(crypto::decrypt);
+        }
+    });
+
+    let response = TodosList {
+        routing,
+        highlighter: None,
+        etag,
+        total: summary.total,
+        page,
+        todos,
     }
+    .respond_to(&req);
+    if req.method() == Method::HEAD {
+        return Ok(with_content_length(response).await);
+    }
+    Ok(response)
 }
 
-#[get("/todos")]
-async fn todos_list_handler(
+#[get("/todos/stats")]
+async fn todos_stats_handler(
+    req: HttpRequest,
     pool: web::Data<PgPool>,
-    routing: web::Data<RoutingService>,
-) -> Result<TodosList, Error> {
-    let todos = sqlx::query_as!(Todo, r#"SELECT * FROM todos ORDER BY id"#)
-        .fetch_all(pool.get_ref())
-        .await?;
+) -> Result<HttpResponse, Error> {
+    let counts = retry::retry(|| {
+        sqlx::query!(r#"SELECT COUNT(*) AS "total!", COUNT(*) FILTER (WHERE completed) AS "completed!" FROM todos"#)
+            .fetch_one(pool.get_ref())
+    })
+    .await?;
 
-    let routing = routing.get_ref().clone();
-    Ok(TodosList { routing, todos })
+    let stats = TodoStats {
+        total: counts.total,
+        completed: counts.completed,
+        active: counts.total - counts.completed,
+    };
+    Ok(negotiation::respond(&req, HttpResponse::Ok(), &stats))
 }
 
-#[get("/todos/{id:\\d+}")]
+#[route(
+    "/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}",
+    method = "GET",
+    method = "HEAD"
+)]
 async fn todos_show_handler(
-    id: web::Path<i64>,
+    todo: web::Path<TodoRef>,
     pool: web::Data<PgPool>,
-    routing: web::Data<RoutingService>,
+    routing: RoutingService,
 ) -> Result<TodoPresenter, Error> {
-    let todo = sqlx::query_as!(Todo, r#"SELECT * FROM todos WHERE id = $1"#, *id)
-        .fetch_one(pool.get_ref())
-        .await?;
+    let id = ids::resolve(pool.get_ref(), *todo).await?;
+    let todo = retry::retry(|| {
+        sqlx::query_as!(Todo, r#"SELECT * FROM todos WHERE id = $1"#, id).fetch_one(pool.get_ref())
+    })
+    .await
+    .and_then(crypto::decrypt)?;
 
-    let url = routing.todo_url(*id);
-    Ok(TodoPresenter { todo, url })
+    Ok(routing.present(todo))
 }
 
 #[post("/todos")]
 async fn create_todo_handler(
-    pool: web::Data<PgPool>,
-    todo: web::Json<NewTodo>,
-    routing: web::Data<RoutingService>,
+    req: HttpRequest,
+    tx: Tx,
+    todo: Body<NewTodo>,
+    routing: RoutingService,
+    slack: web::Data<SlackNotifier>,
 ) -> Result<TodoPresenter, Error> {
-    let title = &todo.title;
-    let order = todo.order.unwrap_or(0);
-    let todo = sqlx::query_as!(Todo, r#"INSERT INTO todos (title, "order") VALUES($1, $2) RETURNING id, title, completed, "order""#, title, order)
-        .fetch_one(pool.get_ref())
-        .await?;
+    todo.validate()?;
+    let due_at = match &todo.due {
+        Some(due) => parse_due_field(&req, due)?,
+        None => todo.due_at,
+    };
+
+    let title = normalize_title(&todo.title);
+    let color = todo.color.as_deref().map(normalize_color);
+    let mut tx = tx.lock().await?;
+    custom_fields::validate_values(&mut tx, &todo.custom_fields).await?;
+    let values = custom_fields::merge(&empty_object(), &todo.custom_fields);
+    let location = todo.location.as_ref();
+    // Without an explicit order new todos are appended to the end of the list
+    let order = match todo.order {
+        Some(order) => {
+            make_room_for_order(&mut tx, order, None).await?;
+            order
+        }
+        None => next_order(&mut tx).await?,
+    };
+    let todo = sqlx::query_as!(Todo, r#"INSERT INTO todos (title, "order", color, due_at, custom_fields, estimate_minutes, latitude, longitude, place_name, uuid) VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, COALESCE($10, gen_ulid())) RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, crypto::encrypt_title(&title), order, color, due_at, values, todo.estimate_minutes, location.map(|location| location.latitude), location.map(|location| location.longitude), location.and_then(Location::place_name), todo.uuid)
+        .fetch_one(&mut *tx)
+        .await
+        .and_then(crypto::decrypt)?;
+    history::record(&mut tx, Action::Create, None, Some(&todo)).await?;
+
+    let todo = routing.present(todo);
+    slack.todo_created(&mut tx, &todo.todo, &todo.url).await?;
+    Ok(todo)
+}
+
+#[post("/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}/duplicate")]
+async fn duplicate_todo_handler(
+    todo: web::Path<TodoRef>,
+    tx: Tx,
+    routing: RoutingService,
+    slack: web::Data<SlackNotifier>,
+) -> Result<TodoPresenter, Error> {
+    let mut tx = tx.lock().await?;
+    let id = ids::resolve(&mut *tx, *todo).await?;
+    let original = sqlx::query_as!(Todo, r#"SELECT * FROM todos WHERE id = $1"#, id)
+        .fetch_one(&mut *tx)
+        .await
+        .and_then(crypto::decrypt)?;
+
+    // the copy starts over: it isn't completed and has no time tracked yet
+    let title = copy_title(&original.title);
+    let status = original.status().with_completed(false);
+    let order = next_order(&mut tx).await?;
+    let todo = sqlx::query_as!(Todo, r#"INSERT INTO todos (title, "order", starred, color, due_at, status, custom_fields, estimate_minutes, latitude, longitude, place_name) VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, crypto::encrypt_title(&title), order, original.starred, original.color, original.due_at, status.as_str(), original.custom_fields, original.estimate_minutes, original.latitude, original.longitude, original.place_name)
+        .fetch_one(&mut *tx)
+        .await
+        .and_then(crypto::decrypt)?;
+    history::record(&mut tx, Action::Create, None, Some(&todo)).await?;
+
+    let todo = routing.present(todo);
+    slack.todo_created(&mut tx, &todo.todo, &todo.url).await?;
+    Ok(todo)
+}
+
+/// Parses the `due` field of a request in the client's timezone.
+fn parse_due_field(req: &HttpRequest, due: &str) -> Result<Option<DateTime<Utc>>, Error> {
+    let tz = due::request_timezone(req)?;
+    let mut errors = ValidationErrors::default();
+    let due_at = due::parse_due(&mut errors, due, &tz);
+    errors.into_result()?;
+    Ok(due_at)
+}
+
+/// Appends the copy suffix, shortening the original title if needed to stay within the limit.
+fn copy_title(title: &str) -> String {
+    let max_length = MAX_TITLE_LENGTH - COPY_SUFFIX.chars().count();
+    let title: String = title.chars().take(max_length).collect();
+    format!("{}{}", title, COPY_SUFFIX)
+}
 
-    let url = routing.todo_url(todo.id);
-    Ok(TodoPresenter { todo, url })
+/// What changing a todo's status takes besides the database: the allowed transitions, the rules
+/// for blocked todos and the notifications about completed ones.
+struct TodoServices {
+    workflow: Workflow,
+    dependencies: DependencySettings,
+    slack: SlackNotifier,
 }
 
-#[patch("/todos/{id:\\d+}")]
+#[patch("/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}")]
 async fn patch_todo_handler(
-    id: web::Path<i64>,
-    pool: web::Data<PgPool>,
-    update_todo: web::Json<UpdateTodo>,
-    routing: web::Data<RoutingService>,
+    req: HttpRequest,
+    todo: web::Path<TodoRef>,
+    tx: Tx,
+    update_todo: Body<UpdateTodo>,
+    routing: RoutingService,
+    services: web::Data<TodoServices>,
 ) -> Result<TodoPresenter, Error> {
-    let mut todo = sqlx::query_as!(Todo, r#"SELECT * FROM todos WHERE id = $1"#, *id)
-        .fetch_one(pool.get_ref())
-        .await?;
+    update_todo.validate()?;
+
+    let mut tx = tx.lock().await?;
+    let id = ids::resolve(&mut *tx, *todo).await?;
+    let mut todo = sqlx::query_as!(Todo, r#"SELECT * FROM todos WHERE id = $1"#, id)
+        .fetch_one(&mut *tx)
+        .await
+        .and_then(crypto::decrypt)?;
+
+    let expected_version = update_todo.version.unwrap_or(todo.version);
+    if expected_version != todo.version {
+        return Err(stale_version_error());
+    }
+    let before = todo.clone();
 
     if let Some(title) = &update_todo.title {
-        todo.title = title.clone();
+        todo.title = normalize_title(title);
     }
+    let mut status = todo.status();
     if let Some(completed) = update_todo.completed {
-        todo.completed = completed;
+        status = status.with_completed(completed);
+    }
+    if let Some(new_status) = update_todo.status {
+        status = new_status;
+    }
+    let mut errors = ValidationErrors::default();
+    services
+        .workflow
+        .validate(&mut errors, todo.status(), status);
+    errors.into_result()?;
+
+    let completed = status == Status::Done;
+    if completed && !todo.completed && services.dependencies.enforce {
+        dependencies::ensure_unblocked(&mut tx, todo.id).await?;
+    }
+    if completed != todo.completed {
+        todo.completed_at = if completed { Some(Utc::now()) } else { None };
     }
+    todo.completed = completed;
+    todo.status = status.as_str().to_owned();
     if let Some(order) = update_todo.order {
+        if order != todo.order {
+            make_room_for_order(&mut tx, order, Some(todo.id)).await?;
+        }
         todo.order = order;
     }
-    let todo = sqlx::query_as!(Todo, r#"UPDATE todos SET title = $1, completed = $2, "order" = $3 WHERE id = $4 RETURNING id, title, completed, "order""#, todo.title, todo.completed, todo.order, todo.id)
-        .fetch_one(pool.get_ref())
+    if let Some(starred) = update_todo.starred {
+        todo.starred = starred;
+    }
+    if let Some(color) = &update_todo.color {
+        todo.color = color.as_deref().map(normalize_color);
+    }
+    if let Some(due_at) = update_todo.due_at {
+        todo.due_at = due_at;
+    }
+    if let Some(due) = &update_todo.due {
+        todo.due_at = parse_due_field(&req, due)?;
+    }
+    if let Some(estimate_minutes) = update_todo.estimate_minutes {
+        todo.estimate_minutes = estimate_minutes;
+    }
+    if let Some(location) = &update_todo.location {
+        todo.latitude = location.as_ref().map(|location| location.latitude);
+        todo.longitude = location.as_ref().map(|location| location.longitude);
+        todo.place_name = location.as_ref().and_then(Location::place_name);
+    }
+    if let Some(values) = &update_todo.custom_fields {
+        custom_fields::validate_values(&mut tx, values).await?;
+        todo.custom_fields = custom_fields::merge(&todo.custom_fields, values);
+    }
+    // The version check guards against updates made between the SELECT above and this UPDATE
+    let todo = sqlx::query_as!(Todo, r#"UPDATE todos SET title = $1, completed = $2, "order" = $3, completed_at = $4, starred = $5, color = $6, due_at = $7, status = $8, custom_fields = $9, estimate_minutes = $10, latitude = $11, longitude = $12, place_name = $13, version = version + 1 WHERE id = $14 AND version = $15 RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, crypto::encrypt_title(&todo.title), todo.completed, todo.order, todo.completed_at, todo.starred, todo.color, todo.due_at, todo.status, todo.custom_fields, todo.estimate_minutes, todo.latitude, todo.longitude, todo.place_name, todo.id, expected_version)
+        .fetch_optional(&mut *tx)
+        .await?
+        .map(crypto::decrypt)
+        .transpose()?
+        .ok_or_else(stale_version_error)?;
+    history::record(&mut tx, Action::Update, Some(&before), Some(&todo)).await?;
+
+    let todo = routing.present(todo);
+    if todo.todo.completed && !before.completed {
+        services
+            .slack
+            .todo_completed(&mut tx, &todo.todo, &todo.url)
+            .await?;
+    }
+    Ok(todo)
+}
+
+/// Creates the todo with the UUID picked by the client, or replaces the fields `NewTodo` covers
+/// when it already exists, so that clients can safely retry writes they don't know the outcome
+/// of. Responds with 201 when the todo was created and 200 when it was updated.
+#[put("/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}")]
+async fn upsert_todo_handler(
+    req: HttpRequest,
+    todo_ref: web::Path<TodoRef>,
+    tx: Tx,
+    todo: Body<NewTodo>,
+    routing: RoutingService,
+    slack: web::Data<SlackNotifier>,
+) -> Result<HttpResponse, Error> {
+    let mut errors = ValidationErrors::default();
+    let uuid = match *todo_ref {
+        TodoRef::Uuid(uuid) => Some(uuid),
+        TodoRef::Id(_) => {
+            let reason = "todos can only be put by their UUID".to_owned();
+            errors.add("uuid", "invalid", &[("reason", reason)]);
+            None
+        }
+    };
+    if todo.uuid.is_some() && todo.uuid != uuid {
+        let reason = "needs to match the UUID in the path".to_owned();
+        errors.add("uuid", "invalid", &[("reason", reason)]);
+    }
+    errors.into_result()?;
+    todo.validate()?;
+    let uuid = uuid.expect("a missing UUID is a validation error");
+    let due_at = match &todo.due {
+        Some(due) => parse_due_field(&req, due)?,
+        None => todo.due_at,
+    };
+
+    let title = normalize_title(&todo.title);
+    let color = todo.color.as_deref().map(normalize_color);
+    let location = todo.location.as_ref();
+    let mut tx = tx.lock().await?;
+    custom_fields::validate_values(&mut tx, &todo.custom_fields).await?;
+    let values = custom_fields::merge(&empty_object(), &todo.custom_fields);
+    let existing = sqlx::query_as!(
+        Todo,
+        r#"SELECT * FROM todos WHERE uuid = $1 FOR UPDATE"#,
+        uuid
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .map(crypto::decrypt)
+    .transpose()?;
+
+    let (todo, created) = match existing {
+        None => {
+            let order = match todo.order {
+                Some(order) => {
+                    make_room_for_order(&mut tx, order, None).await?;
+                    order
+                }
+                None => next_order(&mut tx).await?,
+            };
+            // a concurrent request creating the same todo makes this one conflict, retrying it
+            // then updates the todo that request created
+            let todo = sqlx::query_as!(Todo, r#"INSERT INTO todos (title, "order", color, due_at, custom_fields, estimate_minutes, latitude, longitude, place_name, uuid) VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) ON CONFLICT (uuid) DO NOTHING RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, crypto::encrypt_title(&title), order, color, due_at, values, todo.estimate_minutes, location.map(|location| location.latitude), location.map(|location| location.longitude), location.and_then(Location::place_name), uuid)
+                .fetch_optional(&mut *tx)
+                .await?
+                .map(crypto::decrypt)
+                .transpose()?
+                .ok_or_else(stale_version_error)?;
+            history::record(&mut tx, Action::Create, None, Some(&todo)).await?;
+            (todo, true)
+        }
+        Some(before) => {
+            let mut updated = before.clone();
+            updated.title = title;
+            updated.color = color;
+            updated.due_at = due_at;
+            updated.estimate_minutes = todo.estimate_minutes;
+            updated.latitude = location.map(|location| location.latitude);
+            updated.longitude = location.map(|location| location.longitude);
+            updated.place_name = location.and_then(Location::place_name);
+            updated.custom_fields = values;
+            if let Some(order) = todo.order {
+                if order != updated.order {
+                    make_room_for_order(&mut tx, order, Some(updated.id)).await?;
+                }
+                updated.order = order;
+            }
+
+            // a retried request changes nothing, which shouldn't bump the version either
+            let unchanged =
+                serde_json::to_value(&updated).ok() == serde_json::to_value(&before).ok();
+            if unchanged {
+                (before, false)
+            } else {
+                let todo = sqlx::query_as!(Todo, r#"UPDATE todos SET title = $1, "order" = $2, color = $3, due_at = $4, custom_fields = $5, estimate_minutes = $6, latitude = $7, longitude = $8, place_name = $9, version = version + 1 WHERE id = $10 RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, crypto::encrypt_title(&updated.title), updated.order, updated.color, updated.due_at, updated.custom_fields, updated.estimate_minutes, updated.latitude, updated.longitude, updated.place_name, updated.id)
+                    .fetch_one(&mut *tx)
+                    .await
+                    .and_then(crypto::decrypt)?;
+                history::record(&mut tx, Action::Update, Some(&before), Some(&todo)).await?;
+                (todo, false)
+            }
+        }
+    };
+
+    let todo = routing.present(todo);
+    if !created {
+        return Ok(todo.respond_to(&req));
+    }
+    slack.todo_created(&mut tx, &todo.todo, &todo.url).await?;
+    let location = todo.url.clone();
+    let mut response = todo.respond_to(&req);
+    *response.status_mut() = StatusCode::CREATED;
+    response.headers_mut().insert(
+        header::LOCATION,
+        header::HeaderValue::from_str(&location).map_err(|_| Error::InternalError)?,
+    );
+    Ok(response)
+}
+
+/// Orders are unique, so when `order` is already taken by another todo, it and every todo after
+/// it are shifted down by one, each with a revision so syncing clients see them move.
+async fn make_room_for_order(
+    tx: &mut Transaction<'_, Postgres>,
+    order: f64,
+    id: Option<i64>,
+) -> Result<(), sqlx::Error> {
+    lock_orders(tx).await?;
+    let taken = sqlx::query_scalar!(r#"SELECT EXISTS(SELECT 1 FROM todos WHERE "order" = $1 AND id IS DISTINCT FROM $2) AS "taken!""#, order, id)
+        .fetch_one(&mut *tx)
         .await?;
 
-    let url = routing.todo_url(todo.id);
-    Ok(TodoPresenter { todo, url })
+    if taken {
+        let before = sqlx::query_as!(
+            Todo,
+            r#"SELECT * FROM todos WHERE "order" >= $1 AND id IS DISTINCT FROM $2 FOR UPDATE"#,
+            order,
+            id
+        )
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(crypto::decrypt)
+        .collect::<Result<Vec<_>, _>>()?;
+        let after = sqlx::query_as!(Todo, r#"UPDATE todos SET "order" = "order" + 1, version = version + 1 WHERE "order" >= $1 AND id IS DISTINCT FROM $2 RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, order, id)
+            .fetch_all(&mut *tx)
+            .await?
+            .into_iter()
+            .map(crypto::decrypt)
+            .collect::<Result<Vec<_>, _>>()?;
+        history::record_updates(tx, before, after).await?;
+    }
+
+    Ok(())
 }
 
-#[delete("/todos")]
-async fn delete_todos_handler(pool: web::Data<PgPool>) -> impl Responder {
-    let result = sqlx::query!(r#"DELETE FROM todos"#)
-        .execute(pool.get_ref())
-        .await;
+/// The order after the last todo, for appending one to the list.
+async fn next_order(tx: &mut Transaction<'_, Postgres>) -> Result<f64, sqlx::Error> {
+    lock_orders(tx).await?;
+    sqlx::query_scalar!(r#"SELECT COALESCE(MAX("order"), 0) + 1 AS "order!" FROM todos"#)
+        .fetch_one(&mut *tx)
+        .await
+}
+
+/// Makes transactions placing todos take turns until they end. Two of them appending at the
+/// same time would otherwise pick the same order, and the second would only fail on the unique
+/// order when committing.
+async fn lock_orders(tx: &mut Transaction<'_, Postgres>) -> Result<(), sqlx::Error> {
+    sqlx::query!(r#"SELECT FROM pg_advisory_xact_lock($1)"#, ORDER_LOCK_KEY)
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
+
+/// An ETag for lists of todos. Every change either writes a revision or bumps a version, which
+/// makes for an ETag that doesn't require reading the whole list.
+async fn todos_etag(pool: &PgPool) -> Result<String, sqlx::Error> {
+    let summary = sqlx::query!(r#"SELECT (SELECT COALESCE(MAX(id), 0) FROM todo_revisions) AS "revision!", (SELECT COALESCE(SUM(version), 0)::bigint FROM todos) AS "versions!""#)
+        .fetch_one(pool)
+        .await?;
+    Ok(format!("{}-{}", summary.revision, summary.versions))
+}
 
-    match result {
-        Ok(todo) => HttpResponse::NoContent().finish(),
-        _ => HttpResponse::BadRequest().body("Error trying to delete a todo"),
+fn stale_version_error() -> Error {
+    Error::Conflict {
+        reason: "the todo was modified by another request, fetch it and try again".to_owned(),
     }
 }
 
-#[delete("/todos/{id:\\d+}")]
-async fn delete_todo_handler(
-    path: web::Path<i64>,
-    pool: web::Data<PgPool>,
+/// Deletes the todos matching the filters, all of them without any, responding with how many
+/// were deleted.
+#[delete("/todos")]
+async fn delete_todos_handler(
+    req: HttpRequest,
+    tx: Tx,
+    filter: web::Query<DeleteFilter>,
 ) -> Result<HttpResponse, Error> {
-    let id: i64 = path.into_inner();
-    let result = sqlx::query!(r#"DELETE FROM todos WHERE id = $1"#, id)
-        .execute(pool.get_ref())
-        .await?;
+    let DeleteFilter {
+        completed,
+        completed_before,
+        custom_field,
+    } = filter.into_inner();
+    let (field_key, field_value) = match custom_field.as_deref() {
+        Some(filter) => {
+            let (key, value) = custom_fields::parse_filter(filter)?;
+            (Some(key), Some(value))
+        }
+        None => (None, None),
+    };
+
+    let mut tx = tx.lock().await?;
+    let todos = sqlx::query_as!(Todo, r#"DELETE FROM todos WHERE ($1::boolean IS NULL OR completed = $1) AND ($2::timestamptz IS NULL OR completed_at < $2) AND ($3::text IS NULL OR custom_fields ->> $3 = $4) RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, completed, completed_before, field_key, field_value)
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(crypto::decrypt)
+        .collect::<Result<Vec<_>, _>>()?;
+    for todo in &todos {
+        history::record(&mut tx, Action::Delete, Some(todo), None).await?;
+    }
+
+    let deleted = DeletedTodos {
+        deleted: todos.len(),
+    };
+    Ok(negotiation::respond(&req, HttpResponse::Ok(), &deleted))
+}
+
+#[delete("/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}")]
+async fn delete_todo_handler(path: web::Path<TodoRef>, tx: Tx) -> Result<HttpResponse, Error> {
+    let mut tx = tx.lock().await?;
+    let id = ids::resolve(&mut *tx, path.into_inner()).await?;
+    let todo = sqlx::query_as!(Todo, r#"DELETE FROM todos WHERE id = $1 RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .map(crypto::decrypt)
+        .transpose()?
+        .ok_or(Error::NotFound)?;
+    history::record(&mut tx, Action::Delete, Some(&todo), None).await?;
 
     Ok(HttpResponse::NoContent().finish())
 }
@@ -208,69 +993,702 @@ struct RoutingService {
     host: String,
     port: u16,
     scheme: String,
+    /// Prefix of every path, for when the API is mounted under a path like `/api` by a proxy.
+    base_path: String,
+    id_scheme: IdScheme,
+    /// Build URLs from the `Forwarded`/`X-Forwarded-*` headers set by a reverse proxy.
+    trust_proxy: bool,
 }
 
 impl RoutingService {
-    fn todo_url(&self, id: i64) -> String {
-        // For production usage I would check if port is equal to 80 and don't insert port in such
-        // case
-        format!("{}://{}:{}/todos/{}", self.scheme, self.host, self.port, id)
+    /// The configured routing, adjusted to the scheme and host the client used to reach the
+    /// proxy in front of the server.
+    fn for_request(&self, req: &HttpRequest) -> RoutingService {
+        let mut routing = self.clone();
+        if !self.trust_proxy {
+            return routing;
+        }
+
+        let forwarded = Forwarded::from_request(req);
+        if let Some(scheme) = forwarded.scheme {
+            routing.scheme = scheme;
+        }
+        match (forwarded.host, forwarded.port) {
+            (Some(host), port) => {
+                routing.host = host;
+                let default_port = if routing.scheme == "https" { 443 } else { 80 };
+                routing.port = port.unwrap_or(default_port);
+            }
+            (None, Some(port)) => routing.port = port,
+            (None, None) => {}
+        }
+        routing
+    }
+
+    fn todo_url(&self, todo: &Todo) -> String {
+        match self.id_scheme.public_id(todo) {
+            Some(public_id) => self.url(&format!("/todos/{}", public_id)),
+            None => self.url(&format!("/todos/{}", todo.id)),
+        }
+    }
+
+    fn present(&self, todo: Todo) -> TodoPresenter {
+        TodoPresenter {
+            url: self.todo_url(&todo),
+            public_id: self.id_scheme.public_id(&todo),
+            todo,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        let default_port = match self.scheme.as_str() {
+            "http" => Some(80),
+            "https" => Some(443),
+            _ => None,
+        };
+        if default_port == Some(self.port) {
+            format!("{}://{}{}{}", self.scheme, self.host, self.base_path, path)
+        } else {
+            format!(
+                "{}://{}:{}{}{}",
+                self.scheme, self.host, self.port, self.base_path, path
+            )
+        }
+    }
+
+    /// Takes the scheme, host, port and base path from an absolute URL like
+    /// `https://example.com/api`.
+    fn with_base_url(mut self, url: &reqwest::Url) -> RoutingService {
+        self.scheme = url.scheme().to_owned();
+        self.host = url.host_str().unwrap_or_default().to_owned();
+        self.port = url.port_or_known_default().unwrap_or(80);
+        self.base_path = normalize_base_path(url.path());
+        self
+    }
+}
+
+/// Turns `api/`, `/api` and `/api/` into `/api`, and `/` into an empty prefix.
+fn normalize_base_path(path: &str) -> String {
+    let path = path.trim().trim_matches('/');
+    if path.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", path)
+    }
+}
+
+/// Handlers take the routing as an extractor, so that URLs are built for the current request.
+impl FromRequest for RoutingService {
+    type Config = ();
+    type Error = Error;
+    type Future = future::Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        match req.app_data::<web::Data<RoutingService>>() {
+            Some(routing) => future::ok(routing.for_request(req)),
+            None => {
+                error!("RoutingService isn't registered as app data");
+                future::err(Error::InternalError)
+            }
+        }
     }
 }
 
+/// Every endpoint of the API.
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(metrics::metrics_handler)
+        .service(version::version_handler);
+    admin::configure(cfg);
+    cfg.service(todos_list_handler)
+        .service(todos_stats_handler)
+        .service(export::export_xlsx_handler)
+        .service(search::search_todos_handler)
+        .service(due::today_todos_handler)
+        .service(due::overdue_todos_handler)
+        .service(due::calendar_handler)
+        .service(due::workload_handler)
+        .service(location::nearby_todos_handler)
+        .service(create_todo_handler)
+        .service(delete_todo_handler)
+        .service(delete_todos_handler)
+        .service(todos_show_handler)
+        .service(patch_todo_handler)
+        .service(upsert_todo_handler)
+        .service(duplicate_todo_handler)
+        .service(history::todo_history_handler)
+        .service(history::revert_todo_handler)
+        .service(history::undo_handler)
+        .service(import::import_todoist_handler)
+        .service(import::import_trello_handler)
+        .service(telegram::telegram_webhook_handler)
+        .service(inbound_email::mailgun_inbound_handler);
+    dav::configure(cfg);
+    cfg.service(sync::todos_changes_handler)
+        .service(sync::todos_poll_handler)
+        .service(sync::sync_handler)
+        .service(filters::filters_list_handler)
+        .service(filters::create_filter_handler)
+        .service(filters::show_filter_handler)
+        .service(filters::delete_filter_handler)
+        .service(filters::filter_todos_handler)
+        .service(custom_fields::custom_fields_list_handler)
+        .service(custom_fields::create_custom_field_handler)
+        .service(custom_fields::delete_custom_field_handler)
+        .service(dependencies::dependencies_list_handler)
+        .service(dependencies::create_dependency_handler)
+        .service(dependencies::delete_dependency_handler)
+        .service(time_tracking::start_timer_handler)
+        .service(time_tracking::stop_timer_handler)
+        .service(time_tracking::report_handler);
+}
+
 #[actix_web::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    let mut args = env::args().skip(1).peekable();
+    // variables already set in the environment take precedence over the ones in the file
+    if args.peek().map(String::as_str) == Some("--env-file") {
+        args.next();
+        let path = args.next().context("--env-file needs the path of a file")?;
+        dotenvy::from_path(&path).with_context(|| format!("Failed to load {}", path))?;
+    } else if let Err(e) = dotenvy::dotenv() {
+        if !e.not_found() {
+            return Err(e).context("Failed to load .env");
+        }
+    }
+
+    settings::init_logger();
+    match args.next().as_deref() {
+        None => {}
+        Some("bench") => return bench::run(args).await,
+        Some(command) => anyhow::bail!("unknown command {}, the only one is bench", command),
+    }
+    let _sentry = reporting::init(
+        env::var("SENTRY_DSN").ok(),
+        env::var("SENTRY_ENVIRONMENT").ok(),
+    );
 
     let mut listenfd = ListenFd::from_env();
 
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL is not set in .env file");
-    let host = env::var("HOST").unwrap_or("127.0.0.1".to_owned());
-    let port: u16 = env::var("PORT")
-        .unwrap_or("8080".to_owned())
-        .parse()
-        .expect("PORT needs to be in 0-65535 range");
-    let scheme = env::var("SCHEME").unwrap_or("http".to_owned());
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(errors) => {
+            eprint!("{}", errors);
+            process::exit(1);
+        }
+    };
+    let Config {
+        mut database,
+        database_timeout_ms,
+        slow_query_threshold,
+        host,
+        port,
+        scheme,
+        base_url,
+        base_path,
+        trust_proxy,
+        metrics_addr,
+        slack_webhook_url,
+        events,
+        mqtt: mqtt_settings,
+        telegram: telegram_settings,
+        inbound_email,
+        cache: cache_settings,
+        rebalance_interval,
+        breaker_threshold,
+        breaker_cool_down,
+        cleanup_completed_after_days,
+        retention,
+        dependencies: dependency_settings,
+        workflow,
+        maintenance,
+        admin: admin_settings,
+        id_scheme,
+        deprecations,
+        settings,
+    } = config;
+    let maintenance_mode = MaintenanceMode::new(maintenance);
+    let settings = Arc::new(settings);
+    settings::reload_on_signal(settings.clone());
+
+    // every statement is timed, the ones slower than the threshold are logged as warnings,
+    // without the values bound to them, which would put titles and other user data in the logs
+    database
+        .log_statements(LevelFilter::Debug)
+        .log_slow_statements(LevelFilter::Warn, slow_query_threshold);
 
+    // Postgres cancels statements running longer than the timeout and waiting for a free
+    // connection is bounded by it as well, both are reported as Error::Timeout
+    // one of the connections is held by the change feed for good
     let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&database_url)
+        .max_connections(6)
+        .connect_timeout(Duration::from_millis(database_timeout_ms))
+        .after_connect(move |conn| {
+            Box::pin(async move {
+                let statement = format!("SET statement_timeout = {}", database_timeout_ms);
+                conn.execute(statement.as_str()).await?;
+                Ok(())
+            })
+        })
+        .connect_with(database)
         .await
-        .unwrap();
+        .context("Failed to connect to the database")?;
+
+    sqlx::migrate!().run(&pool).await?;
+    let change_feed = web::Data::new(
+        ChangeFeed::listen(&pool)
+            .await
+            .context("Failed to listen for changes")?,
+    );
+    if let Some(settings) = mqtt_settings {
+        mqtt::spawn(settings, pool.clone(), &change_feed);
+    }
 
-    let routing_service = web::Data::new(RoutingService {
+    let metrics = web::Data::new(Metrics::default());
+    let slack = web::Data::new(SlackNotifier::new(slack_webhook_url));
+    let publisher = match &events {
+        Some(settings) => Some(Rc::new(Publisher::connect(settings).await?)),
+        None => None,
+    };
+    let mut routing_service = RoutingService {
         host: host.clone(),
         port,
         scheme: scheme.clone(),
+        base_path: String::new(),
+        id_scheme,
+        trust_proxy,
+    };
+    if let Some(base_url) = base_url {
+        routing_service = routing_service.with_base_url(&base_url);
+    }
+    if let Some(base_path) = base_path {
+        routing_service.base_path = normalize_base_path(&base_path);
+    }
+    let routing_service = web::Data::new(routing_service);
+    let mut scheduler = Scheduler::new();
+    jobs::register(
+        &mut scheduler,
+        &pool,
+        JobsConfig {
+            rebalance_every: rebalance_interval,
+            cleanup_completed_after_days,
+            retention,
+        },
+        &metrics,
+        &slack,
+        &routing_service,
+        publisher,
+    );
+    let jobs_metrics = web::Data::new(scheduler.metrics());
+    scheduler.start();
+    let breaker = web::Data::new(CircuitBreaker::new(
+        breaker_threshold,
+        breaker_cool_down,
+        metrics.get_ref().clone(),
+    ));
+
+    let todo_services = web::Data::new(TodoServices {
+        workflow,
+        dependencies: dependency_settings,
+        slack: slack.get_ref().clone(),
     });
+    let allowed_methods = web::Data::new(AllowedMethods::new());
+    let settings_data = web::Data::from(settings.clone());
+    let maintenance_mode = web::Data::new(maintenance_mode);
+    let admin_settings = web::Data::new(admin_settings);
+    let telegram_bot = web::Data::new(TelegramBot::new(telegram_settings));
+    let inbound_email = web::Data::new(InboundEmail(inbound_email));
+    let deprecations = web::Data::new(deprecations);
 
-    let mut server = HttpServer::new(move || {
+    let listeners = handoff::Listeners::take(
+        &mut listenfd,
+        &format!("{}:{}", host, port),
+        metrics_addr.as_deref(),
+    )?;
+    let fds = listeners.raw_fds();
+
+    let metrics_server = match listeners.metrics {
+        Some(listener) => {
+            let metrics = metrics.clone();
+            let jobs_metrics = jobs_metrics.clone();
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(metrics.clone())
+                    .app_data(jobs_metrics.clone())
+                    .service(metrics::metrics_handler)
+            })
+            .workers(1)
+            .listen(listener)?
+            .run();
+            Some(server)
+        }
+        None => None,
+    };
+
+    let server = HttpServer::new(move || {
         let cors = Cors::default()
-            .allow_any_origin()
+            .allowed_origin_fn({
+                let settings = settings.clone();
+                move |origin, _| {
+                    let origin = origin.to_str().unwrap_or_default();
+                    settings.current().allows_origin(origin)
+                }
+            })
             .allow_any_header()
             .allow_any_method()
+            .expose_headers(vec![request_id::REQUEST_ID_HEADER, "deprecation", "sunset"])
             .max_age(3600);
         App::new()
             /* .wrap(Logger::default())
             .wrap(Logger::new("%a %{User-Agent}i")) */
             .app_data(web::Data::new(pool.clone()))
             .app_data(routing_service.clone())
+            .app_data(slack.clone())
+            .app_data(todo_services.clone())
+            .app_data(web::Data::new(retention))
+            .app_data(allowed_methods.clone())
+            .app_data(settings_data.clone())
+            .app_data(maintenance_mode.clone())
+            .app_data(admin_settings.clone())
+            .app_data(telegram_bot.clone())
+            .app_data(inbound_email.clone())
+            .app_data(metrics.clone())
+            .app_data(jobs_metrics.clone())
+            .app_data(change_feed.clone())
+            .app_data(web::JsonConfig::default().error_handler(error::json_error_handler))
+            .app_data(web::PathConfig::default().error_handler(error::path_error_handler))
+            .app_data(web::QueryConfig::default().error_handler(error::query_error_handler))
+            .wrap_fn({
+                let metrics = metrics.clone();
+                // a panicking handler would otherwise drop the connection without any response
+                move |req, srv| {
+                    let http_req = req.request().clone();
+                    let metrics = metrics.clone();
+                    AssertUnwindSafe(srv.call(req))
+                        .catch_unwind()
+                        .map(move |res| match res {
+                            Ok(res) => res,
+                            Err(_) => {
+                                error!(
+                                    "Handler panicked on {} {}",
+                                    http_req.method(),
+                                    http_req.path()
+                                );
+                                metrics.increment(
+                                    "http_panics_total",
+                                    "Requests whose handler panicked.",
+                                );
+                                let mut res =
+                                    ServiceResponse::from_err(Error::InternalError, http_req);
+                                res.response_mut()
+                                    .extensions_mut()
+                                    .insert(reporting::PanicReported);
+                                Ok(res)
+                            }
+                        })
+                }
+            })
+            // commits the transaction handlers wrote through, or rolls it back on failures
+            .wrap_fn(|req, srv| {
+                srv.call(req)
+                    .and_then(|res| transaction::finish(res).map(Ok))
+            })
+            .wrap_fn({
+                let breaker = breaker.clone();
+                move |req, srv| {
+                    // metrics stay available to show why requests are rejected
+                    let guarded = req.path() != "/metrics";
+                    if guarded {
+                        if let Err(e) = breaker.check() {
+                            return future::Either::Left(future::ok(req.error_response(e)));
+                        }
+                    }
+                    let breaker = breaker.clone();
+                    future::Either::Right(srv.call(req).map(move |res| {
+                        match (guarded, &res) {
+                            (true, Ok(res)) => breaker.record(res),
+                            (true, Err(_)) => breaker.record_failure(),
+                            (false, _) => {}
+                        }
+                        res
+                    }))
+                }
+            })
+            .wrap_fn({
+                let maintenance_mode = maintenance_mode.clone();
+                move |req, srv| match maintenance_mode.check(req.method(), req.path()) {
+                    Err(e) => future::Either::Left(future::ok(req.error_response(e))),
+                    Ok(()) => future::Either::Right(srv.call(req)),
+                }
+            })
+            .wrap_fn({
+                let settings = settings.clone();
+                move |req, srv| {
+                    let disabled = settings.current().feature_flags.disabled_for(req.request());
+                    match disabled {
+                        Some(feature) => {
+                            debug!(
+                                "Feature {} is disabled for {}",
+                                feature.as_str(),
+                                req.path()
+                            );
+                            future::Either::Left(future::ok(req.error_response(Error::NotFound)))
+                        }
+                        None => future::Either::Right(srv.call(req)),
+                    }
+                }
+            })
+            .wrap_fn(|req, srv| {
+                let instance = req.path().to_owned();
+                let locale = Locale::from_request(req.request());
+                srv.call(req)
+                    .map(move |res| res.map(move |res| error::with_instance(res, instance, locale)))
+            })
+            .wrap_fn({
+                let deprecations = deprecations.clone();
+                move |req, srv| {
+                    let deprecations = deprecations.clone();
+                    srv.call(req).map(move |res| {
+                        res.map(|mut res| {
+                            deprecations.mark(&mut res);
+                            res
+                        })
+                    })
+                }
+            })
+            .wrap_fn(move |req, srv| {
+                srv.call(req).map(move |res| {
+                    res.map(|mut res| {
+                        caching::set_cache_control(&mut res, cache_settings);
+                        res
+                    })
+                })
+            })
+            // handlers are timed as well, to find the one that's slow when none of its queries is
+            .wrap_fn(move |req, srv| {
+                let started = Instant::now();
+                srv.call(req).map(move |res| {
+                    if let Ok(res) = &res {
+                        let req = res.request();
+                        let route = req.match_pattern().unwrap_or_else(|| req.path().to_owned());
+                        let elapsed = started.elapsed();
+                        if elapsed >= slow_query_threshold {
+                            warn!("Slow handler for {} {}: {:?}", req.method(), route, elapsed);
+                        } else {
+                            debug!("Handler for {} {}: {:?}", req.method(), route, elapsed);
+                        }
+                    }
+                    res
+                })
+            })
             .wrap(cors)
-            .service(todos_list_handler)
-            .service(create_todo_handler)
-            .service(delete_todo_handler)
-            .service(delete_todos_handler)
-            .service(todos_show_handler)
-            .service(patch_todo_handler)
-    });
+            .wrap_fn(|req, srv| {
+                let id = request_id::assign(&req);
+                reporting::in_hub(|| {
+                    request_id::in_scope(id.clone(), || {
+                        srv.call(req).map(move |res| {
+                            res.map(|mut res| {
+                                request_id::finish(&mut res, &id);
+                                reporting::capture_server_error(&res, &id);
+                                res
+                            })
+                        })
+                    })
+                })
+            })
+            // several HTTP clients append a slash, `/todos/` is routed like `/todos`
+            .wrap(NormalizePath::new(TrailingSlash::Trim))
+            .configure(routes)
+            .default_service(web::route().to(allow::fallback_handler))
+    })
+    .listen(listeners.http)?
+    .run();
 
-    server = match listenfd.take_tcp_listener(0)? {
-        Some(listener) => server.listen(listener)?,
-        None => server.bind(format!("{}:{}", host, port))?,
-    };
+    let mut servers = vec![server.clone()];
+    servers.extend(metrics_server.clone());
+    handoff::upgrade_on_signal(fds, servers);
 
     info!("Starting server");
-    server.run().await?;
+    match metrics_server {
+        Some(metrics_server) => {
+            let (server, metrics_server) = future::join(server, metrics_server).await;
+            server?;
+            metrics_server?;
+        }
+        None => server.await?,
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::BodySize;
+    use actix_web::test;
+
+    /// The plan Postgres picks for a query with sequential scans ruled out, since the few todos
+    /// of a test database would otherwise always be scanned.
+    async fn plan(pool: &PgPool, query: &str) -> String {
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query("SET LOCAL enable_seqscan = off")
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        let lines: Vec<String> = sqlx::query_scalar(&format!("EXPLAIN {}", query))
+            .fetch_all(&mut tx)
+            .await
+            .unwrap();
+        lines.join("\n")
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs a database, see test_support"]
+    async fn dav_listings_use_the_order_index() {
+        let pool = test_support::pool().await;
+        let plan = plan(&pool, r#"SELECT * FROM todos ORDER BY "order", id"#).await;
+        assert!(plan.contains("todos_order_idx"), "{}", plan);
+    }
+
+    /// The query of `todos_list_handler`, with the filters left out or set.
+    fn list_query(completed_after: &str) -> String {
+        format!(
+            r#"SELECT * FROM todos WHERE ({0}::timestamptz IS NULL OR completed_at >= {0}) AND (NULL::timestamptz IS NULL OR completed_at < NULL) AND (NULL::boolean IS NULL OR starred = NULL) AND (NULL::text IS NULL OR custom_fields ->> NULL = NULL) ORDER BY starred DESC, id LIMIT 50 OFFSET 0"#,
+            completed_after
+        )
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs a database, see test_support"]
+    async fn lists_use_the_list_index() {
+        let pool = test_support::pool().await;
+        let plan = plan(&pool, &list_query("NULL")).await;
+        assert!(plan.contains("todos_list_idx"), "{}", plan);
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs a database, see test_support"]
+    async fn lists_filtered_by_completion_use_the_completed_at_index() {
+        let pool = test_support::pool().await;
+        let plan = plan(&pool, &list_query("'2024-01-01T00:00:00Z'")).await;
+        assert!(plan.contains("todos_completed_at_idx"), "{}", plan);
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs a database, see test_support"]
+    async fn completion_filters_use_the_completed_index() {
+        let pool = test_support::pool().await;
+        let plan = plan(&pool, "SELECT id FROM todos WHERE completed").await;
+        assert!(plan.contains("todos_completed_idx"), "{}", plan);
+    }
+
+    #[actix_rt::test]
+    async fn trailing_and_duplicate_slashes_are_trimmed_for_dav() {
+        let app = test::init_service(
+            App::new()
+                .wrap(NormalizePath::new(TrailingSlash::Trim))
+                .configure(routes),
+        )
+        .await;
+
+        for path in ["/dav", "/dav/", "//dav", "/dav//"] {
+            let req = test::TestRequest::default()
+                .method(Method::OPTIONS)
+                .uri(path)
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::NO_CONTENT, "{}", path);
+            assert!(res.headers().contains_key("DAV"), "{}", path);
+        }
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs a database, see test_support"]
+    async fn trailing_and_duplicate_slashes_are_trimmed_for_todos() {
+        let pool = test_support::pool().await;
+        let app = test::init_service(
+            App::new()
+                .configure(test_support::app_data(pool))
+                .wrap(NormalizePath::new(TrailingSlash::Trim))
+                .configure(routes),
+        )
+        .await;
+
+        for path in ["/todos", "/todos/", "//todos", "/todos//"] {
+            let req = test::TestRequest::get().uri(path).to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::OK, "{}", path);
+        }
+    }
+
+    fn assert_problem_not_found<B>(res: &ServiceResponse<B>) {
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/problem+json"
+        );
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs a database, see test_support"]
+    async fn changing_missing_todos_is_not_found() {
+        let pool = test_support::pool().await;
+        let app = test::init_service(
+            App::new()
+                .configure(test_support::app_data(pool))
+                .configure(routes),
+        )
+        .await;
+
+        let missing = [i64::MAX.to_string(), uuid::Uuid::new_v4().to_string()];
+        for id in &missing {
+            let path = format!("/todos/{}", id);
+            let req = test::TestRequest::delete().uri(&path).to_request();
+            assert_problem_not_found(&test::call_service(&app, req).await);
+
+            let req = test::TestRequest::patch()
+                .uri(&path)
+                .set_json(&serde_json::json!({ "title": "Missing" }))
+                .to_request();
+            assert_problem_not_found(&test::call_service(&app, req).await);
+        }
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs a database, see test_support"]
+    async fn head_responses_to_lists_have_the_length_of_the_list() {
+        let pool = test_support::pool().await;
+        let app = test::init_service(
+            App::new()
+                .configure(test_support::app_data(pool))
+                .configure(routes),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/todos").to_request();
+        let list = test::read_body(test::call_service(&app, req).await).await;
+        let req = test::TestRequest::default()
+            .method(Method::HEAD)
+            .uri("/todos")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.response().body().size(),
+            BodySize::Sized(list.len() as u64)
+        );
+    }
+
+    #[test]
+    fn copies_get_the_suffix() {
+        assert_eq!(copy_title("Buy milk"), "Buy milk (copy)");
+    }
+
+    #[test]
+    fn long_titles_are_shortened_to_fit_the_suffix() {
+        let title = "é".repeat(MAX_TITLE_LENGTH);
+        let copy = copy_title(&title);
+        assert_eq!(copy.chars().count(), MAX_TITLE_LENGTH);
+        assert!(copy.ends_with(COPY_SUFFIX));
+        assert!(copy.starts_with(&"é".repeat(MAX_TITLE_LENGTH - COPY_SUFFIX.len())));
+    }
+}
@@ -0,0 +1,42 @@
+use crate::error::Error;
+use actix_web::http::Method;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Read-only mode for migrations and failovers: while enabled, requests that could change
+/// data are rejected with 503 and reads keep working.
+#[derive(Debug, Default)]
+pub struct MaintenanceMode(AtomicBool);
+
+impl MaintenanceMode {
+    pub fn new(enabled: bool) -> Self {
+        MaintenanceMode(AtomicBool::new(enabled))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, enabled: bool) {
+        if self.0.swap(enabled, Ordering::Relaxed) != enabled {
+            warn!(
+                "Maintenance mode {}",
+                if enabled { "enabled" } else { "disabled" }
+            );
+        }
+    }
+
+    /// Admin endpoints stay available, otherwise maintenance mode couldn't be turned off.
+    pub fn check(&self, method: &Method, path: &str) -> Result<(), Error> {
+        // PROPFIND and REPORT are how CalDAV clients read
+        let read = method == Method::GET
+            || method == Method::HEAD
+            || method == Method::OPTIONS
+            || method.as_str() == "PROPFIND"
+            || method.as_str() == "REPORT";
+        if read || !self.is_enabled() || path.starts_with("/admin/") {
+            Ok(())
+        } else {
+            Err(Error::Maintenance)
+        }
+    }
+}
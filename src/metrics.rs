@@ -0,0 +1,107 @@
+use crate::scheduler::JobsMetrics;
+use actix_web::{get, web, HttpResponse};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Metric {
+    kind: Kind,
+    help: &'static str,
+    value: f64,
+}
+
+/// Counters and gauges reported by `GET /metrics` in the Prometheus text format, shared between
+/// whoever records them and the handler.
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<Mutex<BTreeMap<&'static str, Metric>>>);
+
+impl Metrics {
+    pub fn increment(&self, name: &'static str, help: &'static str) {
+        let mut metrics = self.0.lock().unwrap();
+        metrics
+            .entry(name)
+            .or_insert(Metric {
+                kind: Kind::Counter,
+                help,
+                value: 0.0,
+            })
+            .value += 1.0;
+    }
+
+    pub fn set_gauge(&self, name: &'static str, help: &'static str, value: f64) {
+        let mut metrics = self.0.lock().unwrap();
+        metrics.insert(
+            name,
+            Metric {
+                kind: Kind::Gauge,
+                help,
+                value,
+            },
+        );
+    }
+
+    fn render(&self, out: &mut String) {
+        let metrics = self.0.lock().unwrap();
+        for (name, metric) in metrics.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, metric.help);
+            let _ = writeln!(out, "# TYPE {} {}", name, metric.kind.as_str());
+            let _ = writeln!(out, "{} {}", name, metric.value);
+        }
+    }
+}
+
+/// Renders the background jobs' metrics, labelled by job.
+fn render_jobs(jobs: &JobsMetrics, out: &mut String) {
+    let jobs = jobs.snapshot();
+    if jobs.is_empty() {
+        return;
+    }
+
+    let _ = writeln!(out, "# HELP job_runs_total Runs of a background job.");
+    let _ = writeln!(out, "# TYPE job_runs_total counter");
+    for (name, job) in &jobs {
+        let _ = writeln!(out, "job_runs_total{{job=\"{}\"}} {}", name, job.runs);
+    }
+    let _ = writeln!(
+        out,
+        "# HELP job_failures_total Failed runs of a background job."
+    );
+    let _ = writeln!(out, "# TYPE job_failures_total counter");
+    for (name, job) in &jobs {
+        let _ = writeln!(
+            out,
+            "job_failures_total{{job=\"{}\"}} {}",
+            name, job.failures
+        );
+    }
+}
+
+#[get("/metrics")]
+pub async fn metrics_handler(
+    metrics: web::Data<Metrics>,
+    jobs: web::Data<JobsMetrics>,
+) -> HttpResponse {
+    let mut body = String::new();
+    metrics.render(&mut body);
+    render_jobs(&jobs, &mut body);
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
@@ -0,0 +1,208 @@
+use crate::error::Error;
+use crate::reporting;
+use actix_web::dev::Payload;
+use actix_web::http::header;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, HttpResponseBuilder};
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::ops::Deref;
+
+pub const MESSAGE_PACK: &str = "application/msgpack";
+pub const PROTOBUF: &str = "application/x-protobuf";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    MessagePack,
+    Protobuf,
+    PlainText,
+}
+
+impl Format {
+    /// Formats in the order of server preference, used to break ties between equal qualities.
+    const ALL: &'static [Format] = &[
+        Format::Json,
+        Format::MessagePack,
+        Format::Protobuf,
+        Format::PlainText,
+    ];
+
+    fn media_type(self) -> (&'static str, &'static str) {
+        match self {
+            Format::Json => ("application", "json"),
+            Format::MessagePack => ("application", "msgpack"),
+            Format::Protobuf => ("application", "x-protobuf"),
+            Format::PlainText => ("text", "plain"),
+        }
+    }
+
+    /// How well a single media range from an `Accept` header matches the format, more specific
+    /// ranges rank higher.
+    fn specificity(self, range: &str) -> Option<u8> {
+        let (kind, subtype) = self.media_type();
+        let mut parts = range.splitn(2, '/');
+        let range_kind = parts.next()?.trim();
+        let range_subtype = parts.next()?.trim();
+
+        match (range_kind, range_subtype) {
+            ("*", "*") => Some(0),
+            (k, "*") if k.eq_ignore_ascii_case(kind) => Some(1),
+            (k, s) if k.eq_ignore_ascii_case(kind) && s.eq_ignore_ascii_case(subtype) => Some(2),
+            _ => None,
+        }
+    }
+}
+
+/// Picks the response format based on the `Accept` header, defaulting to JSON.
+pub fn preferred_format(req: &HttpRequest) -> Format {
+    let accept = match req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+    {
+        Some(accept) => accept,
+        None => return Format::Json,
+    };
+
+    let ranges = accept
+        .split(',')
+        .map(|range| {
+            let mut params = range.split(';');
+            let media_range = params.next().unwrap_or("").trim();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (media_range, quality)
+        })
+        .collect::<Vec<(&str, f32)>>();
+
+    let quality = |format: Format| {
+        ranges
+            .iter()
+            .filter_map(|(range, quality)| {
+                format
+                    .specificity(range)
+                    .map(|specificity| (specificity, *quality))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, quality)| quality)
+            .unwrap_or(0.0)
+    };
+
+    let mut best = Format::Json;
+    let mut best_quality = 0.0;
+    for format in Format::ALL {
+        let quality = quality(*format);
+        if quality > best_quality {
+            best = *format;
+            best_quality = quality;
+        }
+    }
+    best
+}
+
+/// Encodes `body` as MessagePack. `#[serde(flatten)]` serializes maps without knowing their
+/// length up front, which MessagePack can't encode, so the body goes through a JSON value first.
+pub fn to_message_pack<T: Serialize>(body: &T) -> Result<Vec<u8>, Error> {
+    let value = serde_json::to_value(body).map_err(|_| Error::InternalError)?;
+    rmp_serde::to_vec_named(&value).map_err(|e| {
+        error!("Failed to encode a response as MessagePack: {}", e);
+        reporting::record_cause(&e);
+        Error::InternalError
+    })
+}
+
+/// Finishes `response` with `body` as MessagePack when the client prefers it and as JSON
+/// otherwise, protobuf and plain text only being available for todos.
+pub fn respond<T: Serialize>(
+    req: &HttpRequest,
+    mut response: HttpResponseBuilder,
+    body: &T,
+) -> HttpResponse {
+    response.insert_header((header::VARY, "Accept"));
+    match preferred_format(req) {
+        Format::MessagePack => match to_message_pack(body) {
+            Ok(bytes) => response.content_type(MESSAGE_PACK).body(bytes),
+            Err(e) => HttpResponse::from_error(e),
+        },
+        Format::Json | Format::Protobuf | Format::PlainText => response.json(body),
+    }
+}
+
+/// The format of the request body, `None` leaving it to `web::Json` to only accept JSON.
+fn body_format(req: &HttpRequest) -> Option<Format> {
+    let media_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())?
+        .trim();
+    let is = |other: &str| media_type.eq_ignore_ascii_case(other);
+    if is(MESSAGE_PACK) || is("application/x-msgpack") {
+        Some(Format::MessagePack)
+    } else if is(PROTOBUF) || is("application/protobuf") {
+        Some(Format::Protobuf)
+    } else {
+        None
+    }
+}
+
+/// Request bodies, which can also be sent as protobuf when they implement `from_protobuf`.
+pub trait Decode: DeserializeOwned {
+    fn from_protobuf(_bytes: &[u8]) -> Result<Self, Error> {
+        Err(Error::MalformedBody {
+            reason: "protobuf isn't supported for this request".to_owned(),
+            field: None,
+            line: None,
+            column: None,
+        })
+    }
+}
+
+/// A request body sent as JSON or, with a `Content-Type` of `application/msgpack` or
+/// `application/x-protobuf`, as MessagePack or protobuf. Takes the place of `web::Json` for the
+/// todo endpoints.
+pub struct Body<T>(pub T);
+
+impl<T> Body<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Body<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Decode + 'static> FromRequest for Body<T> {
+    type Config = ();
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let format = match body_format(req) {
+            Some(format) => format,
+            None => {
+                let json = web::Json::<T>::from_request(req, payload);
+                return async move { Ok(Body(json.await?.into_inner())) }.boxed_local();
+            }
+        };
+
+        let bytes = web::Bytes::from_request(req, payload);
+        async move {
+            let bytes = bytes.await?;
+            let body = match format {
+                Format::Protobuf => T::from_protobuf(&bytes)?,
+                _ => rmp_serde::from_slice(&bytes).map_err(Error::from)?,
+            };
+            Ok(Body(body))
+        }
+        .boxed_local()
+    }
+}
@@ -0,0 +1,106 @@
+use crate::crypto;
+use crate::outbox::{self, Event};
+use crate::Todo;
+use sqlx::{Postgres, Transaction};
+
+/// Posts todo activity to a Slack incoming webhook. Notifications go through the outbox, so
+/// they're only sent once the change they're about is committed. Without a configured webhook
+/// URL every notification is a no-op.
+#[derive(Clone)]
+pub struct SlackNotifier {
+    client: reqwest::Client,
+    webhook_url: Option<String>,
+}
+
+impl SlackNotifier {
+    pub fn new(webhook_url: Option<String>) -> Self {
+        SlackNotifier {
+            client: reqwest::Client::new(),
+            webhook_url,
+        }
+    }
+
+    pub async fn todo_created(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        todo: &Todo,
+        url: &str,
+    ) -> Result<(), sqlx::Error> {
+        if self.webhook_url.is_none() {
+            return Ok(());
+        }
+        let event = Event::TodoCreated {
+            title: crypto::encrypt_title(&todo.title),
+            url: url.to_owned(),
+        };
+        outbox::enqueue(tx, event).await
+    }
+
+    pub async fn todo_completed(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        todo: &Todo,
+        url: &str,
+    ) -> Result<(), sqlx::Error> {
+        if self.webhook_url.is_none() {
+            return Ok(());
+        }
+        let event = Event::TodoCompleted {
+            title: crypto::encrypt_title(&todo.title),
+            url: url.to_owned(),
+        };
+        outbox::enqueue(tx, event).await
+    }
+
+    pub async fn todo_overdue(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        todo: &Todo,
+        url: &str,
+    ) -> Result<(), sqlx::Error> {
+        if self.webhook_url.is_none() {
+            return Ok(());
+        }
+        let event = Event::TodoOverdue {
+            title: crypto::encrypt_title(&todo.title),
+            url: url.to_owned(),
+        };
+        outbox::enqueue(tx, event).await
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.webhook_url.is_some()
+    }
+
+    /// Sends the message for an event of the outbox.
+    pub async fn deliver(&self, event: &Event) -> Result<(), reqwest::Error> {
+        let webhook_url = match &self.webhook_url {
+            Some(webhook_url) => webhook_url,
+            None => return Ok(()),
+        };
+        let text = match event {
+            Event::TodoCreated { title, url } => format!("New todo: <{}|{}>", url, escape(title)),
+            Event::TodoCompleted { title, url } => {
+                format!("Completed: <{}|{}>", url, escape(title))
+            }
+            Event::TodoOverdue { title, url } => format!("Overdue: <{}|{}>", url, escape(title)),
+            // published to the event broker instead
+            Event::Change { .. } => return Ok(()),
+        };
+
+        self.client
+            .post(webhook_url)
+            .json(&serde_json::json!({ "text": text }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Escapes the characters Slack treats as control sequences in message text.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
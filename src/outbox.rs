@@ -0,0 +1,178 @@
+//! Events meant for other systems, written to the `outbox` table in the transaction of the
+//! change they're about. An event exists exactly when its change was committed, and the
+//! dispatcher keeps delivering it until it goes through, so neither a crash nor a rollback can
+//! lose events or send ones about changes that never happened. A crash between delivering an
+//! event and marking it delivered sends it again, delivery is at least once.
+
+use crate::crypto;
+use crate::events::Publisher;
+use crate::notifications::SlackNotifier;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::time::Duration;
+
+/// Events claimed by a single run of the dispatcher.
+const BATCH_SIZE: i64 = 50;
+/// Events still failing after this many attempts are given up on.
+const MAX_ATTEMPTS: i32 = 10;
+/// How long a claimed event is left alone, long enough for a delivery to time out.
+const CLAIM_SECS: i64 = 60;
+const MAX_RETRY_DELAY_SECS: i64 = 60 * 60;
+/// How long delivered events are kept around for looking into what was sent.
+const KEEP_DELIVERED_DAYS: i32 = 7;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Event {
+    /// Titles are stored encrypted, like the todos' own.
+    TodoCreated {
+        title: String,
+        url: String,
+    },
+    TodoCompleted {
+        title: String,
+        url: String,
+    },
+    TodoOverdue {
+        title: String,
+        url: String,
+    },
+    /// For the event publisher, `todo` being a snapshot like the ones of revisions.
+    Change {
+        action: String,
+        todo_id: i64,
+        todo: Value,
+    },
+}
+
+impl Event {
+    fn decrypt(self) -> Result<Event, sqlx::Error> {
+        Ok(match self {
+            Event::TodoCreated { title, url } => Event::TodoCreated {
+                title: crypto::decrypt_title(title)?,
+                url,
+            },
+            Event::TodoCompleted { title, url } => Event::TodoCompleted {
+                title: crypto::decrypt_title(title)?,
+                url,
+            },
+            Event::TodoOverdue { title, url } => Event::TodoOverdue {
+                title: crypto::decrypt_title(title)?,
+                url,
+            },
+            Event::Change {
+                action,
+                todo_id,
+                mut todo,
+            } => {
+                crypto::decrypt_snapshot(&mut todo)?;
+                Event::Change {
+                    action,
+                    todo_id,
+                    todo,
+                }
+            }
+        })
+    }
+
+    async fn deliver(
+        &self,
+        slack: &SlackNotifier,
+        publisher: Option<&Publisher>,
+    ) -> Result<(), String> {
+        match self {
+            Event::Change {
+                action,
+                todo_id,
+                todo,
+            } => match publisher {
+                Some(publisher) => publisher
+                    .publish(action, *todo_id, todo)
+                    .await
+                    .map_err(|e| format!("{:#}", e)),
+                None => Err("no event broker is configured".to_owned()),
+            },
+            event => slack.deliver(event).await.map_err(|e| e.to_string()),
+        }
+    }
+}
+
+pub async fn enqueue(tx: &mut Transaction<'_, Postgres>, event: Event) -> Result<(), sqlx::Error> {
+    let event = serde_json::to_value(&event).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+    sqlx::query!(r#"INSERT INTO outbox (event) VALUES ($1)"#, event)
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
+
+/// Exponential backoff starting at 10 seconds, capped at an hour.
+fn retry_delay(attempts: i32) -> Duration {
+    let secs = 5i64.saturating_mul(1 << attempts.clamp(1, 20));
+    Duration::from_secs(secs.min(MAX_RETRY_DELAY_SECS) as u64)
+}
+
+#[derive(Debug, Default)]
+pub struct Dispatched {
+    pub delivered: usize,
+    pub failed: usize,
+}
+
+/// Delivers the events that are due. Events are claimed before being delivered, so that
+/// instances running the dispatcher at the same time don't deliver them twice.
+pub async fn dispatch(
+    pool: &PgPool,
+    slack: &SlackNotifier,
+    publisher: Option<&Publisher>,
+) -> Result<Dispatched, sqlx::Error> {
+    let claimed = sqlx::query!(r#"UPDATE outbox SET attempts = attempts + 1, next_attempt_at = now() + $1::bigint * INTERVAL '1 second' WHERE id IN (SELECT id FROM outbox WHERE delivered_at IS NULL AND next_attempt_at <= now() AND attempts < $2 ORDER BY id LIMIT $3 FOR UPDATE SKIP LOCKED) RETURNING id, event, attempts"#, CLAIM_SECS, MAX_ATTEMPTS, BATCH_SIZE)
+        .fetch_all(pool)
+        .await?;
+
+    let mut dispatched = Dispatched::default();
+    for row in claimed {
+        let delivered = match serde_json::from_value::<Event>(row.event) {
+            Ok(event) => match event.decrypt() {
+                Ok(event) => event.deliver(slack, publisher).await,
+                Err(e) => Err(e.to_string()),
+            },
+            Err(e) => Err(format!("unknown event: {}", e)),
+        };
+
+        match delivered {
+            Ok(()) => {
+                sqlx::query!(
+                    r#"UPDATE outbox SET delivered_at = now(), last_error = NULL WHERE id = $1"#,
+                    row.id
+                )
+                .execute(pool)
+                .await?;
+                dispatched.delivered += 1;
+            }
+            Err(e) => {
+                if row.attempts >= MAX_ATTEMPTS {
+                    error!(
+                        "Gave up on delivering event {} after {} attempts: {}",
+                        row.id, row.attempts, e
+                    );
+                } else {
+                    warn!("Failed to deliver event {}, retrying later: {}", row.id, e);
+                }
+                let delay = retry_delay(row.attempts).as_secs() as i64;
+                sqlx::query!(r#"UPDATE outbox SET next_attempt_at = now() + $2::bigint * INTERVAL '1 second', last_error = $3 WHERE id = $1"#, row.id, delay, e)
+                    .execute(pool)
+                    .await?;
+                dispatched.failed += 1;
+            }
+        }
+    }
+
+    sqlx::query!(
+        r#"DELETE FROM outbox WHERE delivered_at < now() - $1::integer * INTERVAL '1 day'"#,
+        KEEP_DELIVERED_DAYS
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(dispatched)
+}
@@ -0,0 +1,78 @@
+use crate::error::Error;
+use actix_web::HttpRequest;
+
+pub const DEFAULT_PER_PAGE: i64 = 50;
+pub const MAX_PER_PAGE: i64 = 500;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Page {
+    pub page: i64,
+    pub per_page: i64,
+}
+
+impl Page {
+    /// Lists are only paginated when the client asks for it with `page` or `per_page`.
+    pub fn from_params(page: Option<i64>, per_page: Option<i64>) -> Result<Option<Page>, Error> {
+        if page.is_none() && per_page.is_none() {
+            return Ok(None);
+        }
+
+        let page = page.unwrap_or(1);
+        let per_page = per_page.unwrap_or(DEFAULT_PER_PAGE);
+        if page < 1 {
+            return Err(Error::InvalidQuery {
+                reason: "page needs to be at least 1".to_owned(),
+            });
+        }
+        if per_page < 1 || per_page > MAX_PER_PAGE {
+            return Err(Error::InvalidQuery {
+                reason: format!("per_page needs to be between 1 and {}", MAX_PER_PAGE),
+            });
+        }
+
+        Ok(Some(Page { page, per_page }))
+    }
+
+    pub fn limit(&self) -> i64 {
+        self.per_page
+    }
+
+    pub fn offset(&self) -> i64 {
+        (self.page - 1) * self.per_page
+    }
+
+    fn last_page(&self, total: i64) -> i64 {
+        ((total + self.per_page - 1) / self.per_page).max(1)
+    }
+}
+
+/// Builds an RFC 5988 `Link` header pointing at the neighbouring pages, keeping the other query
+/// parameters of the request intact.
+pub fn link_header(req: &HttpRequest, base_url: &str, page: Page, total: i64) -> String {
+    let params = req
+        .query_string()
+        .split('&')
+        .filter(|param| {
+            !param.is_empty() && !param.starts_with("page=") && !param.starts_with("per_page=")
+        })
+        .collect::<Vec<&str>>();
+    let link = |number: i64, rel: &str| {
+        let mut query = params.clone();
+        let page_param = format!("page={}", number);
+        let per_page_param = format!("per_page={}", page.per_page);
+        query.push(&page_param);
+        query.push(&per_page_param);
+        format!("<{}?{}>; rel=\"{}\"", base_url, query.join("&"), rel)
+    };
+
+    let last = page.last_page(total);
+    let mut links = vec![link(1, "first")];
+    if page.page > 1 {
+        links.push(link((page.page - 1).min(last), "prev"));
+    }
+    if page.page < last {
+        links.push(link(page.page + 1, "next"));
+    }
+    links.push(link(last, "last"));
+    links.join(", ")
+}
@@ -0,0 +1,136 @@
+//! Wakes up long polling requests as soon as anything changes. A trigger notifies the
+//! `todo_revisions` channel of every revision written, so changes made through any instance are
+//! seen, and the feed keeps track of the latest revision notified.
+
+use crate::error::Error;
+use actix_web::rt;
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::{self, Instant};
+
+const CHANNEL: &str = "todo_revisions";
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Proxies tend to give up on requests idle for a minute, so polls end before that.
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(55);
+
+pub struct ChangeFeed {
+    latest: watch::Receiver<i64>,
+}
+
+pub async fn latest_revision(pool: &PgPool) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(r#"SELECT COALESCE(MAX(id), 0) AS "latest!" FROM todo_revisions"#)
+        .fetch_one(pool)
+        .await
+}
+
+impl ChangeFeed {
+    /// Starts listening on a connection of its own, which is held for as long as the server runs.
+    pub async fn listen(pool: &PgPool) -> Result<ChangeFeed, sqlx::Error> {
+        let mut listener = PgListener::connect_with(pool).await?;
+        listener.listen(CHANNEL).await?;
+        // only read once listening, so that no revision falls in between
+        let (sender, latest) = watch::channel(latest_revision(pool).await?);
+
+        let pool = pool.clone();
+        rt::spawn(async move {
+            loop {
+                match listener.try_recv().await {
+                    Ok(Some(notification)) => match notification.payload().parse::<i64>() {
+                        // transactions can commit in a different order than they got their ids
+                        Ok(id) => sender.send_modify(|latest| *latest = (*latest).max(id)),
+                        Err(_) => warn!(
+                            "Ignored a notification about revision {:?}",
+                            notification.payload()
+                        ),
+                    },
+                    // the connection was lost and reestablished, whatever was notified in
+                    // between is caught up on from the table
+                    Ok(None) => match latest_revision(&pool).await {
+                        Ok(id) => sender.send_modify(|latest| *latest = (*latest).max(id)),
+                        Err(e) => error!("Failed to catch up on revisions: {}", e),
+                    },
+                    Err(e) => {
+                        error!("Failed to receive revision notifications: {}", e);
+                        time::sleep(RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        });
+
+        Ok(ChangeFeed { latest })
+    }
+
+    /// The latest revision notified, changing along with it.
+    pub fn subscribe(&self) -> watch::Receiver<i64> {
+        self.latest.clone()
+    }
+
+    /// Waits for a revision after `cursor`, returning false when the deadline passes first.
+    pub async fn wait_past(&self, cursor: i64, deadline: Instant) -> bool {
+        let mut latest = self.latest.clone();
+        let changed = latest.wait_for(|latest| *latest > cursor);
+        matches!(time::timeout_at(deadline, changed).await, Ok(Ok(_)))
+    }
+}
+
+/// Parses a timeout like `30s`, or `30` for the same number of seconds, capped at `MAX_TIMEOUT`.
+pub fn parse_timeout(timeout: Option<&str>) -> Result<Duration, Error> {
+    let timeout = match timeout {
+        Some(timeout) => timeout.trim(),
+        None => return Ok(DEFAULT_TIMEOUT),
+    };
+    let seconds = timeout
+        .strip_suffix('s')
+        .unwrap_or(timeout)
+        .parse::<u64>()
+        .map_err(|_| Error::InvalidQuery {
+            reason: "timeout needs to be a number of seconds, like 30s".to_owned(),
+        })?;
+    Ok(Duration::from_secs(seconds).min(MAX_TIMEOUT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeouts_are_seconds_with_or_without_the_unit() {
+        assert_eq!(
+            parse_timeout(Some("10s")).ok(),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            parse_timeout(Some(" 10 ")).ok(),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(parse_timeout(Some("0")).ok(), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn missing_timeouts_are_the_default() {
+        assert_eq!(parse_timeout(None).ok(), Some(DEFAULT_TIMEOUT));
+    }
+
+    #[test]
+    fn long_timeouts_are_capped() {
+        assert_eq!(parse_timeout(Some("3600s")).ok(), Some(MAX_TIMEOUT));
+    }
+
+    #[test]
+    fn other_timeouts_are_invalid() {
+        for timeout in ["", "s", "10m", "-1", "1.5s"] {
+            assert!(
+                matches!(
+                    parse_timeout(Some(timeout)),
+                    Err(Error::InvalidQuery { .. })
+                ),
+                "{}",
+                timeout
+            );
+        }
+    }
+}
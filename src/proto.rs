@@ -0,0 +1,298 @@
+//! The messages of `proto/todo.proto`, written out with prost's derives rather than generated
+//! so that builds don't need `protoc`, and their conversions from and to the JSON models.
+
+use crate::error::Error;
+use crate::location::Location as TodoLocation;
+use crate::negotiation::Decode;
+use crate::status::Status;
+use crate::TodoPresenter;
+use chrono::{DateTime, TimeZone, Utc};
+use prost::Message;
+use prost_types::Timestamp;
+use std::convert::{TryFrom, TryInto};
+use uuid::Uuid;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Location {
+    #[prost(double, tag = "1")]
+    pub latitude: f64,
+    #[prost(double, tag = "2")]
+    pub longitude: f64,
+    #[prost(string, optional, tag = "3")]
+    pub place_name: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Todo {
+    #[prost(oneof = "TodoId", tags = "1, 2")]
+    pub id: Option<TodoId>,
+    #[prost(string, tag = "3")]
+    pub url: String,
+    #[prost(string, tag = "4")]
+    pub uuid: String,
+    #[prost(string, tag = "5")]
+    pub title: String,
+    #[prost(bool, tag = "6")]
+    pub completed: bool,
+    #[prost(double, tag = "7")]
+    pub order: f64,
+    #[prost(int64, tag = "8")]
+    pub version: i64,
+    #[prost(message, optional, tag = "9")]
+    pub completed_at: Option<Timestamp>,
+    #[prost(bool, tag = "10")]
+    pub starred: bool,
+    #[prost(string, optional, tag = "11")]
+    pub color: Option<String>,
+    #[prost(message, optional, tag = "12")]
+    pub due_at: Option<Timestamp>,
+    #[prost(string, tag = "13")]
+    pub status: String,
+    #[prost(string, tag = "14")]
+    pub custom_fields: String,
+    #[prost(int64, tag = "15")]
+    pub tracked_seconds: i64,
+    #[prost(int32, optional, tag = "16")]
+    pub estimate_minutes: Option<i32>,
+    #[prost(message, optional, tag = "17")]
+    pub location: Option<Location>,
+    #[prost(string, optional, tag = "18")]
+    pub highlight: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum TodoId {
+    #[prost(int64, tag = "1")]
+    NumericId(i64),
+    #[prost(string, tag = "2")]
+    PublicId(String),
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TodoList {
+    #[prost(message, repeated, tag = "1")]
+    pub todos: Vec<Todo>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct NewTodo {
+    #[prost(string, optional, tag = "1")]
+    pub uuid: Option<String>,
+    #[prost(string, tag = "2")]
+    pub title: String,
+    #[prost(double, optional, tag = "3")]
+    pub order: Option<f64>,
+    #[prost(string, optional, tag = "4")]
+    pub color: Option<String>,
+    #[prost(message, optional, tag = "5")]
+    pub due_at: Option<Timestamp>,
+    #[prost(string, optional, tag = "6")]
+    pub due: Option<String>,
+    #[prost(int32, optional, tag = "7")]
+    pub estimate_minutes: Option<i32>,
+    #[prost(message, optional, tag = "8")]
+    pub location: Option<Location>,
+    #[prost(string, optional, tag = "9")]
+    pub custom_fields: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct UpdateTodo {
+    #[prost(string, optional, tag = "1")]
+    pub title: Option<String>,
+    #[prost(bool, optional, tag = "2")]
+    pub completed: Option<bool>,
+    #[prost(string, optional, tag = "3")]
+    pub status: Option<String>,
+    #[prost(double, optional, tag = "4")]
+    pub order: Option<f64>,
+    #[prost(bool, optional, tag = "5")]
+    pub starred: Option<bool>,
+    #[prost(string, optional, tag = "6")]
+    pub color: Option<String>,
+    #[prost(message, optional, tag = "7")]
+    pub due_at: Option<Timestamp>,
+    #[prost(int32, optional, tag = "8")]
+    pub estimate_minutes: Option<i32>,
+    #[prost(string, optional, tag = "9")]
+    pub due: Option<String>,
+    #[prost(message, optional, tag = "10")]
+    pub location: Option<Location>,
+    #[prost(string, optional, tag = "11")]
+    pub custom_fields: Option<String>,
+    #[prost(int64, optional, tag = "12")]
+    pub version: Option<i64>,
+    #[prost(string, repeated, tag = "13")]
+    pub clear: Vec<String>,
+}
+
+fn malformed(field: &str, reason: String) -> Error {
+    Error::MalformedBody {
+        reason,
+        field: Some(field.to_owned()),
+        line: None,
+        column: None,
+    }
+}
+
+fn timestamp(time: DateTime<Utc>) -> Timestamp {
+    Timestamp {
+        seconds: time.timestamp(),
+        nanos: time.timestamp_subsec_nanos() as i32,
+    }
+}
+
+fn date_time(field: &str, timestamp: Timestamp) -> Result<DateTime<Utc>, Error> {
+    u32::try_from(timestamp.nanos)
+        .ok()
+        .and_then(|nanos| Utc.timestamp_opt(timestamp.seconds, nanos).single())
+        .ok_or_else(|| malformed(field, "timestamp out of range".to_owned()))
+}
+
+fn custom_fields(
+    custom_fields: Option<String>,
+) -> Result<serde_json::Map<String, serde_json::Value>, Error> {
+    match custom_fields.as_deref().map(str::trim) {
+        None | Some("") => Ok(serde_json::Map::new()),
+        Some(json) => serde_json::from_str(json)
+            .map_err(|e| malformed("custom_fields", format!("needs to be a JSON object: {}", e))),
+    }
+}
+
+/// Fields of `UpdateTodo` that can be removed by listing them in `clear`.
+const CLEARABLE: &[&str] = &["color", "due_at", "estimate_minutes", "location"];
+
+/// A field listed in `clear` becomes `Some(None)`, like an explicit `null` in JSON.
+fn clearable<T>(cleared: &[String], field: &str, value: Option<T>) -> Option<Option<T>> {
+    match value {
+        Some(value) => Some(Some(value)),
+        None if cleared.iter().any(|cleared| cleared == field) => Some(None),
+        None => None,
+    }
+}
+
+impl From<Location> for TodoLocation {
+    fn from(location: Location) -> Self {
+        TodoLocation {
+            latitude: location.latitude,
+            longitude: location.longitude,
+            place_name: location.place_name,
+        }
+    }
+}
+
+impl From<&TodoPresenter> for Todo {
+    fn from(presenter: &TodoPresenter) -> Self {
+        let todo = &presenter.todo;
+        let id = match &presenter.public_id {
+            Some(public_id) => TodoId::PublicId(public_id.clone()),
+            None => TodoId::NumericId(todo.id),
+        };
+        let location = match (todo.latitude, todo.longitude) {
+            (Some(latitude), Some(longitude)) => Some(Location {
+                latitude,
+                longitude,
+                place_name: todo.place_name.clone(),
+            }),
+            _ => None,
+        };
+        Todo {
+            id: Some(id),
+            url: presenter.url.clone(),
+            uuid: todo.uuid.to_string(),
+            title: todo.title.clone(),
+            completed: todo.completed,
+            order: todo.order,
+            version: todo.version,
+            completed_at: todo.completed_at.map(timestamp),
+            starred: todo.starred,
+            color: todo.color.clone(),
+            due_at: todo.due_at.map(timestamp),
+            status: todo.status.clone(),
+            custom_fields: todo.custom_fields.to_string(),
+            tracked_seconds: todo.tracked_seconds,
+            estimate_minutes: todo.estimate_minutes,
+            location,
+            highlight: None,
+        }
+    }
+}
+
+impl TryFrom<NewTodo> for crate::NewTodo {
+    type Error = Error;
+
+    fn try_from(todo: NewTodo) -> Result<Self, Error> {
+        let uuid = todo
+            .uuid
+            .map(|uuid| Uuid::parse_str(&uuid))
+            .transpose()
+            .map_err(|e| malformed("uuid", e.to_string()))?;
+        Ok(crate::NewTodo {
+            uuid,
+            title: todo.title,
+            order: todo.order,
+            color: todo.color,
+            due_at: todo
+                .due_at
+                .map(|due_at| date_time("due_at", due_at))
+                .transpose()?,
+            due: todo.due,
+            estimate_minutes: todo.estimate_minutes,
+            location: todo.location.map(TodoLocation::from),
+            custom_fields: custom_fields(todo.custom_fields)?,
+        })
+    }
+}
+
+impl TryFrom<UpdateTodo> for crate::UpdateTodo {
+    type Error = Error;
+
+    fn try_from(update: UpdateTodo) -> Result<Self, Error> {
+        for field in &update.clear {
+            if !CLEARABLE.contains(&field.as_str()) {
+                return Err(malformed("clear", format!("`{}` can't be cleared", field)));
+            }
+        }
+        let cleared = &update.clear;
+
+        let status = update
+            .status
+            .map(|status| status.parse::<Status>())
+            .transpose()
+            .map_err(|reason| malformed("status", reason))?;
+        let due_at = update
+            .due_at
+            .map(|due_at| date_time("due_at", due_at))
+            .transpose()?;
+        let custom_fields = match update.custom_fields {
+            Some(values) => Some(custom_fields(Some(values))?),
+            None => None,
+        };
+        Ok(crate::UpdateTodo {
+            title: update.title,
+            completed: update.completed,
+            status,
+            order: update.order,
+            starred: update.starred,
+            color: clearable(cleared, "color", update.color),
+            due_at: clearable(cleared, "due_at", due_at),
+            estimate_minutes: clearable(cleared, "estimate_minutes", update.estimate_minutes),
+            due: update.due,
+            location: clearable(cleared, "location", update.location.map(TodoLocation::from)),
+            custom_fields,
+            version: update.version,
+        })
+    }
+}
+
+impl Decode for crate::NewTodo {
+    fn from_protobuf(bytes: &[u8]) -> Result<Self, Error> {
+        NewTodo::decode(bytes)?.try_into()
+    }
+}
+
+impl Decode for crate::UpdateTodo {
+    fn from_protobuf(bytes: &[u8]) -> Result<Self, Error> {
+        UpdateTodo::decode(bytes)?.try_into()
+    }
+}
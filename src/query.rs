@@ -0,0 +1,416 @@
+//! A small query language for filtering todos, e.g. `completed:false -title:"draft" milk`.
+//!
+//! A query is a whitespace separated list of terms that all have to match. A term is either
+//! free text, matched against the title, or a `key:value` filter. Values and free text can be
+//! quoted to include whitespace and any term can be negated with a leading `-`.
+
+use crate::error::Error;
+use crate::status::Status;
+use crate::validation::normalize_title;
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::postgres::PgArguments;
+use sqlx::Arguments;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Term {
+    Text(String),
+    Filter { key: String, value: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Clause {
+    negated: bool,
+    term: Term,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query(Vec<Clause>);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Param {
+    Bool(bool),
+    Text(String),
+    Time(DateTime<Utc>),
+}
+
+/// A query compiled to an SQL condition on the `todos` table, along with its bound parameters.
+#[derive(Debug, Clone)]
+pub struct Condition {
+    pub sql: String,
+    params: Vec<Param>,
+}
+
+impl Condition {
+    /// Arguments for the placeholders in `sql`, built anew for each statement using them.
+    pub fn arguments(&self) -> PgArguments {
+        let mut arguments = PgArguments::default();
+        for param in &self.params {
+            match param {
+                Param::Bool(value) => arguments.add(*value),
+                Param::Text(value) => arguments.add(value.clone()),
+                Param::Time(value) => arguments.add(*value),
+            }
+        }
+        arguments
+    }
+}
+
+fn invalid(reason: String) -> Error {
+    Error::InvalidQuery { reason }
+}
+
+/// Reads a possibly quoted word, stopping at whitespace (or at `:` when `key` is set).
+fn read_word(
+    chars: &mut std::iter::Peekable<std::str::Chars<'_>>,
+    key: bool,
+) -> Result<String, Error> {
+    let mut word = String::new();
+    if chars.peek() == Some(&'"') {
+        chars.next();
+        loop {
+            match chars.next() {
+                Some('"') => return Ok(word),
+                Some('\\') => match chars.next() {
+                    Some(c) => word.push(c),
+                    None => break,
+                },
+                Some(c) => word.push(c),
+                None => break,
+            }
+        }
+        return Err(invalid("unterminated quote in the search query".to_owned()));
+    }
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() || (key && c == ':') {
+            break;
+        }
+        word.push(c);
+        chars.next();
+    }
+    Ok(word)
+}
+
+pub fn parse(input: &str) -> Result<Query, Error> {
+    let mut clauses = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    loop {
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let negated = chars.peek() == Some(&'-');
+        if negated {
+            chars.next();
+        }
+
+        let quoted = chars.peek() == Some(&'"');
+        let word = read_word(&mut chars, true)?;
+        let term = if !quoted && chars.peek() == Some(&':') {
+            chars.next();
+            let value = read_word(&mut chars, false)?;
+            Term::Filter {
+                key: word.to_lowercase(),
+                value,
+            }
+        } else {
+            Term::Text(word)
+        };
+
+        if let Term::Text(text) = &term {
+            if text.is_empty() {
+                return Err(invalid("empty term in the search query".to_owned()));
+            }
+        }
+        clauses.push(Clause { negated, term });
+    }
+
+    Ok(Query(clauses))
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, Error> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" => Ok(true),
+        "false" | "no" => Ok(false),
+        _ => Err(invalid(format!("`{}` expects true or false", key))),
+    }
+}
+
+/// Accepts RFC 3339 timestamps as well as plain dates, which are taken as midnight UTC.
+fn parse_time(key: &str, value: &str) -> Result<DateTime<Utc>, Error> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| DateTime::from_utc(date.and_hms(0, 0, 0), Utc))
+        .map_err(|_| invalid(format!("`{}` expects a date like 2021-08-01", key)))
+}
+
+/// Turns text into a LIKE pattern matching it anywhere, with the wildcards in it escaped.
+fn contains_pattern(text: &str) -> String {
+    let escaped = normalize_title(text)
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+/// Matches titles ignoring case and accents, so "cafe" finds "Café". `search_text` is defined
+/// by a migration, along with the index backing it.
+fn title_matches(pattern: String) -> String {
+    format!("search_text(title) LIKE search_text({})", pattern)
+}
+
+impl Query {
+    /// The text the titles of matching todos contain, negated terms match nothing to highlight.
+    pub fn text_terms(&self) -> Vec<String> {
+        self.0
+            .iter()
+            .filter(|clause| !clause.negated)
+            .filter_map(|clause| match &clause.term {
+                Term::Text(text) => Some(normalize_title(text)),
+                Term::Filter { key, value } if key == "title" => Some(normalize_title(value)),
+                Term::Filter { .. } => None,
+            })
+            .collect()
+    }
+
+    /// Compiles the query, numbering its placeholders from `$first_param` so the condition can
+    /// be combined with other parameters.
+    pub fn compile(&self, first_param: usize) -> Result<Condition, Error> {
+        let mut conditions = Vec::new();
+        let mut params = Vec::new();
+        let mut placeholder = |param: Param| {
+            params.push(param);
+            format!("${}", first_param + params.len() - 1)
+        };
+
+        for clause in &self.0 {
+            let condition = match &clause.term {
+                Term::Text(text) => title_matches(placeholder(Param::Text(contains_pattern(text)))),
+                Term::Filter { key, value } => match key.as_str() {
+                    "completed" => format!(
+                        "completed = {}",
+                        placeholder(Param::Bool(parse_bool(key, value)?))
+                    ),
+                    "status" => {
+                        let status = value.parse::<Status>().map_err(invalid)?;
+                        format!(
+                            "status = {}",
+                            placeholder(Param::Text(status.as_str().to_owned()))
+                        )
+                    }
+                    "starred" => format!(
+                        "starred = {}",
+                        placeholder(Param::Bool(parse_bool(key, value)?))
+                    ),
+                    "title" => title_matches(placeholder(Param::Text(contains_pattern(value)))),
+                    "completed_after" => format!(
+                        "completed_at >= {}",
+                        placeholder(Param::Time(parse_time(key, value)?))
+                    ),
+                    "completed_before" => format!(
+                        "completed_at < {}",
+                        placeholder(Param::Time(parse_time(key, value)?))
+                    ),
+                    "due_after" => format!(
+                        "due_at >= {}",
+                        placeholder(Param::Time(parse_time(key, value)?))
+                    ),
+                    "due_before" => format!(
+                        "due_at < {}",
+                        placeholder(Param::Time(parse_time(key, value)?))
+                    ),
+                    key if key.starts_with("field.") => {
+                        let field = placeholder(Param::Text(key["field.".len()..].to_owned()));
+                        let value = placeholder(Param::Text(value.clone()));
+                        format!("custom_fields ->> {} = {}", field, value)
+                    }
+                    _ => return Err(invalid(format!("unknown filter `{}`", key))),
+                },
+            };
+
+            // comparisons with NULL are neither true nor false, negations have to include them
+            if clause.negated {
+                conditions.push(format!("({}) IS NOT TRUE", condition));
+            } else {
+                conditions.push(condition);
+            }
+        }
+
+        let sql = if conditions.is_empty() {
+            "TRUE".to_owned()
+        } else {
+            conditions.join(" AND ")
+        };
+        Ok(Condition { sql, params })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(negated: bool, text: &str) -> Clause {
+        Clause {
+            negated,
+            term: Term::Text(text.to_owned()),
+        }
+    }
+
+    fn filter(negated: bool, key: &str, value: &str) -> Clause {
+        Clause {
+            negated,
+            term: Term::Filter {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            },
+        }
+    }
+
+    fn is_invalid<T: std::fmt::Debug>(result: Result<T, Error>) -> bool {
+        matches!(result, Err(Error::InvalidQuery { .. }))
+    }
+
+    #[test]
+    fn terms_are_text_or_filters() {
+        assert_eq!(
+            parse("  milk Completed:false  starred:yes ").ok(),
+            Some(Query(vec![
+                text(false, "milk"),
+                filter(false, "completed", "false"),
+                filter(false, "starred", "yes"),
+            ]))
+        );
+        assert_eq!(parse("").ok(), Some(Query(vec![])));
+    }
+
+    #[test]
+    fn negation_applies_to_the_whole_term() {
+        assert_eq!(
+            parse(r#"-draft -title:"old milk""#).ok(),
+            Some(Query(vec![
+                text(true, "draft"),
+                filter(true, "title", "old milk"),
+            ]))
+        );
+    }
+
+    #[test]
+    fn quotes_keep_whitespace_colons_and_escapes() {
+        assert_eq!(
+            parse(r#""buy milk" "at: 5pm" "say \"hi\"""#).ok(),
+            Some(Query(vec![
+                text(false, "buy milk"),
+                text(false, "at: 5pm"),
+                text(false, r#"say "hi""#),
+            ]))
+        );
+        // only the part before the first colon is the key
+        assert_eq!(
+            parse("due_after:2021-08-01T10:00:00Z").ok(),
+            Some(Query(vec![filter(
+                false,
+                "due_after",
+                "2021-08-01T10:00:00Z"
+            )]))
+        );
+    }
+
+    #[test]
+    fn malformed_queries_are_invalid() {
+        assert!(is_invalid(parse(r#""buy milk"#)));
+        assert!(is_invalid(parse(r#"title:"milk"#)));
+        assert!(is_invalid(parse("milk -")));
+        assert!(is_invalid(parse(r#""""#)));
+    }
+
+    #[test]
+    fn terms_compile_to_conditions_that_all_have_to_match() {
+        let condition = parse("milk completed:true -starred:no")
+            .and_then(|query| query.compile(1))
+            .unwrap();
+        assert_eq!(
+            condition.sql,
+            "search_text(title) LIKE search_text($1) AND completed = $2 AND (starred = $3) IS NOT TRUE"
+        );
+        assert_eq!(
+            condition.params,
+            [
+                Param::Text("%milk%".to_owned()),
+                Param::Bool(true),
+                Param::Bool(false),
+            ]
+        );
+    }
+
+    #[test]
+    fn placeholders_start_at_the_given_number() {
+        let condition = parse("status:done field.priority:high")
+            .and_then(|query| query.compile(3))
+            .unwrap();
+        assert_eq!(condition.sql, "status = $3 AND custom_fields ->> $4 = $5");
+        assert_eq!(
+            condition.params,
+            [
+                Param::Text("done".to_owned()),
+                Param::Text("priority".to_owned()),
+                Param::Text("high".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_queries_match_everything() {
+        let condition = parse("").and_then(|query| query.compile(1)).unwrap();
+        assert_eq!(condition.sql, "TRUE");
+        assert!(condition.params.is_empty());
+    }
+
+    #[test]
+    fn like_wildcards_in_text_are_escaped() {
+        let condition = parse(r#""100%_done""#)
+            .and_then(|query| query.compile(1))
+            .unwrap();
+        assert_eq!(condition.params, [Param::Text(r"%100\%\_done%".to_owned())]);
+    }
+
+    #[test]
+    fn dates_are_midnight_utc() {
+        let condition =
+            parse("completed_after:2021-08-01 completed_before:2021-08-02T12:00:00+02:00")
+                .and_then(|query| query.compile(1))
+                .unwrap();
+        assert_eq!(condition.sql, "completed_at >= $1 AND completed_at < $2");
+        assert_eq!(
+            condition.params,
+            [
+                Param::Time("2021-08-01T00:00:00Z".parse().unwrap()),
+                Param::Time("2021-08-02T10:00:00Z".parse().unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_filters_fail_to_compile() {
+        for query in [
+            "colour:red",
+            "completed:maybe",
+            "status:someday",
+            "due_before:tomorrow",
+        ] {
+            let compiled = parse(query).and_then(|query| query.compile(1));
+            assert!(is_invalid(compiled), "{}", query);
+        }
+    }
+
+    #[test]
+    fn highlighted_terms_skip_negations_and_other_filters() {
+        let query = parse("milk -bread title:eggs starred:true").unwrap();
+        assert_eq!(query.text_terms(), ["milk", "eggs"]);
+    }
+}
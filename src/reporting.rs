@@ -0,0 +1,84 @@
+//! Error reporting to Sentry. Without a configured DSN nothing is sent and capturing is a no-op.
+//!
+//! Only internal errors are reported, the other server errors like timeouts or maintenance are
+//! expected. Each request gets a hub of its own, so that the causes recorded while handling it
+//! end up on its report and not on the one of a concurrent request.
+
+use crate::error::Error;
+use crate::request_id::RequestId;
+use actix_web::dev::ServiceResponse;
+use sentry::protocol::Breadcrumb;
+use sentry::{ClientInitGuard, ClientOptions, Hub, Level, SentryFutureExt};
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::Arc;
+
+/// Marks responses to panics, which the panic integration reported already.
+pub struct PanicReported;
+
+/// Starts the Sentry client, panics are reported by its default integrations. The returned
+/// guard flushes pending events when dropped, so it has to live until the server stops.
+pub fn init(dsn: Option<String>, environment: Option<String>) -> Option<ClientInitGuard> {
+    let dsn = dsn?;
+    let guard = sentry::init((
+        dsn,
+        ClientOptions {
+            release: Some(format!("todo-backend@{}", env!("CARGO_PKG_VERSION")).into()),
+            environment: environment.map(Into::into),
+            ..Default::default()
+        },
+    ));
+    if guard.is_enabled() {
+        info!("Reporting errors to Sentry");
+    }
+    Some(guard)
+}
+
+/// Calls the service and drives the response it returns on a hub of the request's own.
+pub fn in_hub<C, F>(call: C) -> impl Future<Output = F::Output>
+where
+    C: FnOnce() -> F,
+    F: Future,
+{
+    let hub = Arc::new(Hub::new_from_top(Hub::current()));
+    let response = Hub::run(hub.clone(), call);
+    response.bind_hub(hub)
+}
+
+/// Records the error an internal error was caused by, to be sent along with the report of the
+/// request. Internal errors don't carry their cause, which is only known where they're created.
+pub fn record_cause(cause: &dyn Display) {
+    sentry::add_breadcrumb(Breadcrumb {
+        category: Some("cause".to_owned()),
+        message: Some(cause.to_string()),
+        level: Level::Error,
+        ..Default::default()
+    });
+}
+
+/// Reports a response to an internal error along with the request it failed.
+pub fn capture_server_error<B>(res: &ServiceResponse<B>, id: &RequestId) {
+    let internal = res
+        .response()
+        .error()
+        .and_then(|e| e.as_error::<Error>())
+        .map_or(false, |e| matches!(e, Error::InternalError));
+    if !internal || res.response().extensions().contains::<PanicReported>() {
+        return;
+    }
+
+    let req = res.request();
+    sentry::with_scope(
+        |scope| {
+            scope.set_tag("request_id", &id.0);
+            scope.set_tag("method", req.method());
+            scope.set_tag("status", res.status().as_u16());
+            scope.set_extra("path", req.path().into());
+            scope.set_extra("query", req.query_string().into());
+        },
+        || {
+            let message = format!("{} {}: {}", req.method(), req.path(), Error::InternalError);
+            sentry::capture_message(&message, Level::Error)
+        },
+    );
+}
@@ -0,0 +1,97 @@
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::HttpMessage;
+use rand::{distributions::Alphanumeric, Rng};
+use std::future::Future;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const REQUEST_ID_LENGTH: usize = 20;
+/// Incoming ids longer than this are replaced, so clients can't flood the logs.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Identifies a request in logs and error responses, stored in the request extensions.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+tokio::task_local! {
+    /// The id of the request being handled, for the logger to add to every line.
+    static CURRENT: RequestId;
+}
+
+impl RequestId {
+    /// Reuses the id a proxy or client sent along, generating a new one otherwise.
+    fn from_request(req: &ServiceRequest) -> Self {
+        let incoming = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|id| id.to_str().ok())
+            .map(str::trim)
+            .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH);
+
+        match incoming {
+            Some(id) => RequestId(id.to_owned()),
+            None => RequestId(
+                rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(REQUEST_ID_LENGTH)
+                    .map(char::from)
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Assigns the request its id, returning it so it can be logged once the response is ready.
+pub fn assign(req: &ServiceRequest) -> RequestId {
+    let id = RequestId::from_request(req);
+    req.extensions_mut().insert(id.clone());
+    id
+}
+
+/// Calls the service and drives the response it returns with the id as the current one, so
+/// that whatever is logged on the way is logged under it.
+pub fn in_scope<C, F>(id: RequestId, call: C) -> impl Future<Output = F::Output>
+where
+    C: FnOnce() -> F,
+    F: Future,
+{
+    let response = CURRENT.sync_scope(id.clone(), call);
+    CURRENT.scope(id, response)
+}
+
+/// The id of the request being handled, `None` outside of requests.
+pub fn current() -> Option<String> {
+    CURRENT.try_with(|id| id.0.clone()).ok()
+}
+
+/// Returns the id in the response headers and logs the outcome of the request.
+pub fn finish<B>(res: &mut ServiceResponse<B>, id: &RequestId) {
+    if let Ok(value) = HeaderValue::from_str(&id.0) {
+        res.headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+
+    let req = res.request();
+    if res.status().is_server_error() {
+        let reason = res
+            .response()
+            .error()
+            .map(|e| format!("{:?}", e))
+            .unwrap_or_default();
+        error!(
+            "{} {} -> {} {}",
+            req.method(),
+            req.path(),
+            res.status(),
+            reason
+        );
+    } else {
+        info!("{} {} -> {}", req.method(), req.path(), res.status());
+    }
+}
+
+/// The id of a request that went through `assign`.
+pub fn of(req: &actix_web::HttpRequest) -> Option<String> {
+    req.extensions().get::<RequestId>().map(|id| id.0.clone())
+}
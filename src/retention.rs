@@ -0,0 +1,100 @@
+//! Retention of the revisions in `todo_revisions`. Revisions of existing todos make up their
+//! history, the ones of deleted todos are the tombstones telling syncing clients about the
+//! deletions, and each kind is kept for its own number of days. Sync tokens pointing before the
+//! oldest remaining revision expire, so clients with outdated tokens sync again from scratch.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetentionPolicy {
+    /// Days to keep revisions of existing todos for, forever when `None`.
+    pub revisions_days: Option<i32>,
+    /// Days to keep revisions of deleted todos for, forever when `None`.
+    pub tombstones_days: Option<i32>,
+}
+
+impl RetentionPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.revisions_days.is_some() || self.tombstones_days.is_some()
+    }
+}
+
+/// What a policy would delete if it was enforced right now.
+#[derive(Serialize)]
+pub struct Expired {
+    retention_days: i32,
+    count: i64,
+    oldest: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct RetentionReport {
+    revisions: Option<Expired>,
+    tombstones: Option<Expired>,
+}
+
+#[derive(Debug, Default)]
+pub struct Deleted {
+    pub revisions: u64,
+    pub tombstones: u64,
+}
+
+async fn expired_revisions(pool: &PgPool, days: i32) -> Result<Expired, sqlx::Error> {
+    let expired = sqlx::query!(r#"SELECT COUNT(*) AS "count!", MIN(created_at) AS oldest FROM todo_revisions WHERE created_at < now() - $1::integer * INTERVAL '1 day' AND EXISTS (SELECT 1 FROM todos WHERE todos.id = todo_revisions.todo_id)"#, days)
+        .fetch_one(pool)
+        .await?;
+    Ok(Expired {
+        retention_days: days,
+        count: expired.count,
+        oldest: expired.oldest,
+    })
+}
+
+async fn expired_tombstones(pool: &PgPool, days: i32) -> Result<Expired, sqlx::Error> {
+    let expired = sqlx::query!(r#"SELECT COUNT(*) AS "count!", MIN(created_at) AS oldest FROM todo_revisions WHERE created_at < now() - $1::integer * INTERVAL '1 day' AND NOT EXISTS (SELECT 1 FROM todos WHERE todos.id = todo_revisions.todo_id)"#, days)
+        .fetch_one(pool)
+        .await?;
+    Ok(Expired {
+        retention_days: days,
+        count: expired.count,
+        oldest: expired.oldest,
+    })
+}
+
+/// Reports what `enforce` would delete without deleting anything.
+pub async fn report(
+    pool: &PgPool,
+    policy: RetentionPolicy,
+) -> Result<RetentionReport, sqlx::Error> {
+    let revisions = match policy.revisions_days {
+        Some(days) => Some(expired_revisions(pool, days).await?),
+        None => None,
+    };
+    let tombstones = match policy.tombstones_days {
+        Some(days) => Some(expired_tombstones(pool, days).await?),
+        None => None,
+    };
+    Ok(RetentionReport {
+        revisions,
+        tombstones,
+    })
+}
+
+pub async fn enforce(pool: &PgPool, policy: RetentionPolicy) -> Result<Deleted, sqlx::Error> {
+    let mut deleted = Deleted::default();
+    if let Some(days) = policy.revisions_days {
+        deleted.revisions = sqlx::query!(r#"DELETE FROM todo_revisions WHERE created_at < now() - $1::integer * INTERVAL '1 day' AND EXISTS (SELECT 1 FROM todos WHERE todos.id = todo_revisions.todo_id)"#, days)
+            .execute(pool)
+            .await?
+            .rows_affected();
+    }
+    if let Some(days) = policy.tombstones_days {
+        deleted.tombstones = sqlx::query!(r#"DELETE FROM todo_revisions WHERE created_at < now() - $1::integer * INTERVAL '1 day' AND NOT EXISTS (SELECT 1 FROM todos WHERE todos.id = todo_revisions.todo_id)"#, days)
+            .execute(pool)
+            .await?
+            .rows_affected();
+    }
+    Ok(deleted)
+}
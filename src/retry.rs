@@ -0,0 +1,67 @@
+//! Retrying of database calls failing because of brief Postgres blips, so they don't surface
+//! as internal errors. Only calls that are safe to repeat as a whole should be retried.
+
+use actix_web::rt;
+use rand::Rng;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::future::Future;
+use std::time::Duration;
+
+const MAX_ATTEMPTS: u32 = 3;
+const BASE_DELAY: Duration = Duration::from_millis(50);
+
+/// SQLSTATEs of errors that are expected to go away when the statement is simply run again.
+const TRANSIENT_CODES: &[&str] = &[
+    "40001", // serialization_failure
+    "40P01", // deadlock_detected
+    "57P01", // admin_shutdown
+    "08000", // connection_exception
+    "08003", // connection_does_not_exist
+    "08006", // connection_failure
+];
+
+pub fn is_transient(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Io(_) => true,
+        sqlx::Error::Database(e) => e
+            .code()
+            .map(|code| TRANSIENT_CODES.contains(&code.as_ref()))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Exponential backoff with full jitter, so retries of concurrent requests don't line up.
+fn backoff(attempt: u32) -> Duration {
+    let max = BASE_DELAY * 2u32.pow(attempt - 1);
+    let millis = rand::thread_rng().gen_range(0..=max.as_millis() as u64);
+    Duration::from_millis(millis)
+}
+
+/// Runs `operation`, repeating it up to `MAX_ATTEMPTS` times while it fails with a transient
+/// error.
+pub async fn retry<T, F, Fut>(mut operation: F) -> Result<T, sqlx::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Err(e) if attempt < MAX_ATTEMPTS && is_transient(&e) => {
+                warn!(
+                    "Retrying after a transient database error (attempt {}): {}",
+                    attempt, e
+                );
+                rt::time::sleep(backoff(attempt)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Starts a transaction, retrying when no healthy connection could be obtained.
+pub async fn begin(pool: &PgPool) -> Result<Transaction<'static, Postgres>, sqlx::Error> {
+    retry(|| pool.begin()).await
+}
@@ -0,0 +1,114 @@
+use actix_web::rt;
+use chrono::{DateTime, Utc};
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+type JobFn = Box<dyn Fn() -> LocalBoxFuture<'static, anyhow::Result<()>>>;
+
+struct ScheduledJob {
+    name: &'static str,
+    every: Duration,
+    run: JobFn,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct JobMetrics {
+    pub runs: u64,
+    pub failures: u64,
+    pub last_duration_ms: Option<u128>,
+    pub last_run_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+/// Metrics of all registered jobs, shared between the jobs and whoever wants to report them.
+#[derive(Clone, Default)]
+pub struct JobsMetrics(Arc<Mutex<BTreeMap<&'static str, JobMetrics>>>);
+
+impl JobsMetrics {
+    fn record(
+        &self,
+        name: &'static str,
+        duration: Duration,
+        result: &anyhow::Result<()>,
+    ) -> JobMetrics {
+        let mut jobs = self.0.lock().unwrap();
+        let metrics = jobs.entry(name).or_insert_with(JobMetrics::default);
+        metrics.runs += 1;
+        metrics.last_duration_ms = Some(duration.as_millis());
+        metrics.last_run_at = Some(Utc::now());
+        metrics.last_error = match result {
+            Ok(()) => None,
+            Err(e) => {
+                metrics.failures += 1;
+                Some(format!("{:#}", e))
+            }
+        };
+        metrics.clone()
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<&'static str, JobMetrics> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Runs registered background jobs at fixed intervals, so features needing periodic work don't
+/// spawn their own ad-hoc tasks. A failing run is logged and retried on the next tick.
+#[derive(Default)]
+pub struct Scheduler {
+    jobs: Vec<ScheduledJob>,
+    metrics: JobsMetrics,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler::default()
+    }
+
+    /// Metrics of the jobs, updated after every run once the scheduler is started.
+    pub fn metrics(&self) -> JobsMetrics {
+        self.metrics.clone()
+    }
+
+    pub fn every<F, Fut>(&mut self, name: &'static str, every: Duration, job: F) -> &mut Self
+    where
+        F: Fn() -> Fut + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + 'static,
+    {
+        self.jobs.push(ScheduledJob {
+            name,
+            every,
+            run: Box::new(move || job().boxed_local()),
+        });
+        self
+    }
+
+    pub fn start(self) {
+        for job in self.jobs {
+            let metrics = self.metrics.clone();
+            info!("Scheduling job {} every {:?}", job.name, job.every);
+            rt::spawn(async move {
+                let mut interval = rt::time::interval(job.every);
+                loop {
+                    interval.tick().await;
+                    let started = Instant::now();
+                    let result = (job.run)().await;
+                    let stats = metrics.record(job.name, started.elapsed(), &result);
+                    match result {
+                        Ok(()) => debug!(
+                            "Job {} finished in {:?} ({} runs, {} failures)",
+                            job.name,
+                            started.elapsed(),
+                            stats.runs,
+                            stats.failures
+                        ),
+                        Err(e) => error!("Job {} failed: {:#}", job.name, e),
+                    }
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use crate::crypto;
+use crate::error::Error;
+use crate::highlight::Highlighter;
+use crate::pagination::Page;
+use crate::query::{self, Query};
+use crate::{todos_etag, RoutingService, Todo, TodosList};
+use actix_web::{route, web};
+use futures_util::stream::StreamExt;
+use serde::Deserialize;
+use sqlx::PgPool;
+
+#[derive(Deserialize)]
+pub struct SearchParams {
+    q: String,
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+/// Lists the todos matching a query like `completed:false "buy milk"`, see `query` for the syntax.
+#[route("/todos/search", method = "GET", method = "HEAD")]
+pub async fn search_todos_handler(
+    params: web::Query<SearchParams>,
+    pool: web::Data<PgPool>,
+    routing: RoutingService,
+) -> Result<TodosList, Error> {
+    let SearchParams { q, page, per_page } = params.into_inner();
+    let page = Page::from_params(page, per_page)?;
+    let query = query::parse(&q)?;
+
+    matching_todos(pool.get_ref(), routing, &query, page).await
+}
+
+/// Streams the todos matching a query, shared by searches and saved filters. The parts of the
+/// titles matching the query's text are highlighted.
+pub async fn matching_todos(
+    pool: &PgPool,
+    routing: RoutingService,
+    query: &Query,
+    page: Option<Page>,
+) -> Result<TodosList, Error> {
+    let condition = query.compile(1)?;
+
+    let etag = todos_etag(pool).await?;
+
+    let count = format!("SELECT COUNT(*) FROM todos WHERE {}", condition.sql);
+    let total = sqlx::query_scalar_with::<_, i64, _>(&count, condition.arguments())
+        .fetch_one(pool)
+        .await?;
+
+    let pool = pool.clone();
+    let mut sql = format!(
+        "SELECT * FROM todos WHERE {} ORDER BY starred DESC, id",
+        condition.sql
+    );
+    if let Some(page) = page {
+        sql.push_str(&format!(" LIMIT {} OFFSET {}", page.limit(), page.offset()));
+    }
+    let todos = Box::pin(async_stream::stream! {
+        let mut rows = sqlx::query_as_with::<_, Todo, _>(&sql, condition.arguments()).fetch(&pool);
+        while let Some(todo) = rows.next().await {
+            yield todo.and_then(crypto::decrypt);
+        }
+    });
+
+    Ok(TodosList {
+        routing,
+        highlighter: Highlighter::new(query.text_terms()),
+        etag,
+        total,
+        page,
+        todos,
+    })
+}
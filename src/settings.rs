@@ -0,0 +1,172 @@
+//! Settings that can change without a restart. They're read from the environment at startup and
+//! read again on SIGHUP or `POST /admin/reload`, with the file at `SETTINGS_FILE` (`KEY=value`
+//! lines) taking precedence, since the environment of a running process can't be changed.
+//! Requests see either the old or the new settings as a whole, never a mix of both.
+
+use crate::features::FeatureFlags;
+use crate::request_id;
+use actix_web::rt;
+use actix_web::rt::signal::unix::{signal, SignalKind};
+use log::LevelFilter;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::sync::{Arc, RwLock};
+
+pub struct Reloadable {
+    /// The most verbose level logged. Without `RUST_LOG` it's the only level that applies,
+    /// otherwise it can only make the levels set by `RUST_LOG` less verbose.
+    pub log_level: Option<LevelFilter>,
+    /// Origins allowed to make CORS requests, any origin when `None`.
+    pub cors_origins: Option<Vec<String>>,
+    pub feature_flags: FeatureFlags,
+}
+
+impl Reloadable {
+    fn load() -> Result<Self, Vec<String>> {
+        let file = match env::var("SETTINGS_FILE") {
+            Ok(path) => read_file(&path).map_err(|e| vec![e])?,
+            Err(_) => HashMap::new(),
+        };
+        let var = |name: &str| file.get(name).cloned().or_else(|| env::var(name).ok());
+
+        let mut errors = Vec::new();
+        let log_level = match var("LOG_LEVEL") {
+            Some(level) => level.parse().map(Some).unwrap_or_else(|_| {
+                errors.push(format!(
+                    "LOG_LEVEL can't be {}, it needs to be off, error, warn, info, debug or trace",
+                    level
+                ));
+                None
+            }),
+            None => None,
+        };
+        let cors_origins = var("CORS_ORIGINS").map(|origins| {
+            origins
+                .split(',')
+                .map(|origin| origin.trim().trim_end_matches('/').to_owned())
+                .filter(|origin| !origin.is_empty())
+                .collect()
+        });
+        let feature_flags = FeatureFlags::parse(&var("FEATURE_FLAGS").unwrap_or_default())
+            .map_err(|e| errors.push(format!("FEATURE_FLAGS is invalid: {}", e)))
+            .ok();
+
+        match feature_flags {
+            Some(feature_flags) if errors.is_empty() => Ok(Reloadable {
+                log_level,
+                cors_origins,
+                feature_flags,
+            }),
+            _ => Err(errors),
+        }
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        match &self.cors_origins {
+            Some(origins) => origins.iter().any(|allowed| allowed == origin),
+            None => true,
+        }
+    }
+}
+
+fn read_file(path: &str) -> Result<HashMap<String, String>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("SETTINGS_FILE {} can't be read: {}", path, e))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+        .collect())
+}
+
+/// Sets up logging so that `LOG_LEVEL` can raise the level later on. Without `RUST_LOG` the
+/// logger lets everything through and the level is only limited by `log::max_level`, which
+/// starts at the usual default of errors only. Lines logged while handling a request carry its
+/// id.
+pub fn init_logger() {
+    let mut logger = env_logger::Builder::from_default_env();
+    logger.format(|buf, record| {
+        let request = request_id::current()
+            .map(|id| format!(" {}", id))
+            .unwrap_or_default();
+        writeln!(
+            buf,
+            "[{} {:<5} {}{}] {}",
+            buf.timestamp(),
+            record.level(),
+            record.target(),
+            request,
+            record.args()
+        )
+    });
+    if env::var_os("RUST_LOG").is_some() {
+        logger.init();
+    } else {
+        logger.filter_level(LevelFilter::Trace).init();
+        log::set_max_level(LevelFilter::Error);
+    }
+}
+
+/// A handle on the current settings, shared through app data.
+pub struct Settings {
+    current: RwLock<Arc<Reloadable>>,
+    /// The level `RUST_LOG` set up, restored when `LOG_LEVEL` is removed.
+    initial_log_level: LevelFilter,
+}
+
+impl Settings {
+    pub fn load() -> Result<Self, Vec<String>> {
+        let settings = Settings {
+            current: RwLock::new(Arc::new(Reloadable::load()?)),
+            initial_log_level: log::max_level(),
+        };
+        settings.apply();
+        Ok(settings)
+    }
+
+    pub fn current(&self) -> Arc<Reloadable> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Loads the settings again, keeping the current ones when the new ones aren't valid.
+    pub fn reload(&self) -> Result<(), Vec<String>> {
+        let reloaded = Reloadable::load()?;
+        *self.current.write().unwrap() = Arc::new(reloaded);
+        self.apply();
+        info!("Reloaded the settings");
+        Ok(())
+    }
+
+    fn apply(&self) {
+        let log_level = self.current().log_level;
+        log::set_max_level(log_level.unwrap_or(self.initial_log_level));
+    }
+}
+
+pub fn reload_on_signal(settings: Arc<Settings>) {
+    rt::spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                error!(
+                    "Failed to listen for SIGHUP, settings can't be reloaded with it: {}",
+                    e
+                );
+                return;
+            }
+        };
+
+        while hangups.recv().await.is_some() {
+            if let Err(errors) = settings.reload() {
+                error!(
+                    "Kept the current settings, the new ones are invalid: {}",
+                    errors.join("; ")
+                );
+            }
+        }
+    });
+}
@@ -0,0 +1,101 @@
+use crate::validation::ValidationErrors;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::str::FromStr;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Todo,
+    InProgress,
+    Done,
+    Blocked,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Todo => "todo",
+            Status::InProgress => "in_progress",
+            Status::Done => "done",
+            Status::Blocked => "blocked",
+        }
+    }
+
+    /// The status implied by setting `completed` through the older boolean field.
+    pub fn with_completed(self, completed: bool) -> Status {
+        match (self, completed) {
+            (_, true) => Status::Done,
+            (Status::Done, false) => Status::Todo,
+            (status, false) => status,
+        }
+    }
+}
+
+impl FromStr for Status {
+    type Err = String;
+
+    fn from_str(status: &str) -> Result<Self, Self::Err> {
+        match status.trim() {
+            "todo" => Ok(Status::Todo),
+            "in_progress" => Ok(Status::InProgress),
+            "done" => Ok(Status::Done),
+            "blocked" => Ok(Status::Blocked),
+            status => Err(format!("unknown status `{}`", status)),
+        }
+    }
+}
+
+/// Transitions allowed unless configured otherwise, blocked todos have to be unblocked before
+/// they can be finished.
+const DEFAULT_TRANSITIONS: &str = "todo>in_progress,todo>done,todo>blocked,\
+    in_progress>todo,in_progress>done,in_progress>blocked,\
+    blocked>todo,blocked>in_progress,\
+    done>todo,done>in_progress";
+
+/// The status transitions todos are allowed to go through.
+#[derive(Debug, Clone)]
+pub struct Workflow(HashSet<(Status, Status)>);
+
+impl Workflow {
+    /// Parses a comma separated list of `from>to` pairs, like `todo>done,done>todo`.
+    pub fn parse(transitions: &str) -> Result<Workflow, String> {
+        transitions
+            .split(',')
+            .filter(|transition| !transition.trim().is_empty())
+            .map(|transition| {
+                let mut statuses = transition.splitn(2, '>');
+                let from = statuses.next().unwrap_or("").parse()?;
+                let to = statuses
+                    .next()
+                    .ok_or_else(|| format!("`{}` needs to look like from>to", transition.trim()))?
+                    .parse()?;
+                Ok((from, to))
+            })
+            .collect::<Result<HashSet<(Status, Status)>, String>>()
+            .map(Workflow)
+    }
+
+    pub fn allows(&self, from: Status, to: Status) -> bool {
+        from == to || self.0.contains(&(from, to))
+    }
+
+    pub fn validate(&self, errors: &mut ValidationErrors, from: Status, to: Status) {
+        if !self.allows(from, to) {
+            errors.add(
+                "status",
+                "invalid_transition",
+                &[
+                    ("from", from.as_str().to_owned()),
+                    ("to", to.as_str().to_owned()),
+                ],
+            );
+        }
+    }
+}
+
+impl Default for Workflow {
+    fn default() -> Self {
+        Workflow::parse(DEFAULT_TRANSITIONS).expect("the default transitions are valid")
+    }
+}
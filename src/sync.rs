@@ -0,0 +1,777 @@
+use crate::crypto;
+use crate::dependencies;
+use crate::error::Error;
+use crate::history::{self, Action};
+use crate::ids::{self, TodoRef};
+use crate::negotiation::{self, Body, Decode};
+use crate::poll::{self, ChangeFeed};
+use crate::transaction::Tx;
+use crate::validation::{
+    normalize_color, normalize_title, validate_color, validate_order, validate_title,
+    ValidationErrors,
+};
+use crate::{make_room_for_order, next_order, RoutingService, Todo, TodoPresenter, TodoServices};
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::HashMap;
+use tokio::time::Instant;
+
+const SYNC_TOKEN_LENGTH: usize = 40;
+const SYNC_TOKEN_TTL_DAYS: i64 = 30;
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    /// Either a cursor returned by a previous call or an RFC 3339 timestamp.
+    since: Option<String>,
+    /// A sync token returned by a previous call, takes precedence over `since`.
+    token: Option<String>,
+    /// Identifies the client tokens are issued to, without it only the cursor is returned.
+    device_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Changes {
+    changes: Vec<TodoPresenter>,
+    deleted: Vec<i64>,
+    cursor: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+enum Since {
+    Cursor(i64),
+    Timestamp(DateTime<Utc>),
+}
+
+impl Since {
+    fn parse(since: Option<&str>) -> Result<Since, Error> {
+        let since = match since {
+            Some(since) => since,
+            None => return Ok(Since::Cursor(0)),
+        };
+
+        if let Ok(cursor) = since.parse() {
+            return Ok(Since::Cursor(cursor));
+        }
+        DateTime::parse_from_rfc3339(since)
+            .map(|since| Since::Timestamp(since.with_timezone(&Utc)))
+            .map_err(|_| Error::InvalidQuery {
+                reason: "since needs to be a cursor or an RFC 3339 timestamp".to_owned(),
+            })
+    }
+}
+
+/// Resolves a sync token to the cursor it was issued for. Tokens that expired, or whose
+/// revisions were already pruned, can't be synced from and the client has to start over.
+async fn token_cursor(pool: &PgPool, token: &str) -> Result<i64, Error> {
+    let cursor = sqlx::query_scalar!(
+        r#"SELECT cursor FROM sync_tokens WHERE token = $1 AND expires_at > now()"#,
+        token
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(Error::SyncTokenExpired)?;
+
+    let oldest = sqlx::query_scalar!(r#"SELECT MIN(id) FROM todo_revisions"#)
+        .fetch_one(pool)
+        .await?;
+    match oldest {
+        Some(oldest) if oldest > cursor + 1 => Err(Error::SyncTokenExpired),
+        _ => Ok(cursor),
+    }
+}
+
+/// Issues a new token for the device, replacing the one it had before.
+async fn issue_token(pool: &PgPool, device_id: &str, cursor: i64) -> Result<String, sqlx::Error> {
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SYNC_TOKEN_LENGTH)
+        .map(char::from)
+        .collect();
+    let expires_at = Utc::now() + Duration::days(SYNC_TOKEN_TTL_DAYS);
+
+    sqlx::query!(r#"INSERT INTO sync_tokens (token, device_id, cursor, expires_at) VALUES ($1, $2, $3, $4) ON CONFLICT (device_id) DO UPDATE SET token = $1, cursor = $3, expires_at = $4"#, token, device_id, cursor, expires_at)
+        .execute(pool)
+        .await?;
+
+    Ok(token)
+}
+
+/// Removes sync tokens that can't be used anymore.
+pub async fn delete_expired_tokens(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(r#"DELETE FROM sync_tokens WHERE expires_at <= now()"#)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+impl ChangesQuery {
+    async fn since(&self, pool: &PgPool) -> Result<Since, Error> {
+        match &self.token {
+            Some(token) => Ok(Since::Cursor(token_cursor(pool, token).await?)),
+            None => Since::parse(self.since.as_deref()),
+        }
+    }
+
+    async fn respond(
+        &self,
+        req: &HttpRequest,
+        pool: &PgPool,
+        (changes, deleted, cursor): (Vec<TodoPresenter>, Vec<i64>, i64),
+    ) -> Result<HttpResponse, Error> {
+        // every device has a single token, clients sharing one would keep replacing each other's
+        let token = match &self.device_id {
+            Some(device_id) => Some(issue_token(pool, device_id, cursor).await?),
+            None => None,
+        };
+
+        let changes = Changes {
+            changes,
+            deleted,
+            cursor,
+            token,
+        };
+        Ok(negotiation::respond(req, HttpResponse::Ok(), &changes))
+    }
+}
+
+/// The todos created or updated since the given point, the ids of the deleted ones and the
+/// cursor to continue from.
+async fn changes_since(
+    pool: &PgPool,
+    routing: &RoutingService,
+    since: Since,
+) -> Result<(Vec<TodoPresenter>, Vec<i64>, i64), Error> {
+    // capping the range at the current last revision keeps the cursor consistent with the
+    // changes returned, even when new revisions are written in the meantime
+    let cursor =
+        sqlx::query_scalar!(r#"SELECT COALESCE(MAX(id), 0) AS "cursor!" FROM todo_revisions"#)
+            .fetch_one(pool)
+            .await?;
+    let (after_id, after_time) = match since {
+        Since::Cursor(cursor) => (Some(cursor), None),
+        Since::Timestamp(timestamp) => (None, Some(timestamp)),
+    };
+    let touched = sqlx::query_scalar!(r#"SELECT DISTINCT todo_id FROM todo_revisions WHERE id <= $1 AND ($2::bigint IS NULL OR id > $2) AND ($3::timestamptz IS NULL OR created_at > $3)"#, cursor, after_id, after_time)
+        .fetch_all(pool)
+        .await?;
+
+    let todos = sqlx::query_as!(
+        Todo,
+        r#"SELECT * FROM todos WHERE id = ANY($1) ORDER BY id"#,
+        &touched
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(crypto::decrypt)
+    .collect::<Result<Vec<_>, _>>()?;
+    let deleted = touched
+        .into_iter()
+        .filter(|id| !todos.iter().any(|todo| todo.id == *id))
+        .collect();
+    let changes = todos
+        .into_iter()
+        .map(|todo| routing.present(todo))
+        .collect();
+    Ok((changes, deleted, cursor))
+}
+
+/// Returns todos created or updated since the given point along with ids of deleted ones, so
+/// clients can sync incrementally. The returned token (or cursor) is meant for the next call.
+#[get("/todos/changes")]
+pub async fn todos_changes_handler(
+    req: HttpRequest,
+    query: web::Query<ChangesQuery>,
+    pool: web::Data<PgPool>,
+    routing: RoutingService,
+) -> Result<HttpResponse, Error> {
+    let since = query.since(pool.get_ref()).await?;
+    let changes = changes_since(pool.get_ref(), &routing, since).await?;
+    query.respond(&req, pool.get_ref(), changes).await
+}
+
+#[derive(Deserialize)]
+struct PollQuery {
+    #[serde(flatten)]
+    changes: ChangesQuery,
+    /// How long to wait for changes, like `30s`.
+    timeout: Option<String>,
+}
+
+/// Like `/todos/changes`, but waits for changes when there are none yet, up to the timeout.
+/// Meant for clients behind proxies that don't let WebSockets or server-sent events through,
+/// which poll again with the returned token right away.
+#[get("/todos/poll")]
+pub async fn todos_poll_handler(
+    req: HttpRequest,
+    query: web::Query<PollQuery>,
+    pool: web::Data<PgPool>,
+    routing: RoutingService,
+    feed: web::Data<ChangeFeed>,
+) -> Result<HttpResponse, Error> {
+    let deadline = Instant::now() + poll::parse_timeout(query.timeout.as_deref())?;
+    let mut since = query.changes.since(pool.get_ref()).await?;
+    let changes = loop {
+        let (changes, deleted, cursor) = changes_since(pool.get_ref(), &routing, since).await?;
+        if !changes.is_empty() || !deleted.is_empty() || !feed.wait_past(cursor, deadline).await {
+            break (changes, deleted, cursor);
+        }
+        since = Since::Cursor(cursor);
+    };
+    query.changes.respond(&req, pool.get_ref(), changes).await
+}
+
+/// A change made by a client while offline, along with the version it was based on.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Operation {
+    Create {
+        /// A client-side identifier, echoed back so the client can match the created todo.
+        client_id: Option<String>,
+        title: String,
+        #[serde(default)]
+        completed: bool,
+        order: Option<f64>,
+    },
+    Update {
+        #[serde(deserialize_with = "ids::deserialize_json")]
+        id: TodoRef,
+        base_version: i64,
+        changed_at: Option<DateTime<Utc>>,
+        #[serde(default)]
+        fields: FieldChanges,
+    },
+    Delete {
+        #[serde(deserialize_with = "ids::deserialize_json")]
+        id: TodoRef,
+        base_version: i64,
+        changed_at: Option<DateTime<Utc>>,
+    },
+}
+
+#[derive(Deserialize, Default)]
+struct FieldChanges {
+    title: Option<String>,
+    completed: Option<bool>,
+    order: Option<f64>,
+    starred: Option<bool>,
+    #[serde(default, deserialize_with = "crate::deserialize_some")]
+    color: Option<Option<String>>,
+    #[serde(default, deserialize_with = "crate::deserialize_some")]
+    due_at: Option<Option<DateTime<Utc>>>,
+}
+
+#[derive(Deserialize)]
+struct SyncRequest {
+    operations: Vec<Operation>,
+}
+
+impl Decode for SyncRequest {}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum OperationStatus {
+    /// Applied as sent, nothing changed on the server in the meantime.
+    Applied,
+    /// Applied after resolving concurrent server changes, see the conflicts.
+    Merged,
+    /// Not applied, the todo in the result is the current server state if it still exists.
+    Rejected,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Winner {
+    Client,
+    Server,
+}
+
+#[derive(Serialize)]
+struct FieldConflict {
+    field: &'static str,
+    winner: Winner,
+}
+
+#[derive(Serialize)]
+struct OperationResult {
+    status: OperationStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    todo: Option<TodoPresenter>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    conflicts: Vec<FieldConflict>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<ValidationErrors>,
+    /// Why a valid operation was rejected, when the todo alone doesn't tell.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+impl OperationResult {
+    fn new(status: OperationStatus, todo: Option<TodoPresenter>) -> Self {
+        OperationResult {
+            status,
+            client_id: None,
+            todo,
+            conflicts: Vec::new(),
+            errors: None,
+            reason: None,
+        }
+    }
+
+    fn invalid(errors: ValidationErrors) -> Self {
+        OperationResult {
+            errors: Some(errors),
+            ..OperationResult::new(OperationStatus::Rejected, None)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SyncResponse {
+    results: Vec<OperationResult>,
+    cursor: i64,
+}
+
+/// Returns when each field of a todo was last changed on the server after `base_version`.
+async fn server_changes_since(
+    tx: &mut Transaction<'_, Postgres>,
+    id: i64,
+    base_version: i64,
+) -> Result<HashMap<String, DateTime<Utc>>, sqlx::Error> {
+    let revisions = sqlx::query!(r#"SELECT before, after, created_at FROM todo_revisions WHERE todo_id = $1 AND (after->>'version')::bigint > $2 ORDER BY id"#, id, base_version)
+        .fetch_all(&mut *tx)
+        .await?;
+
+    let mut changes = HashMap::new();
+    for mut revision in revisions {
+        // encrypted titles differ even when they weren't changed
+        for snapshot in revision.before.iter_mut().chain(revision.after.iter_mut()) {
+            crypto::decrypt_snapshot(snapshot)?;
+        }
+        for field in history::diff(revision.before.as_ref(), revision.after.as_ref()).keys() {
+            changes.insert(field.clone(), revision.created_at);
+        }
+    }
+    Ok(changes)
+}
+
+/// The todo an operation refers to, locked until the sync is over, `None` when it's gone.
+async fn locked_todo(
+    tx: &mut Transaction<'_, Postgres>,
+    todo: TodoRef,
+) -> Result<Option<Todo>, Error> {
+    let id = match ids::resolve(&mut *tx, todo).await {
+        Ok(id) => id,
+        Err(Error::NotFound) => return Ok(None),
+        Err(error) => return Err(error),
+    };
+    let todo = sqlx::query_as!(Todo, r#"SELECT * FROM todos WHERE id = $1 FOR UPDATE"#, id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .map(crypto::decrypt)
+        .transpose()?;
+    Ok(todo)
+}
+
+async fn apply_create(
+    tx: &mut Transaction<'_, Postgres>,
+    title: &str,
+    completed: bool,
+    order: Option<f64>,
+) -> Result<Todo, sqlx::Error> {
+    let order = match order {
+        Some(order) => {
+            make_room_for_order(tx, order, None).await?;
+            order
+        }
+        None => next_order(tx).await?,
+    };
+    let todo = sqlx::query_as!(Todo, r#"INSERT INTO todos (title, completed, completed_at, status, "order") VALUES($1, $2, CASE WHEN $2 THEN now() END, CASE WHEN $2 THEN 'done' ELSE 'todo' END, $3) RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, crypto::encrypt_title(&normalize_title(title)), completed, order)
+        .fetch_one(&mut *tx)
+        .await
+        .and_then(crypto::decrypt)?;
+    history::record(tx, Action::Create, None, Some(&todo)).await?;
+    Ok(todo)
+}
+
+/// Merges the client's field changes into the current todo. Fields only one side changed are
+/// kept from that side, fields both sides changed go to whoever changed them last. Status changes
+/// go through the same workflow and blocker checks as when patching a todo, and nothing is
+/// written if they fail.
+async fn apply_update(
+    tx: &mut Transaction<'_, Postgres>,
+    services: &TodoServices,
+    current: Todo,
+    base_version: i64,
+    changed_at: DateTime<Utc>,
+    fields: FieldChanges,
+) -> Result<(Todo, Vec<FieldConflict>), Error> {
+    let server_changes = server_changes_since(tx, current.id, base_version).await?;
+    let mut conflicts = Vec::new();
+    let mut client_wins = |field: &'static str| match server_changes.get(field) {
+        Some(server_changed_at) if *server_changed_at >= changed_at => {
+            conflicts.push(FieldConflict {
+                field,
+                winner: Winner::Server,
+            });
+            false
+        }
+        Some(_) => {
+            conflicts.push(FieldConflict {
+                field,
+                winner: Winner::Client,
+            });
+            true
+        }
+        None => true,
+    };
+
+    let mut todo = current.clone();
+    if let Some(title) = fields.title {
+        if client_wins("title") {
+            todo.title = normalize_title(&title);
+        }
+    }
+    if let Some(completed) = fields.completed {
+        if client_wins("completed") && completed != todo.completed {
+            let status = todo.status().with_completed(completed);
+            let mut errors = ValidationErrors::default();
+            services
+                .workflow
+                .validate(&mut errors, todo.status(), status);
+            errors.into_result()?;
+            if completed && services.dependencies.enforce {
+                dependencies::ensure_unblocked(tx, todo.id).await?;
+            }
+            todo.status = status.as_str().to_owned();
+            todo.completed = completed;
+            todo.completed_at = if completed { Some(changed_at) } else { None };
+        }
+    }
+    if let Some(order) = fields.order {
+        if client_wins("order") && order != todo.order {
+            make_room_for_order(tx, order, Some(todo.id)).await?;
+            todo.order = order;
+        }
+    }
+    if let Some(starred) = fields.starred {
+        if client_wins("starred") {
+            todo.starred = starred;
+        }
+    }
+    if let Some(color) = fields.color {
+        if client_wins("color") {
+            todo.color = color.as_deref().map(normalize_color);
+        }
+    }
+    if let Some(due_at) = fields.due_at {
+        if client_wins("due_at") {
+            todo.due_at = due_at;
+        }
+    }
+
+    let todo = sqlx::query_as!(Todo, r#"UPDATE todos SET title = $1, completed = $2, "order" = $3, completed_at = $4, starred = $5, color = $6, due_at = $7, status = $8, version = version + 1 WHERE id = $9 RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, crypto::encrypt_title(&todo.title), todo.completed, todo.order, todo.completed_at, todo.starred, todo.color, todo.due_at, todo.status, todo.id)
+        .fetch_one(&mut *tx)
+        .await
+        .and_then(crypto::decrypt)?;
+    history::record(tx, Action::Update, Some(&current), Some(&todo)).await?;
+    Ok((todo, conflicts))
+}
+
+fn validate_fields(
+    title: Option<&str>,
+    order: Option<f64>,
+    color: Option<&str>,
+) -> Result<(), ValidationErrors> {
+    let mut errors = ValidationErrors::default();
+    if let Some(title) = title {
+        validate_title(&mut errors, title);
+    }
+    if let Some(order) = order {
+        validate_order(&mut errors, order);
+    }
+    if let Some(color) = color {
+        validate_color(&mut errors, color);
+    }
+    errors.into_result()
+}
+
+/// Applies a batch of offline operations in a single transaction. Operations based on a stale
+/// version are merged field by field instead of being rejected outright.
+#[post("/sync")]
+pub async fn sync_handler(
+    req: HttpRequest,
+    request: Body<SyncRequest>,
+    tx: Tx,
+    routing: RoutingService,
+    services: web::Data<TodoServices>,
+) -> Result<HttpResponse, Error> {
+    let present = |todo: Todo| routing.present(todo);
+
+    let mut results = Vec::new();
+    let mut tx = tx.lock().await?;
+    for operation in request.into_inner().operations {
+        let result = match operation {
+            Operation::Create {
+                client_id,
+                title,
+                completed,
+                order,
+            } => match validate_fields(Some(title.as_str()), order, None) {
+                Ok(()) => {
+                    let todo = apply_create(&mut tx, &title, completed, order).await?;
+                    OperationResult {
+                        client_id,
+                        ..OperationResult::new(OperationStatus::Applied, Some(present(todo)))
+                    }
+                }
+                Err(errors) => OperationResult {
+                    client_id,
+                    ..OperationResult::invalid(errors)
+                },
+            },
+            Operation::Update {
+                id,
+                base_version,
+                changed_at,
+                fields,
+            } => {
+                let color = fields.color.as_ref().and_then(|color| color.as_deref());
+                if let Err(errors) = validate_fields(fields.title.as_deref(), fields.order, color) {
+                    results.push(OperationResult::invalid(errors));
+                    continue;
+                }
+                let current = locked_todo(&mut tx, id).await?;
+                match current {
+                    Some(current) => {
+                        let changed_at = changed_at.unwrap_or_else(Utc::now);
+                        let update = apply_update(
+                            &mut tx,
+                            &services,
+                            current.clone(),
+                            base_version,
+                            changed_at,
+                            fields,
+                        )
+                        .await;
+                        match update {
+                            Ok((todo, conflicts)) => {
+                                let status = if conflicts.is_empty() {
+                                    OperationStatus::Applied
+                                } else {
+                                    OperationStatus::Merged
+                                };
+                                OperationResult {
+                                    conflicts,
+                                    ..OperationResult::new(status, Some(present(todo)))
+                                }
+                            }
+                            Err(Error::ValidationFailed { errors }) => {
+                                OperationResult::invalid(errors)
+                            }
+                            Err(Error::Conflict { reason }) => OperationResult {
+                                reason: Some(reason),
+                                ..OperationResult::new(
+                                    OperationStatus::Rejected,
+                                    Some(present(current)),
+                                )
+                            },
+                            Err(error) => return Err(error),
+                        }
+                    }
+                    // deleted on the server, the deletion wins
+                    None => OperationResult::new(OperationStatus::Rejected, None),
+                }
+            }
+            Operation::Delete {
+                id,
+                base_version,
+                changed_at,
+            } => {
+                let current = locked_todo(&mut tx, id).await?;
+                match current {
+                    Some(current) => {
+                        let changed_at = changed_at.unwrap_or_else(Utc::now);
+                        let server_changes =
+                            server_changes_since(&mut tx, current.id, base_version).await?;
+                        let updated_later = server_changes
+                            .values()
+                            .any(|server_changed_at| *server_changed_at >= changed_at);
+                        if updated_later {
+                            OperationResult::new(OperationStatus::Rejected, Some(present(current)))
+                        } else {
+                            sqlx::query!(r#"DELETE FROM todos WHERE id = $1"#, current.id)
+                                .execute(&mut *tx)
+                                .await?;
+                            history::record(&mut tx, Action::Delete, Some(&current), None).await?;
+                            OperationResult::new(OperationStatus::Applied, None)
+                        }
+                    }
+                    None => OperationResult::new(OperationStatus::Applied, None),
+                }
+            }
+        };
+        results.push(result);
+    }
+
+    let cursor =
+        sqlx::query_scalar!(r#"SELECT COALESCE(MAX(id), 0) AS "cursor!" FROM todo_revisions"#)
+            .fetch_one(&mut *tx)
+            .await?;
+
+    let response = SyncResponse { results, cursor };
+    Ok(negotiation::respond(&req, HttpResponse::Ok(), &response))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{routes, test_support};
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+
+    /// A sync request updating a todo the client last saw as `todo`.
+    fn update(todo: &Value, changed_at: &str, fields: Value) -> Value {
+        json!({
+            "operations": [{
+                "op": "update",
+                "id": todo["id"],
+                "base_version": todo["version"],
+                "changed_at": changed_at,
+                "fields": fields,
+            }]
+        })
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs a database, see test_support"]
+    async fn updates_merge_with_concurrent_server_changes() {
+        let pool = test_support::pool().await;
+        let app = test::init_service(
+            App::new()
+                .configure(test_support::app_data(pool))
+                .configure(routes),
+        )
+        .await;
+
+        // the client saw the todo before it was renamed on the server
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .set_json(&json!({ "title": "Buy milk" }))
+            .to_request();
+        let todo: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        let req = test::TestRequest::patch()
+            .uri(&format!("/todos/{}", todo["id"]))
+            .set_json(&json!({ "title": "Buy oat milk" }))
+            .to_request();
+        test::call_service(&app, req).await;
+
+        // the server renamed it later, its title wins and the client's star is kept
+        let fields = json!({ "title": "Buy soy milk", "starred": true });
+        let req = test::TestRequest::post()
+            .uri("/sync")
+            .set_json(&update(&todo, "2000-01-01T00:00:00Z", fields))
+            .to_request();
+        let response: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        let result = &response["results"][0];
+        assert_eq!(result["status"], "merged");
+        assert_eq!(result["todo"]["title"], "Buy oat milk");
+        assert_eq!(result["todo"]["starred"], true);
+        assert_eq!(
+            result["conflicts"],
+            json!([{ "field": "title", "winner": "server" }])
+        );
+
+        // the client renamed it later, its title wins
+        let fields = json!({ "title": "Buy soy milk" });
+        let req = test::TestRequest::post()
+            .uri("/sync")
+            .set_json(&update(&todo, "2100-01-01T00:00:00Z", fields))
+            .to_request();
+        let response: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        let result = &response["results"][0];
+        assert_eq!(result["status"], "merged");
+        assert_eq!(result["todo"]["title"], "Buy soy milk");
+        assert_eq!(
+            result["conflicts"],
+            json!([{ "field": "title", "winner": "client" }])
+        );
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs a database, see test_support"]
+    async fn updates_of_the_current_version_are_applied() {
+        let pool = test_support::pool().await;
+        let app = test::init_service(
+            App::new()
+                .configure(test_support::app_data(pool))
+                .configure(routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .set_json(&json!({ "title": "Buy milk" }))
+            .to_request();
+        let todo: Value = test::read_body_json(test::call_service(&app, req).await).await;
+
+        let fields = json!({ "title": "Buy oat milk", "completed": true });
+        let req = test::TestRequest::post()
+            .uri("/sync")
+            .set_json(&update(&todo, "2000-01-01T00:00:00Z", fields))
+            .to_request();
+        let response: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        let result = &response["results"][0];
+        assert_eq!(result["status"], "applied");
+        assert_eq!(result["todo"]["title"], "Buy oat milk");
+        assert_eq!(result["todo"]["completed"], true);
+        assert!(result.get("conflicts").is_none());
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs a database, see test_support"]
+    async fn updates_go_through_the_workflow() {
+        let pool = test_support::pool().await;
+        let app = test::init_service(
+            App::new()
+                .configure(test_support::app_data(pool))
+                .configure(routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .set_json(&json!({ "title": "Buy milk" }))
+            .to_request();
+        let todo: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        let req = test::TestRequest::patch()
+            .uri(&format!("/todos/{}", todo["id"]))
+            .set_json(&json!({ "status": "blocked" }))
+            .to_request();
+        let todo: Value = test::read_body_json(test::call_service(&app, req).await).await;
+
+        // blocked todos have to be unblocked before they can be finished
+        let fields = json!({ "title": "Buy oat milk", "completed": true });
+        let req = test::TestRequest::post()
+            .uri("/sync")
+            .set_json(&update(&todo, "2100-01-01T00:00:00Z", fields))
+            .to_request();
+        let response: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        let result = &response["results"][0];
+        assert_eq!(result["status"], "rejected");
+        assert!(result["errors"].is_object());
+
+        let req = test::TestRequest::patch()
+            .uri(&format!("/todos/{}", todo["id"]))
+            .set_json(&json!({}))
+            .to_request();
+        let todo: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(todo["title"], "Buy milk");
+        assert_eq!(todo["status"], "blocked");
+    }
+}
@@ -0,0 +1,318 @@
+//! A Telegram bot for the todo list, enabled by `TELEGRAM_BOT_TOKEN`. Telegram posts updates to
+//! `POST /integrations/telegram`, registered with the Bot API's `setWebhook` and the secret in
+//! `TELEGRAM_WEBHOOK_SECRET`, which Telegram sends back with every update.
+//!
+//! There are no accounts, everyone shares the one list, so chats stand in for users: only the
+//! chats listed in `TELEGRAM_CHATS` are answered. Any text sent to the bot becomes a todo,
+//! `/list` shows the open todos with a button completing each of them.
+
+use crate::admin::constant_time_eq;
+use crate::dependencies;
+use crate::error::Error;
+use crate::history::{self, Action};
+use crate::notifications::SlackNotifier;
+use crate::retry;
+use crate::status::Status;
+use crate::validation::{normalize_title, validate_title, ValidationErrors};
+use crate::{crypto, next_order, RoutingService, Todo, TodoServices};
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{PgPool, Postgres, Transaction};
+
+const API_URL: &str = "https://api.telegram.org";
+const SECRET_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+/// The most todos `/list` shows, a button each.
+const LIST_LIMIT: i64 = 20;
+const BUTTON_TITLE_LENGTH: usize = 40;
+const COMPLETE_PREFIX: &str = "complete:";
+const HELP: &str = "Send me anything to add it as a todo, or /list to see the open todos and \
+                    complete them.";
+
+pub struct TelegramSettings {
+    pub token: String,
+    pub webhook_secret: String,
+    pub chats: Vec<i64>,
+}
+
+pub struct TelegramBot {
+    client: reqwest::Client,
+    settings: Option<TelegramSettings>,
+}
+
+#[derive(Deserialize)]
+struct Update {
+    message: Option<Message>,
+    callback_query: Option<CallbackQuery>,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    message_id: i64,
+    chat: Chat,
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Chat {
+    id: i64,
+}
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    id: String,
+    message: Option<Message>,
+    data: Option<String>,
+}
+
+impl TelegramBot {
+    pub fn new(settings: Option<TelegramSettings>) -> Self {
+        TelegramBot {
+            client: reqwest::Client::new(),
+            settings,
+        }
+    }
+
+    /// Calls a Bot API method. Failures are only logged, Telegram would otherwise send the
+    /// update again and the change it made would be made twice.
+    async fn call(&self, token: &str, method: &str, body: Value) {
+        let url = format!("{}/bot{}/{}", API_URL, token, method);
+        let sent = self.client.post(&url).json(&body).send().await;
+        if let Err(e) = sent.and_then(|response| response.error_for_status()) {
+            warn!("Telegram's {} failed: {}", method, e.without_url());
+        }
+    }
+}
+
+/// Fails unless the request carries the webhook secret, pretending the endpoint doesn't exist
+/// when the bot isn't configured.
+fn authorize<'a>(req: &HttpRequest, bot: &'a TelegramBot) -> Result<&'a TelegramSettings, Error> {
+    let settings = bot.settings.as_ref().ok_or(Error::NotFound)?;
+    let given = req
+        .headers()
+        .get(SECRET_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(Error::Unauthorized)?;
+
+    if constant_time_eq(given.as_bytes(), settings.webhook_secret.as_bytes()) {
+        Ok(settings)
+    } else {
+        Err(Error::Unauthorized)
+    }
+}
+
+async fn open_todos(pool: &PgPool) -> Result<Vec<Todo>, sqlx::Error> {
+    sqlx::query_as!(
+        Todo,
+        r#"SELECT * FROM todos WHERE NOT completed ORDER BY starred DESC, "order", id LIMIT $1"#,
+        LIST_LIMIT
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(crypto::decrypt)
+    .collect()
+}
+
+/// The text and buttons of the `/list` message.
+fn list_message(todos: &[Todo]) -> (String, Value) {
+    if todos.is_empty() {
+        return (
+            "Nothing left to do.".to_owned(),
+            json!({ "inline_keyboard": [] }),
+        );
+    }
+    let buttons = todos
+        .iter()
+        .map(|todo| {
+            let mut title = todo
+                .title
+                .chars()
+                .take(BUTTON_TITLE_LENGTH)
+                .collect::<String>();
+            if title.len() < todo.title.len() {
+                title.push('…');
+            }
+            json!([{
+                "text": format!("✓ {}", title),
+                "callback_data": format!("{}{}", COMPLETE_PREFIX, todo.id),
+            }])
+        })
+        .collect::<Vec<_>>();
+    let text = format!("{} open, tap one to complete it:", todos.len());
+    (text, json!({ "inline_keyboard": buttons }))
+}
+
+async fn add_todo(
+    tx: &mut Transaction<'_, Postgres>,
+    routing: &RoutingService,
+    slack: &SlackNotifier,
+    title: &str,
+) -> Result<Todo, Error> {
+    let mut errors = ValidationErrors::default();
+    validate_title(&mut errors, title);
+    errors.into_result()?;
+
+    let title = normalize_title(title);
+    let order = next_order(tx).await?;
+    let todo = sqlx::query_as!(Todo, r#"INSERT INTO todos (title, "order") VALUES($1, $2) RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, crypto::encrypt_title(&title), order)
+        .fetch_one(&mut *tx)
+        .await
+        .and_then(crypto::decrypt)?;
+    history::record(tx, Action::Create, None, Some(&todo)).await?;
+
+    let todo = routing.present(todo);
+    slack.todo_created(tx, &todo.todo, &todo.url).await?;
+    Ok(todo.todo)
+}
+
+/// Completes a todo the way `PATCH` with `completed: true` does, `None` when it's gone.
+async fn complete_todo(
+    tx: &mut Transaction<'_, Postgres>,
+    routing: &RoutingService,
+    services: &TodoServices,
+    id: i64,
+) -> Result<Option<Todo>, Error> {
+    let before = match sqlx::query_as!(Todo, r#"SELECT * FROM todos WHERE id = $1 FOR UPDATE"#, id)
+        .fetch_optional(&mut *tx)
+        .await?
+    {
+        Some(todo) => crypto::decrypt(todo)?,
+        None => return Ok(None),
+    };
+    if before.completed {
+        return Ok(Some(before));
+    }
+
+    let status = before.status().with_completed(true);
+    let mut errors = ValidationErrors::default();
+    services
+        .workflow
+        .validate(&mut errors, before.status(), status);
+    errors.into_result()?;
+    if services.dependencies.enforce {
+        dependencies::ensure_unblocked(tx, id).await?;
+    }
+
+    let completed = status == Status::Done;
+    let todo = sqlx::query_as!(Todo, r#"UPDATE todos SET completed = $1, completed_at = CASE WHEN $1 THEN now() END, status = $2, version = version + 1 WHERE id = $3 RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, completed, status.as_str(), id)
+        .fetch_one(&mut *tx)
+        .await
+        .and_then(crypto::decrypt)?;
+    history::record(tx, Action::Update, Some(&before), Some(&todo)).await?;
+
+    let todo = routing.present(todo);
+    if todo.todo.completed {
+        services
+            .slack
+            .todo_completed(tx, &todo.todo, &todo.url)
+            .await?;
+    }
+    Ok(Some(todo.todo))
+}
+
+/// What the bot answers with when a change is rejected.
+fn rejection(error: &Error) -> String {
+    match error {
+        Error::ValidationFailed { errors } => match errors.messages().next() {
+            Some(message) => format!("Can't do that, {}.", message),
+            None => "That isn't a valid todo.".to_owned(),
+        },
+        Error::Conflict { reason } => format!("Can't do that, {}.", reason),
+        _ => "Something went wrong, try again later.".to_owned(),
+    }
+}
+
+/// Answers the updates of the bot. Updates are always acknowledged, even the ones the bot
+/// can't do anything with, since Telegram keeps sending unacknowledged ones.
+#[post("/integrations/telegram")]
+pub async fn telegram_webhook_handler(
+    req: HttpRequest,
+    update: web::Json<Update>,
+    bot: web::Data<TelegramBot>,
+    pool: web::Data<PgPool>,
+    routing: RoutingService,
+    services: web::Data<TodoServices>,
+) -> Result<HttpResponse, Error> {
+    let settings = authorize(&req, &bot)?;
+    let update = update.into_inner();
+    let token = settings.token.as_str();
+
+    if let Some(message) = update.message {
+        let chat_id = message.chat.id;
+        if !settings.chats.contains(&chat_id) {
+            info!("Ignored a Telegram message from chat {}", chat_id);
+            return Ok(HttpResponse::Ok().finish());
+        }
+        let text = message.text.unwrap_or_default();
+        let reply = match text.trim() {
+            "/start" | "/help" => json!({ "chat_id": chat_id, "text": HELP }),
+            "/list" => {
+                let (text, keyboard) = list_message(&open_todos(&pool).await?);
+                json!({ "chat_id": chat_id, "text": text, "reply_markup": keyboard })
+            }
+            text if text.starts_with('/') && !text.starts_with("/add") => {
+                json!({ "chat_id": chat_id, "text": HELP })
+            }
+            text => {
+                let title = text.strip_prefix("/add").unwrap_or(text);
+                // committed here rather than by a `Tx`, the reply must only claim what was saved
+                let mut tx = retry::begin(&pool).await?;
+                let text = match add_todo(&mut tx, &routing, &services.slack, title).await {
+                    Ok(todo) => {
+                        tx.commit().await?;
+                        format!("Added: {}", todo.title)
+                    }
+                    Err(e) => rejection(&e),
+                };
+                json!({ "chat_id": chat_id, "text": text })
+            }
+        };
+        bot.call(token, "sendMessage", reply).await;
+    } else if let Some(query) = update.callback_query {
+        let message = match query.message {
+            Some(message) if settings.chats.contains(&message.chat.id) => message,
+            _ => return Ok(HttpResponse::Ok().finish()),
+        };
+        let id = query
+            .data
+            .as_deref()
+            .and_then(|data| data.strip_prefix(COMPLETE_PREFIX))
+            .and_then(|id| id.parse::<i64>().ok());
+        let answer = match id {
+            Some(id) => {
+                let mut tx = retry::begin(&pool).await?;
+                match complete_todo(&mut tx, &routing, &services, id).await {
+                    Ok(Some(todo)) => {
+                        tx.commit().await?;
+                        format!("Completed: {}", todo.title)
+                    }
+                    Ok(None) => "That todo doesn't exist anymore.".to_owned(),
+                    Err(e) => rejection(&e),
+                }
+            }
+            None => "Unknown button.".to_owned(),
+        };
+        bot.call(
+            token,
+            "answerCallbackQuery",
+            json!({ "callback_query_id": query.id, "text": answer }),
+        )
+        .await;
+
+        // the list the button was on is replaced with the current one
+        let (text, keyboard) = list_message(&open_todos(&pool).await?);
+        bot.call(
+            token,
+            "editMessageText",
+            json!({
+                "chat_id": message.chat.id,
+                "message_id": message.message_id,
+                "text": text,
+                "reply_markup": keyboard,
+            }),
+        )
+        .await;
+    }
+    Ok(HttpResponse::Ok().finish())
+}
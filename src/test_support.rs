@@ -0,0 +1,56 @@
+//! Shared by the tests needing a database. They run against the one in `DATABASE_URL`, migrated
+//! to the current schema, and are ignored by default: run them with
+//! `DATABASE_URL=... cargo test -- --ignored`.
+
+use crate::dependencies::DependencySettings;
+use crate::ids::IdScheme;
+use crate::notifications::SlackNotifier;
+use crate::status::Workflow;
+use crate::{RoutingService, TodoServices};
+use actix_web::web;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
+
+pub async fn pool() -> PgPool {
+    let url =
+        std::env::var("DATABASE_URL").expect("The tests needing a database need DATABASE_URL");
+    let pool = PgPoolOptions::new()
+        .max_connections(2)
+        .connect(&url)
+        .await
+        .expect("Failed to connect to the test database");
+    sqlx::migrate!()
+        .run(&pool)
+        .await
+        .expect("Failed to migrate the test database");
+    pool
+}
+
+/// The app data the handlers take, with the default settings and without notifications.
+pub fn app_data(pool: PgPool) -> impl FnOnce(&mut web::ServiceConfig) {
+    with_dependencies(pool, DependencySettings { enforce: false })
+}
+
+/// Like `app_data`, with the given rules for blocked todos.
+pub fn with_dependencies(
+    pool: PgPool,
+    dependencies: DependencySettings,
+) -> impl FnOnce(&mut web::ServiceConfig) {
+    move |cfg| {
+        cfg.app_data(web::Data::new(pool))
+            .app_data(web::Data::new(SlackNotifier::new(None)))
+            .app_data(web::Data::new(RoutingService {
+                host: "localhost".to_owned(),
+                port: 8080,
+                scheme: "http".to_owned(),
+                base_path: String::new(),
+                id_scheme: IdScheme::Numeric,
+                trust_proxy: false,
+            }))
+            .app_data(web::Data::new(TodoServices {
+                workflow: Workflow::default(),
+                dependencies,
+                slack: SlackNotifier::new(None),
+            }));
+    }
+}
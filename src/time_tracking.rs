@@ -0,0 +1,134 @@
+use crate::crypto;
+use crate::due::{start_of_day, timezone};
+use crate::error::Error;
+use crate::history::{self, Action};
+use crate::ids::{self, TodoRef};
+use crate::transaction::Tx;
+use crate::{RoutingService, Todo, TodoPresenter};
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+#[derive(Serialize)]
+struct TimeEntry {
+    id: i64,
+    todo_id: i64,
+    started_at: DateTime<Utc>,
+    stopped_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct ReportParams {
+    from: NaiveDate,
+    to: NaiveDate,
+    tz: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ReportRow {
+    todo_id: i64,
+    title: String,
+    seconds: i64,
+}
+
+#[derive(Serialize)]
+struct Report {
+    from: NaiveDate,
+    to: NaiveDate,
+    total_seconds: i64,
+    todos: Vec<ReportRow>,
+}
+
+#[post("/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}/timer/start")]
+pub async fn start_timer_handler(todo: web::Path<TodoRef>, tx: Tx) -> Result<HttpResponse, Error> {
+    let mut tx = tx.lock().await?;
+    let id = ids::resolve(&mut *tx, *todo).await?;
+    sqlx::query_scalar!(r#"SELECT id FROM todos WHERE id = $1 FOR UPDATE"#, id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    let running = sqlx::query_scalar!(r#"SELECT EXISTS(SELECT 1 FROM time_entries WHERE todo_id = $1 AND stopped_at IS NULL) AS "running!""#, id)
+        .fetch_one(&mut *tx)
+        .await?;
+    if running {
+        return Err(Error::Conflict {
+            reason: "the timer of this todo is already running".to_owned(),
+        });
+    }
+
+    let entry = sqlx::query_as!(TimeEntry, r#"INSERT INTO time_entries (todo_id) VALUES ($1) RETURNING id, todo_id, started_at, stopped_at"#, id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(entry))
+}
+
+/// Stops the running timer and adds the tracked time to the todo.
+#[post("/todos/{id:\\d+|[0-9a-fA-F-]{36}|[0-9A-Za-z]{26}}/timer/stop")]
+pub async fn stop_timer_handler(
+    todo: web::Path<TodoRef>,
+    tx: Tx,
+    routing: RoutingService,
+) -> Result<TodoPresenter, Error> {
+    let mut tx = tx.lock().await?;
+    let id = ids::resolve(&mut *tx, *todo).await?;
+    let before = sqlx::query_as!(Todo, r#"SELECT * FROM todos WHERE id = $1 FOR UPDATE"#, id)
+        .fetch_one(&mut *tx)
+        .await
+        .and_then(crypto::decrypt)?;
+
+    let entry = sqlx::query_as!(TimeEntry, r#"UPDATE time_entries SET stopped_at = now() WHERE todo_id = $1 AND stopped_at IS NULL RETURNING id, todo_id, started_at, stopped_at"#, id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| Error::Conflict {
+            reason: "the timer of this todo isn't running".to_owned(),
+        })?;
+    let seconds = entry
+        .stopped_at
+        .map(|stopped_at| (stopped_at - entry.started_at).num_seconds())
+        .unwrap_or(0);
+
+    let todo = sqlx::query_as!(Todo, r#"UPDATE todos SET tracked_seconds = tracked_seconds + $1, version = version + 1 WHERE id = $2 RETURNING id, title, completed, "order", version, completed_at, starred, color, due_at, status, custom_fields, tracked_seconds, estimate_minutes, latitude, longitude, place_name, uuid"#, seconds, id)
+        .fetch_one(&mut *tx)
+        .await
+        .and_then(crypto::decrypt)?;
+    history::record(&mut tx, Action::Update, Some(&before), Some(&todo)).await?;
+
+    Ok(routing.present(todo))
+}
+
+/// Time tracked per todo between two days in the client's timezone, running timers included.
+/// Entries crossing the range boundaries only count with the part inside the range.
+#[get("/time-entries/report")]
+pub async fn report_handler(
+    req: HttpRequest,
+    params: web::Query<ReportParams>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, Error> {
+    let ReportParams { from, to, tz } = params.into_inner();
+    let tz = timezone(&req, tz.as_deref())?;
+    if to < from {
+        return Err(Error::InvalidQuery {
+            reason: "to needs to be on or after from".to_owned(),
+        });
+    }
+
+    let todos = sqlx::query_as!(ReportRow, r#"SELECT todos.id AS todo_id, todos.title, SUM(EXTRACT(EPOCH FROM LEAST(COALESCE(time_entries.stopped_at, now()), $2) - GREATEST(time_entries.started_at, $1)))::bigint AS "seconds!" FROM time_entries JOIN todos ON todos.id = time_entries.todo_id WHERE time_entries.started_at < $2 AND COALESCE(time_entries.stopped_at, now()) > $1 GROUP BY todos.id, todos.title ORDER BY todos.id"#, start_of_day(&tz, from), start_of_day(&tz, to.succ()))
+        .fetch_all(pool.get_ref())
+        .await?
+        .into_iter()
+        .map(|row| {
+            let title = crypto::decrypt_title(row.title)?;
+            Ok(ReportRow { title, ..row })
+        })
+        .collect::<Result<Vec<_>, sqlx::Error>>()?;
+
+    let total_seconds = todos.iter().map(|row| row.seconds).sum();
+    Ok(HttpResponse::Ok().json(Report {
+        from,
+        to,
+        total_seconds,
+        todos,
+    }))
+}
@@ -0,0 +1,127 @@
+//! One transaction per request for handlers writing to the database. Handlers take a `Tx` and
+//! run all their statements on it, `finish` then commits it when the response is a success and
+//! rolls it back otherwise, so a handler can't leave a change half made, whichever statement
+//! fails.
+//!
+//! The transaction is only begun when the handler first locks it, so that requests don't hold
+//! on to one of the few connections of the pool while their body is still being received.
+
+use crate::error::Error;
+use crate::retry;
+use actix_web::dev::{Payload, ServiceResponse};
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse};
+use futures_util::future::{ready, Ready};
+use futures_util::lock::{Mutex, MutexGuard};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+
+type Shared = Rc<State>;
+
+struct State {
+    pool: PgPool,
+    /// `None` until a handler locks it.
+    tx: Mutex<Option<Transaction<'static, Postgres>>>,
+}
+
+/// Kept in the request extensions, so that `finish` finds the transaction of the request.
+struct RequestTransaction(Shared);
+
+/// The transaction of the current request, begun when a handler first asks for it.
+pub struct Tx(Shared);
+
+impl Tx {
+    /// Begins the transaction unless an earlier call did.
+    pub async fn lock(&self) -> Result<TxGuard<'_>, Error> {
+        let mut guard = self.0.tx.lock().await;
+        if guard.is_none() {
+            *guard = Some(retry::begin(&self.0.pool).await?);
+        }
+        Ok(TxGuard(guard))
+    }
+}
+
+/// The locked transaction, which has always been begun.
+pub struct TxGuard<'a>(MutexGuard<'a, Option<Transaction<'static, Postgres>>>);
+
+impl Deref for TxGuard<'_> {
+    type Target = Transaction<'static, Postgres>;
+
+    fn deref(&self) -> &Self::Target {
+        self.0
+            .as_ref()
+            .expect("the transaction is begun when locking it")
+    }
+}
+
+impl DerefMut for TxGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0
+            .as_mut()
+            .expect("the transaction is begun when locking it")
+    }
+}
+
+impl FromRequest for Tx {
+    type Config = ();
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if let Some(RequestTransaction(shared)) = req.extensions().get() {
+            return ready(Ok(Tx(shared.clone())));
+        }
+        let pool = match req.app_data::<web::Data<PgPool>>() {
+            Some(pool) => pool.get_ref().clone(),
+            None => {
+                error!("PgPool isn't registered as app data");
+                return ready(Err(Error::InternalError));
+            }
+        };
+
+        let shared = Rc::new(State {
+            pool,
+            tx: Mutex::new(None),
+        });
+        req.extensions_mut()
+            .insert(RequestTransaction(shared.clone()));
+        ready(Ok(Tx(shared)))
+    }
+}
+
+/// Commits the transaction of a successful request and rolls it back for any other response.
+/// A failed commit replaces the response with the error.
+pub async fn finish(res: ServiceResponse) -> ServiceResponse {
+    let removed = res
+        .request()
+        .extensions_mut()
+        .remove::<RequestTransaction>();
+    let shared = match removed {
+        Some(RequestTransaction(shared)) => shared,
+        None => return res,
+    };
+    // the handler is done, so nothing else holds on to the transaction anymore
+    let tx = match Rc::try_unwrap(shared) {
+        Ok(state) => state.tx.into_inner(),
+        Err(_) => {
+            error!("The transaction is still in use after the response, rolling it back");
+            return res.into_response(HttpResponse::from_error(Error::InternalError));
+        }
+    };
+    // the handler never needed it
+    let tx = match tx {
+        Some(tx) => tx,
+        None => return res,
+    };
+
+    if !res.status().is_success() {
+        if let Err(e) = tx.rollback().await {
+            warn!("Failed to roll back the transaction: {}", e);
+        }
+        return res;
+    }
+    match tx.commit().await {
+        Ok(()) => res,
+        Err(e) => res.into_response(HttpResponse::from_error(Error::from(e))),
+    }
+}
@@ -0,0 +1,159 @@
+use crate::i18n::Locale;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use unicode_normalization::UnicodeNormalization;
+
+pub const MAX_TITLE_LENGTH: usize = 255;
+pub const MIN_ORDER: f64 = i32::MIN as f64;
+pub const MAX_ORDER: f64 = i32::MAX as f64;
+/// A week of work, longer estimates are better split into several todos.
+pub const MAX_ESTIMATE_MINUTES: i32 = 7 * 24 * 60;
+/// Named colors accepted next to `#rgb` and `#rrggbb` hex values.
+pub const COLOR_PALETTE: &[&str] = &[
+    "red", "orange", "yellow", "green", "teal", "blue", "purple", "pink", "gray",
+];
+
+#[derive(Serialize, Debug, Clone)]
+pub struct FieldError {
+    pub code: &'static str,
+    pub message: String,
+    /// Values substituted into the message when it's rendered in another locale.
+    #[serde(skip)]
+    args: Vec<(&'static str, String)>,
+}
+
+/// Field-level validation errors, keyed by the name of the offending field.
+#[derive(Serialize, Debug, Default, Clone)]
+#[serde(transparent)]
+pub struct ValidationErrors(BTreeMap<&'static str, Vec<FieldError>>);
+
+impl ValidationErrors {
+    /// Adds an error whose message is the `validation.<code>` catalog entry, `field` is always
+    /// available to the message as `{field}`.
+    pub fn add(
+        &mut self,
+        field: &'static str,
+        code: &'static str,
+        args: &[(&'static str, String)],
+    ) {
+        let mut args = args.to_vec();
+        args.push(("field", field.to_owned()));
+        let message = message(Locale::En, code, &args);
+
+        self.0
+            .entry(field)
+            .or_insert_with(Vec::new)
+            .push(FieldError {
+                code,
+                message,
+                args,
+            });
+    }
+
+    /// A copy of the errors with their messages rendered in the given locale.
+    pub fn localized(&self, locale: Locale) -> ValidationErrors {
+        let mut errors = self.clone();
+        for error in errors.0.values_mut().flatten() {
+            error.message = message(locale, error.code, &error.args);
+        }
+        errors
+    }
+
+    /// The messages of all the errors, field by field.
+    pub fn messages(&self) -> impl Iterator<Item = &str> {
+        self.0
+            .values()
+            .flatten()
+            .map(|error| error.message.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn into_result(self) -> Result<(), ValidationErrors> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+fn message(locale: Locale, code: &str, args: &[(&str, String)]) -> String {
+    locale
+        .format(&format!("validation.{}", code), args)
+        .unwrap_or_else(|| code.to_owned())
+}
+
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationErrors>;
+}
+
+/// Brings a title to the form it's stored in: trimmed and in Unicode NFC, so titles that look
+/// the same are also equal byte for byte.
+pub fn normalize_title(title: &str) -> String {
+    title.trim().nfc().collect()
+}
+
+pub fn validate_title(errors: &mut ValidationErrors, title: &str) {
+    let title = normalize_title(title);
+    if title.is_empty() {
+        errors.add("title", "blank", &[]);
+    } else if title.chars().count() > MAX_TITLE_LENGTH {
+        errors.add(
+            "title",
+            "too_long",
+            &[("max", MAX_TITLE_LENGTH.to_string())],
+        );
+    }
+}
+
+pub fn validate_order(errors: &mut ValidationErrors, order: f64) {
+    if !order.is_finite() || order < MIN_ORDER || order > MAX_ORDER {
+        errors.add(
+            "order",
+            "out_of_range",
+            &[
+                ("min", MIN_ORDER.to_string()),
+                ("max", MAX_ORDER.to_string()),
+            ],
+        );
+    }
+}
+
+/// Colors are stored lowercased, so `#FFF` and `#fff` are the same color.
+pub fn normalize_color(color: &str) -> String {
+    color.trim().to_lowercase()
+}
+
+pub fn validate_color(errors: &mut ValidationErrors, color: &str) {
+    let color = normalize_color(color);
+    let is_hex = match color.strip_prefix('#') {
+        Some(hex) => {
+            (hex.len() == 3 || hex.len() == 6) && hex.chars().all(|c| c.is_ascii_hexdigit())
+        }
+        None => false,
+    };
+
+    if !is_hex && !COLOR_PALETTE.contains(&color.as_str()) {
+        errors.add(
+            "color",
+            "invalid_color",
+            &[("palette", COLOR_PALETTE.join(", "))],
+        );
+    }
+}
+
+pub fn validate_estimate(errors: &mut ValidationErrors, estimate_minutes: i32) {
+    if estimate_minutes < 0 || estimate_minutes > MAX_ESTIMATE_MINUTES {
+        errors.add(
+            "estimate_minutes",
+            "out_of_range",
+            &[
+                ("min", "0".to_owned()),
+                ("max", MAX_ESTIMATE_MINUTES.to_string()),
+            ],
+        );
+    }
+}
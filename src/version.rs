@@ -0,0 +1,26 @@
+use actix_web::{get, HttpResponse};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+
+/// What's deployed, as recorded by the build script.
+#[derive(Serialize)]
+struct Version {
+    version: &'static str,
+    git_sha: &'static str,
+    built_at: Option<DateTime<Utc>>,
+    features: Vec<&'static str>,
+}
+
+#[get("/version")]
+pub async fn version_handler() -> HttpResponse {
+    let built_at = env!("BUILD_TIMESTAMP").parse().unwrap_or(0);
+    HttpResponse::Ok().json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("BUILD_GIT_SHA"),
+        built_at: Utc.timestamp_opt(built_at, 0).single(),
+        features: env!("BUILD_FEATURES")
+            .split(',')
+            .filter(|feature| !feature.is_empty())
+            .collect(),
+    })
+}